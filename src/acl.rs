@@ -0,0 +1,318 @@
+use crate::error::Error;
+use crate::messages::Address;
+use crate::rules::{domain_matches, normalize_domain};
+use regex::{Regex, RegexBuilder};
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+// A network written as address/prefix length. A bare address is a network
+// with just that address in it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients can show up as IPv4-mapped IPv6 addresses
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_length)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_length)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], address: &[u8], prefix_length: u8) -> bool {
+    let prefix_length = prefix_length as usize;
+    let full_bytes = prefix_length / 8;
+    if network[..full_bytes] != address[..full_bytes] {
+        return false;
+    }
+    let remaining_bits = prefix_length % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == address[full_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        let invalid = || Error::Config(format!("Invalid CIDR: {}", value));
+        let (network, prefix_length) = match value.split_once('/') {
+            Some((network, prefix_length)) => (network, Some(prefix_length)),
+            None => (value, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let max_length = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(length) => length.parse().map_err(|_| invalid())?,
+            None => max_length,
+        };
+        if prefix_length > max_length {
+            return Err(invalid());
+        }
+        Ok(Cidr {
+            network,
+            prefix_length,
+        })
+    }
+}
+
+// Entries starting with this are regular expressions
+const REGEX_PREFIX: &str = "regex:";
+
+// How a denied domain is written. Matching is case insensitive.
+#[derive(Clone, Debug)]
+pub enum DomainPattern {
+    // The domain itself or, starting with a dot, the domain and all of its
+    // subdomains
+    Domain(String),
+    // "*.example.com", only the subdomains
+    Wildcard(String),
+    // "regex:...", matched anywhere in the domain unless anchored
+    Regex(Regex),
+}
+
+impl DomainPattern {
+    // The domain has to be lowercase and without a trailing dot
+    pub fn matches(&self, domain: &str) -> bool {
+        match self {
+            DomainPattern::Domain(pattern) => domain_matches(pattern, domain),
+            DomainPattern::Wildcard(suffix) => domain.ends_with(suffix.as_str()),
+            DomainPattern::Regex(regex) => regex.is_match(domain),
+        }
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Error> {
+        if let Some(pattern) = value.strip_prefix(REGEX_PREFIX) {
+            let regex = RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| Error::Config(format!("Invalid domain regex {}: {}", pattern, e)))?;
+            return Ok(DomainPattern::Regex(regex));
+        }
+        match value.strip_prefix('*') {
+            // Keeps the dot, so "example.com" itself isn't matched
+            Some(suffix) if suffix.starts_with('.') => {
+                Ok(DomainPattern::Wildcard(normalize_domain(suffix)))
+            }
+            _ => Ok(DomainPattern::Domain(normalize_domain(value))),
+        }
+    }
+}
+
+impl fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DomainPattern::Domain(domain) => write!(f, "{}", domain),
+            DomainPattern::Wildcard(suffix) => write!(f, "*{}", suffix),
+            DomainPattern::Regex(regex) => write!(f, "{}{}", REGEX_PREFIX, regex),
+        }
+    }
+}
+
+// Which destinations clients are allowed to reach. Denials take precedence,
+// and if there are any allowed networks, only those can be reached. Domains
+// are checked against the denied domains as the client sent them, and the
+// addresses they resolve to against the networks.
+#[derive(Default)]
+pub struct DestinationAcl {
+    allowed_cidrs: Vec<Cidr>,
+    denied_cidrs: Vec<Cidr>,
+    denied_domains: Vec<DomainPattern>,
+}
+
+impl DestinationAcl {
+    pub fn allow_cidr(&mut self, cidr: Cidr) {
+        self.allowed_cidrs.push(cidr);
+    }
+
+    pub fn deny_cidr(&mut self, cidr: Cidr) {
+        self.denied_cidrs.push(cidr);
+    }
+
+    pub fn deny_domain(&mut self, pattern: DomainPattern) {
+        self.denied_domains.push(pattern);
+    }
+
+    pub fn allows(&self, address: &Address) -> bool {
+        match address {
+            Address::Ip(address) => self.allows_ip(*address),
+            Address::Domain(domain) => self.allows_domain(domain),
+        }
+    }
+
+    pub fn allows_domain(&self, domain: &str) -> bool {
+        self.denied_domain(domain).is_none()
+    }
+
+    // The first denied domain pattern the domain matches
+    pub fn denied_domain(&self, domain: &str) -> Option<&DomainPattern> {
+        let domain = normalize_domain(domain);
+        self.denied_domains
+            .iter()
+            .find(|pattern| pattern.matches(&domain))
+    }
+
+    // Whether checking a destination needs its addresses
+    pub fn has_cidr_rules(&self) -> bool {
+        !self.allowed_cidrs.is_empty() || !self.denied_cidrs.is_empty()
+    }
+
+    pub fn allows_ip(&self, address: IpAddr) -> bool {
+        if self.denied_cidrs.iter().any(|cidr| cidr.contains(address)) {
+            return false;
+        }
+        self.allowed_cidrs.is_empty()
+            || self.allowed_cidrs.iter().any(|cidr| cidr.contains(address))
+    }
+}
+
+// A set of ports made of single ports and inclusive ranges
+#[derive(Clone, Debug, Default)]
+pub struct PortSet {
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl PortSet {
+    pub fn add(&mut self, ports: RangeInclusive<u16>) {
+        self.ranges.push(ports);
+    }
+
+    // Adds a range written as "first-last", or a single port
+    pub fn add_range(&mut self, range: &str) -> Result<(), Error> {
+        let invalid = || Error::Config(format!("Invalid port range: {}", range));
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let first: u16 = first.trim().parse().map_err(|_| invalid())?;
+        let last: u16 = last.trim().parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        self.add(first..=last);
+        Ok(())
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|range| range.contains(&port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_cidr() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(cidr.contains(ip("::ffff:10.0.0.1")));
+
+        let cidr: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(cidr.contains(ip("192.168.1.1")));
+        assert!(!cidr.contains(ip("192.168.1.2")));
+
+        let cidr: Cidr = "fd00::/7".parse().unwrap();
+        assert!(cidr.contains(ip("fdab::1")));
+        assert!(!cidr.contains(ip("fe80::1")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn denied_domains() {
+        let mut acl = DestinationAcl::default();
+        acl.deny_domain(".internal".parse().unwrap());
+        acl.deny_domain("Example.com".parse().unwrap());
+        assert!(!acl.allows(&Address::Domain("db.internal".into())));
+        assert!(!acl.allows(&Address::Domain("example.COM".into())));
+        assert!(acl.allows(&Address::Domain("www.example.com".into())));
+        assert!(acl.allows(&Address::Domain("internal.org".into())));
+    }
+
+    #[test]
+    fn denied_domain_patterns() {
+        let mut acl = DestinationAcl::default();
+        acl.deny_domain("*.doubleclick.net".parse().unwrap());
+        acl.deny_domain(r"regex:^ads?\d*\.".parse().unwrap());
+        assert!(acl.allows_domain("doubleclick.net"));
+        let pattern = acl.denied_domain("stats.G.doubleclick.net").unwrap();
+        assert_eq!(pattern.to_string(), "*.doubleclick.net");
+        let pattern = acl.denied_domain("AD2.example.com").unwrap();
+        assert_eq!(pattern.to_string(), r"regex:^ads?\d*\.");
+        assert!(acl.allows_domain("reads.example.com"));
+        assert!("regex:(".parse::<DomainPattern>().is_err());
+    }
+
+    #[test]
+    fn denied_domains_normalized() {
+        let mut acl = DestinationAcl::default();
+        acl.deny_domain("example.com".parse().unwrap());
+        acl.deny_domain("*.doubleclick.net".parse().unwrap());
+        acl.deny_domain(r"regex:^tracker\.org$".parse().unwrap());
+        acl.deny_domain("regex:^metrics".parse().unwrap());
+        for domain in [
+            "example.com.",
+            "EXAMPLE.COM.",
+            "stats.DoubleClick.net.",
+            "tracker.org.",
+            "METRICS.example.org",
+        ]
+        .iter()
+        {
+            assert!(!acl.allows_domain(domain), "{} allowed", domain);
+        }
+        // Only a single trailing dot is dropped
+        assert!(acl.allows_domain("example.com.."));
+    }
+
+    #[test]
+    fn denials_take_precedence() {
+        let mut acl = DestinationAcl::default();
+        assert!(acl.allows_ip(ip("10.0.0.1")));
+        acl.allow_cidr("10.0.0.0/8".parse().unwrap());
+        acl.deny_cidr("10.0.0.0/24".parse().unwrap());
+        assert!(acl.allows_ip(ip("10.1.0.1")));
+        assert!(!acl.allows_ip(ip("10.0.0.1")));
+        assert!(!acl.allows_ip(ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn port_ranges() {
+        let mut ports = PortSet::default();
+        ports.add(80..=80);
+        ports.add_range("1024-2048").unwrap();
+        ports.add_range("443").unwrap();
+        assert!(ports.contains(80));
+        assert!(ports.contains(443));
+        assert!(ports.contains(1024));
+        assert!(ports.contains(2048));
+        assert!(!ports.contains(22));
+        assert!(!ports.contains(2049));
+
+        assert!(ports.add_range("2048-1024").is_err());
+        assert!(ports.add_range("1024-").is_err());
+        assert!(ports.add_range("70000").is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use crate::error::Error;
+use crate::messages::AuthenticationMethod;
+use argon2::password_hash::{PasswordHasher, SaltString};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordVerifier, Version};
+use async_trait::async_trait;
+use blake2::{Blake2b512, Digest};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+// Decides who gets to use the proxy. Implement it to check clients against
+// something other than the credentials in the config.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, username: &str, password: &str) -> bool;
+
+    // The method clients have to use to authenticate
+    fn supported_method(&self) -> AuthenticationMethod;
+}
+
+#[derive(Clone)]
+pub struct Credentials {
+    username: String,
+    secret: Secret,
+}
+
+// What a client's password is checked against
+#[derive(Clone)]
+enum Secret {
+    Plaintext(String),
+    Bcrypt(String),
+    Argon2(String),
+}
+
+// Wiped once dropped, so passwords and hashes don't linger in memory after
+// the context goes away or the credentials are reloaded
+impl Drop for Secret {
+    fn drop(&mut self) {
+        match self {
+            Secret::Plaintext(value) | Secret::Bcrypt(value) | Secret::Argon2(value) => {
+                value.zeroize()
+            }
+        }
+    }
+}
+
+impl Secret {
+    fn from_hash(hash: &str) -> Result<Self, Error> {
+        if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+            let parts: bcrypt::HashParts = hash
+                .parse()
+                .map_err(|e| Error::Config(format!("Invalid bcrypt hash: {}", e)))?;
+            if !(4..=31).contains(&parts.get_cost()) {
+                return Err(Error::Config(format!(
+                    "Invalid bcrypt hash: cost {} out of range",
+                    parts.get_cost()
+                )));
+            }
+            Ok(Secret::Bcrypt(hash.into()))
+        } else if hash.starts_with("$argon2") {
+            PasswordHash::new(hash)
+                .map_err(|e| Error::Config(format!("Invalid argon2 hash: {}", e)))?;
+            Ok(Secret::Argon2(hash.into()))
+        } else {
+            Err(Error::Config("Unsupported password hash scheme".into()))
+        }
+    }
+
+    fn is_hash(value: &str) -> bool {
+        value.starts_with("$2") || value.starts_with("$argon2")
+    }
+
+    // Roughly how long checking a password against this takes. Schemes are
+    // ranked first, then their own cost parameters.
+    fn cost(&self) -> (u8, u64) {
+        match self {
+            Secret::Plaintext(_) => (0, 0),
+            Secret::Bcrypt(hash) => (
+                1,
+                hash.get(4..6)
+                    .and_then(|cost| cost.parse().ok())
+                    .unwrap_or(0),
+            ),
+            Secret::Argon2(hash) => {
+                let params = PasswordHash::new(hash)
+                    .ok()
+                    .and_then(|hash| Params::try_from(&hash).ok());
+                match params {
+                    Some(params) => (
+                        2,
+                        params.m_cost() as u64 * params.t_cost() as u64 * params.p_cost() as u64,
+                    ),
+                    None => (2, 0),
+                }
+            }
+        }
+    }
+
+    // A secret no password matches, that's as costly to check as this one
+    fn dummy(&self) -> Secret {
+        const DUMMY_PASSWORD: &str = "rusty-socks unknown user";
+        let dummy = match self {
+            Secret::Plaintext(_) => None,
+            Secret::Bcrypt(_) => bcrypt::hash(DUMMY_PASSWORD, self.cost().1 as u32)
+                .ok()
+                .map(Secret::Bcrypt),
+            Secret::Argon2(hash) => argon2_like(hash, DUMMY_PASSWORD).map(Secret::Argon2),
+        };
+        dummy.unwrap_or_else(|| Secret::Plaintext(DUMMY_PASSWORD.into()))
+    }
+
+    // Hashes are slow to check on purpose, so they're checked on the
+    // blocking pool rather than holding up one of the runtime's workers
+    async fn verify_off_runtime(&self, password: &str) -> bool {
+        if let Secret::Plaintext(_) = self {
+            return self.verify(password);
+        }
+        let secret = self.clone();
+        let mut password = password.to_string();
+        let result = tokio::task::spawn_blocking(move || {
+            let matches = secret.verify(&password);
+            password.zeroize();
+            matches
+        })
+        .await;
+        result.unwrap_or(false)
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Secret::Plaintext(expected) => {
+                constant_time_eq(expected.as_bytes(), password.as_bytes())
+            }
+            Secret::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Secret::Argon2(hash) => match PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+// Hashes the password using the same algorithm, version and parameters as
+// the given argon2 hash
+fn argon2_like(hash: &str, password: &str) -> Option<String> {
+    let hash = PasswordHash::new(hash).ok()?;
+    let algorithm = Algorithm::try_from(hash.algorithm).ok()?;
+    let version = match hash.version {
+        Some(version) => Version::try_from(version).ok()?,
+        None => Version::default(),
+    };
+    let params = Params::try_from(&hash).ok()?;
+    let salt = SaltString::encode_b64(b"rusty-socks-dummy").ok()?;
+    Argon2::new(algorithm, version, params)
+        .hash_password(password.as_bytes(), &salt)
+        .ok()
+        .map(|hash| hash.to_string())
+}
+
+// Compares digests of both, so neither the position of the first difference
+// nor the length of the expected value shows in how long it takes
+fn constant_time_eq(expected: &[u8], actual: &[u8]) -> bool {
+    let expected = Blake2b512::digest(expected);
+    let actual = Blake2b512::digest(actual);
+    expected.ct_eq(&actual).into()
+}
+
+impl Credentials {
+    pub fn new(username: &str, password: &str) -> Self {
+        Credentials {
+            username: username.into(),
+            secret: Secret::Plaintext(password.into()),
+        }
+    }
+
+    // The scheme is detected from the hash's prefix. bcrypt ($2a$, $2b$,
+    // $2y$) and argon2 ($argon2...) are supported.
+    pub fn with_hash(username: &str, hash: &str) -> Result<Self, Error> {
+        Ok(Credentials {
+            username: username.into(),
+            secret: Secret::from_hash(hash)?,
+        })
+    }
+
+    // The username and password to send to a proxy, unless only the
+    // password's hash is known
+    pub(crate) fn plaintext(&self) -> Option<(&str, &str)> {
+        match &self.secret {
+            Secret::Plaintext(password) => Some((&self.username, password)),
+            _ => None,
+        }
+    }
+}
+
+// Credentials kept in memory, the default authenticator. Authentication is
+// only required once credentials have been added, either directly or from a
+// file.
+#[derive(Clone, Default)]
+pub struct CredentialStore {
+    // Secrets by username
+    secrets: HashMap<String, Secret>,
+    // Checked against when the username is unknown, so that takes as long
+    // as checking the most costly known one's password
+    unknown_user_secret: Option<Secret>,
+    // Set as soon as any source of credentials is configured. Whether the
+    // store happens to be empty doesn't matter after that.
+    required: bool,
+}
+
+impl CredentialStore {
+    pub fn add(&mut self, credentials: Credentials) {
+        self.required = true;
+        let costlier = match &self.unknown_user_secret {
+            Some(dummy) => credentials.secret.cost() > dummy.cost(),
+            None => true,
+        };
+        if costlier {
+            self.unknown_user_secret = Some(credentials.secret.dummy());
+        }
+        self.secrets
+            .insert(credentials.username, credentials.secret);
+    }
+
+    pub fn len(&self) -> usize {
+        self.secrets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+
+    // A file without any credentials is an error, as it's more likely to be
+    // a mistake, or a half-written file, than a wish to let anyone in
+    pub fn add_file(&mut self, path: &str) -> Result<(), Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read {}: {}", path, e)))?;
+        let credentials = parse_credentials(&contents, path)?;
+        if credentials.is_empty() {
+            return Err(Error::Config(format!("No credentials found in {}", path)));
+        }
+        for credentials in credentials {
+            self.add(credentials);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Authenticator for CredentialStore {
+    async fn authenticate(&self, username: &str, password: &str) -> bool {
+        match (self.secrets.get(username), &self.unknown_user_secret) {
+            (Some(secret), _) => secret.verify_off_runtime(password).await,
+            (None, Some(secret)) => {
+                secret.verify_off_runtime(password).await;
+                false
+            }
+            (None, None) => !self.required,
+        }
+    }
+
+    fn supported_method(&self) -> AuthenticationMethod {
+        match self.required {
+            true => AuthenticationMethod::UsernamePassword,
+            false => AuthenticationMethod::NoAuthentication,
+        }
+    }
+}
+
+fn parse_credentials(contents: &str, path: &str) -> Result<Vec<Credentials>, Error> {
+    let mut credentials = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((username, hash)) if !username.is_empty() && Secret::is_hash(hash) => credentials
+                .push(
+                    Credentials::with_hash(username, hash)
+                        .map_err(|e| Error::Config(format!("{}:{}: {}", path, index + 1, e)))?,
+                ),
+            Some((username, password)) if !username.is_empty() => {
+                credentials.push(Credentials::new(username, password))
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "{}:{}: expected username:password",
+                    path,
+                    index + 1
+                )))
+            }
+        }
+    }
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_credentials_file() {
+        let error = parse_credentials("foo:bar\nbaz\n", "users").err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "config: users:2: expected username:password"
+        );
+        assert!(parse_credentials(":bar\n", "users").is_err());
+    }
+
+    #[test]
+    fn empty_credentials_file_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("rusty-socks-empty-{}.passwd", std::process::id()));
+        fs::write(&path, "# nobody yet\n\n").unwrap();
+        let mut store = CredentialStore::default();
+        let result = store.add_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn credentials_required_once_added() {
+        let store = CredentialStore::default();
+        assert_eq!(
+            store.supported_method(),
+            AuthenticationMethod::NoAuthentication
+        );
+        assert!(store.authenticate("foo", "bar").await);
+
+        let mut store = CredentialStore::default();
+        store.add(Credentials::new("foo", "bar"));
+        assert_eq!(
+            store.supported_method(),
+            AuthenticationMethod::UsernamePassword
+        );
+        assert!(!store.authenticate("baz", "bar").await);
+    }
+
+    #[test]
+    fn unknown_users_checked_against_costliest_scheme() {
+        let mut store = CredentialStore::default();
+        store.add(Credentials::new("foo", "bar"));
+        let hash = bcrypt::hash("qux", 5).unwrap();
+        store.add(Credentials::with_hash("baz", &hash).unwrap());
+        store.add(Credentials::new("quux", "corge"));
+        let dummy = store.unknown_user_secret.as_ref().unwrap();
+        assert_eq!(dummy.cost(), (1, 5));
+        // It's not a copy of anyone's secret
+        assert!(!dummy.verify("qux"));
+    }
+
+    #[tokio::test]
+    async fn argon2_hashes_verified() {
+        let salt = SaltString::encode_b64(b"some salt").unwrap();
+        let params = Params::new(1024, 2, 1, None).unwrap();
+        let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(b"qux", &salt)
+            .unwrap()
+            .to_string();
+        let mut store = CredentialStore::default();
+        store.add(Credentials::with_hash("baz", &hash).unwrap());
+        assert!(store.authenticate("baz", "qux").await);
+        assert!(!store.authenticate("baz", "quux").await);
+
+        let dummy = store.unknown_user_secret.as_ref().unwrap();
+        assert_eq!(dummy.cost(), (2, 2048));
+        assert!(!dummy.verify("qux"));
+    }
+
+    #[test]
+    fn malformed_bcrypt_hashes_rejected() {
+        let hash = bcrypt::hash("qux", 4).unwrap();
+        assert!(Credentials::with_hash("baz", &hash).is_ok());
+        assert!(Credentials::with_hash("baz", "$2b$12$tooshort").is_err());
+        assert!(Credentials::with_hash("baz", &hash.replacen("$04$", "$99$", 1)).is_err());
+        let error = parse_credentials("foo:$2b$not-a-hash\n", "users")
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with("config: users:1: "));
+    }
+
+    #[test]
+    fn plaintext_comparison() {
+        assert!(constant_time_eq(b"bar", b"bar"));
+        assert!(!constant_time_eq(b"bar", b"baz"));
+        assert!(!constant_time_eq(b"bar", b"ba"));
+        assert!(!constant_time_eq(b"bar", b"barr"));
+    }
+}
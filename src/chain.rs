@@ -0,0 +1,176 @@
+use crate::error::Error;
+use crate::messages::{
+    Address, AuthRequest, AuthResponse, AuthStatusCode, AuthenticationMethod, ClientRequest,
+    Command, HelloRequest, HelloResponse, Parseable, RequestResponse, ResponseCode, Writeable,
+};
+use crate::upstream::{self, SocketOptions};
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tracing::debug;
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+// Another SOCKS5 proxy that upstream connections are tunneled through
+#[derive(Clone, Debug)]
+pub struct UpstreamProxy {
+    pub address: SocketAddr,
+    // Username and password, if the proxy requires them
+    pub credentials: Option<(String, String)>,
+}
+
+// Connects to the destination through the upstream proxy. Domains are sent
+// as they are, so the proxy resolves them. If the proxy refuses to connect,
+// its reply code comes back in `Error::UpstreamProxyRejected`.
+pub async fn connect(
+    proxy: &UpstreamProxy,
+    address: &Address,
+    port: u16,
+    options: &SocketOptions,
+) -> Result<TcpStream, Error> {
+    let mut stream = upstream::connect_address(proxy.address, options).await?;
+    authenticate(&mut stream, proxy).await?;
+    let request = ClientRequest {
+        version: SOCKS_VERSION,
+        command: Command::Connect,
+        address: address.clone(),
+        port,
+    };
+    send(&mut stream, &request).await?;
+    let response = <RequestResponse as Parseable>::new(&mut stream).await?;
+    if response.response_code != ResponseCode::Success {
+        return Err(Error::UpstreamProxyRejected(response.response_code));
+    }
+    debug!(
+        "Upstream proxy connected from {:?}",
+        (response.bind_address, response.port)
+    );
+    Ok(stream)
+}
+
+async fn authenticate(stream: &mut TcpStream, proxy: &UpstreamProxy) -> Result<(), Error> {
+    let mut methods = vec![AuthenticationMethod::NoAuthentication as u8];
+    if proxy.credentials.is_some() {
+        methods.push(AuthenticationMethod::UsernamePassword as u8);
+    }
+    let hello = HelloRequest {
+        version: SOCKS_VERSION,
+        methods,
+    };
+    send(stream, &hello).await?;
+    let response = <HelloResponse as Parseable>::new(stream).await?;
+    match (response.method, &proxy.credentials) {
+        (AuthenticationMethod::NoAuthentication, _) => Ok(()),
+        (AuthenticationMethod::UsernamePassword, Some((username, password))) => {
+            let request = AuthRequest {
+                version: AUTH_VERSION,
+                username: username.clone(),
+                password: password.clone(),
+            };
+            send(stream, &request).await?;
+            let response = <AuthResponse as Parseable>::new(stream).await?;
+            if response.status != AuthStatusCode::Success {
+                return Err(Error::Generic(
+                    "Upstream proxy rejected the credentials".into(),
+                ));
+            }
+            Ok(())
+        }
+        _ => Err(Error::Generic(
+            "Upstream proxy accepts none of the offered methods".into(),
+        )),
+    }
+}
+
+// Writes the whole message at once rather than a few bytes at a time
+async fn send<M: Writeable + Sync>(stream: &mut TcpStream, message: &M) -> Result<(), Error> {
+    let mut buffer = Vec::new();
+    message.write(&mut buffer).await?;
+    stream.write_all(&buffer).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::PortSet;
+    use crate::context::{Context, Credentials};
+    use crate::states::State;
+    use crate::stream::Stream;
+    use tokio::net::TcpListener;
+
+    // Runs a proxy that serves a single connection
+    async fn spawn_proxy(context: Context) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut state = State::new(Stream::buffered(stream));
+            while let Ok(next) = state.process(&context).await {
+                if next.is_finished() {
+                    break;
+                }
+                state = next;
+            }
+        });
+        address
+    }
+
+    async fn spawn_backend() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn connect_through_proxy() {
+        let backend = spawn_backend().await;
+        let proxy = UpstreamProxy {
+            address: spawn_proxy(Context::with_credentials(Credentials::new("foo", "bar"))).await,
+            credentials: Some(("foo".into(), "bar".into())),
+        };
+        let address = Address::Ip(backend.ip());
+        let mut stream = connect(&proxy, &address, backend.port(), &SocketOptions::default())
+            .await
+            .unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[tokio::test]
+    async fn proxy_requires_credentials() {
+        let proxy = UpstreamProxy {
+            address: spawn_proxy(Context::with_credentials(Credentials::new("foo", "bar"))).await,
+            credentials: None,
+        };
+        let address = Address::Domain("localhost".into());
+        let result = connect(&proxy, &address, 80, &SocketOptions::default()).await;
+        assert!(matches!(result, Err(Error::Generic(_))));
+    }
+
+    #[tokio::test]
+    async fn proxy_reply_code_returned() {
+        let mut context = Context::default();
+        let mut ports = PortSet::default();
+        ports.add(443..=443);
+        context.set_allowed_ports(ports);
+        let proxy = UpstreamProxy {
+            address: spawn_proxy(context).await,
+            credentials: None,
+        };
+        let address = Address::Domain("localhost".into());
+        let result = connect(&proxy, &address, 80, &SocketOptions::default()).await;
+        assert!(matches!(
+            result,
+            Err(Error::UpstreamProxyRejected(
+                ResponseCode::ConnectionNotAllowed
+            ))
+        ));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::auth::Credentials;
+use crate::chain::{self, UpstreamProxy};
+use crate::error::Error;
+use crate::messages::Address;
+use crate::stream::Stream;
+use crate::upstream::SocketOptions;
+use std::net::SocketAddr;
+
+// Connects to the target through a SOCKS5 proxy, authenticating with the
+// credentials if the proxy asks for them. The stream's ready to use once
+// it's returned. If the proxy refuses to connect, its reply code comes back
+// in `Error::UpstreamProxyRejected`.
+pub async fn connect(
+    proxy: SocketAddr,
+    target: Address,
+    port: u16,
+    credentials: Option<Credentials>,
+) -> Result<Stream, Error> {
+    let credentials = match &credentials {
+        Some(credentials) => match credentials.plaintext() {
+            Some((username, password)) => Some((username.into(), password.into())),
+            None => {
+                return Err(Error::Generic(
+                    "Can't authenticate with a password hash".into(),
+                ))
+            }
+        },
+        None => None,
+    };
+    let proxy = UpstreamProxy {
+        address: proxy,
+        credentials,
+    };
+    let stream = chain::connect(&proxy, &target, port, &SocketOptions::default()).await?;
+    Ok(Stream::buffered(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::server::serve;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::prelude::*;
+
+    async fn spawn_proxy(context: Context) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(context)));
+        address
+    }
+
+    #[tokio::test]
+    async fn connect_and_exchange_data() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut data = [0; 4];
+            stream.read_exact(&mut data).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+        });
+        let credentials = Credentials::new("foo", "bar");
+        let proxy = spawn_proxy(Context::with_credentials(credentials.clone())).await;
+
+        let target = Address::Ip(backend_addr.ip());
+        let mut stream = connect(proxy, target, backend_addr.port(), Some(credentials))
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut data = [0; 4];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"ping");
+    }
+
+    #[tokio::test]
+    async fn hashed_credentials_rejected() {
+        let hash = bcrypt::hash("bar", 4).unwrap();
+        let credentials = Credentials::with_hash("foo", &hash).unwrap();
+        let result = connect(
+            "127.0.0.1:1".parse().unwrap(),
+            Address::Domain("localhost".into()),
+            80,
+            Some(credentials),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Generic(_))));
+    }
+}
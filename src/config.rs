@@ -0,0 +1,1205 @@
+use crate::acl::{DestinationAcl, PortSet};
+use crate::chain::UpstreamProxy;
+use crate::context::{Context, Credentials};
+use crate::error::Error;
+use crate::listener::Listener;
+use crate::messages::Address;
+use crate::proxy_protocol::ProxyProtocol;
+use crate::rate_limit::RateLimit;
+use crate::rules::DestinationRule;
+use crate::stats::LoadShedding;
+#[cfg(feature = "tls")]
+use crate::tls::{ClientTls, UpstreamTls};
+use crate::upstream::{Keepalive, SocketOptions};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::info;
+
+#[derive(Deserialize)]
+pub struct Config {
+    // Either a single address or a list of them, one listener each
+    #[serde(deserialize_with = "one_or_many")]
+    pub endpoint: Vec<String>,
+    pub ipv6_only: Option<bool>,
+    // Either a single [credentials] table or several [[credentials]] ones
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub credentials: Vec<ConfigCredentials>,
+    pub credentials_file: Option<String>,
+    pub max_concurrent_connects: Option<usize>,
+    #[serde(default = "default_connect_queue_timeout")]
+    pub connect_queue_timeout_secs: u64,
+    pub connect_retries: Option<u32>,
+    #[serde(default = "default_connect_retry_backoff_ms")]
+    pub connect_retry_backoff_ms: u64,
+    pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub reject_over_max_connections: bool,
+    #[serde(default)]
+    pub destination_rules: Vec<ConfigDestinationRule>,
+    // Targets to connect to instead of others, as "host:port" pairs
+    #[serde(default)]
+    pub route_overrides: HashMap<String, String>,
+    #[serde(default)]
+    pub log_rejected_auth_methods: bool,
+    #[serde(default)]
+    pub reject_non_socks_quietly: bool,
+    pub allowed_socks_versions: Option<Vec<u8>>,
+    pub connection_rate_limit: Option<ConfigRateLimit>,
+    pub per_ip_connection_rate_limit: Option<ConfigRateLimit>,
+    pub user_quota: Option<ConfigUserQuota>,
+    pub max_auth_failures: Option<u32>,
+    #[serde(default = "default_auth_lockout_secs")]
+    pub auth_lockout_secs: u64,
+    #[serde(default)]
+    pub upstream_reuse_address: bool,
+    #[serde(default)]
+    pub upstream_bind_address_no_port: bool,
+    pub outgoing_bind_address: Option<IpAddr>,
+    pub stats_interval_secs: Option<u64>,
+    pub last_good_address_cache_size: Option<usize>,
+    pub dns_cache_size: Option<usize>,
+    #[serde(default = "default_dns_cache_max_ttl_secs")]
+    pub dns_cache_max_ttl_secs: u64,
+    pub rule_decision_cache_size: Option<usize>,
+    #[serde(default = "default_rule_decision_cache_ttl_secs")]
+    pub rule_decision_cache_ttl_secs: u64,
+    pub load_shedding: Option<ConfigLoadShedding>,
+    pub upstream_liveness_check_ms: Option<u64>,
+    pub bind_timeout_secs: Option<u64>,
+    pub handshake_timeout_secs: Option<u64>,
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive: Option<ConfigTcpKeepalive>,
+    pub idle_timeout_secs: Option<u64>,
+    pub max_session_duration_secs: Option<u64>,
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    pub proxy_buffer_size: Option<usize>,
+    #[serde(default = "default_shutdown_grace_period_secs")]
+    pub shutdown_grace_period_secs: u64,
+    #[serde(default)]
+    pub allowed_cidrs: Vec<String>,
+    #[serde(default)]
+    pub denied_cidrs: Vec<String>,
+    #[serde(default, alias = "blocked_domains")]
+    pub denied_domains: Vec<String>,
+    #[serde(default)]
+    pub allowed_source_cidrs: Vec<String>,
+    #[serde(default)]
+    pub deny_self_connect: Vec<SocketAddr>,
+    #[serde(default)]
+    pub anonymous_allowed_cidrs: Vec<String>,
+    pub allowed_ports: Option<Vec<ConfigPorts>>,
+    pub send_proxy_protocol: Option<ProxyProtocol>,
+    pub upstream_proxy: Option<ConfigUpstreamProxy>,
+    pub health_endpoint: Option<String>,
+    #[cfg(unix)]
+    pub run_as_user: Option<String>,
+    #[cfg(unix)]
+    pub run_as_group: Option<String>,
+    #[cfg(feature = "metrics")]
+    pub metrics_endpoint: Option<String>,
+    #[cfg(feature = "tls")]
+    pub tls_cert: Option<String>,
+    #[cfg(feature = "tls")]
+    pub tls_key: Option<String>,
+}
+
+// Either a single port or a "first-last" range
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ConfigPorts {
+    Port(u16),
+    Range(String),
+}
+
+#[derive(Deserialize)]
+pub struct ConfigLoadShedding {
+    pub max_active_connections: Option<u64>,
+    pub max_connect_latency_p95_ms: Option<u64>,
+}
+
+fn default_connect_queue_timeout() -> u64 {
+    10
+}
+
+fn default_connect_retry_backoff_ms() -> u64 {
+    100
+}
+
+fn default_dns_cache_max_ttl_secs() -> u64 {
+    60
+}
+
+fn default_auth_lockout_secs() -> u64 {
+    300
+}
+
+fn default_rule_decision_cache_ttl_secs() -> u64 {
+    5
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_shutdown_grace_period_secs() -> u64 {
+    30
+}
+
+// Either the password itself or a bcrypt/argon2 hash of it
+#[derive(Deserialize)]
+pub struct ConfigCredentials {
+    pub username: String,
+    pub password: Option<String>,
+    pub password_hash: Option<String>,
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ConfigRateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigUserQuota {
+    pub bytes: u64,
+    pub reset_interval_secs: u64,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigTcpKeepalive {
+    #[serde(default = "default_tcp_keepalive_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_tcp_keepalive_idle_secs")]
+    pub idle_secs: u64,
+    #[serde(default = "default_tcp_keepalive_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_tcp_keepalive_enabled() -> bool {
+    true
+}
+
+fn default_tcp_keepalive_idle_secs() -> u64 {
+    60
+}
+
+fn default_tcp_keepalive_interval_secs() -> u64 {
+    10
+}
+
+#[derive(Deserialize)]
+pub struct ConfigUpstreamProxy {
+    pub address: SocketAddr,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl From<&ConfigRateLimit> for RateLimit {
+    fn from(config: &ConfigRateLimit) -> Self {
+        RateLimit {
+            per_second: config.per_second,
+            burst: config.burst,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConfigDestinationRule {
+    pub name: Option<String>,
+    pub destination: String,
+    pub port: Option<u16>,
+    pub send_proxy_protocol: Option<ProxyProtocol>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<ConfigUpstreamTls>,
+}
+
+#[cfg(feature = "tls")]
+#[derive(Deserialize)]
+pub struct ConfigUpstreamTls {
+    #[serde(default = "default_verify_certificates")]
+    pub verify_certificates: bool,
+    pub ca_file: Option<String>,
+    pub server_name: Option<String>,
+}
+
+#[cfg(feature = "tls")]
+fn default_verify_certificates() -> bool {
+    true
+}
+
+// How long fetching the config over http(s) may take as a whole, so a stuck
+// server doesn't hang startup
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl Config {
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        toml::from_str(contents)
+            .map_err(|e| Error::Config(format!("Failed to parse config: {}", e)))
+    }
+
+    // The config can come from a file path, stdin ("-") or an http(s) URL
+    pub fn load(source: &str) -> Result<Self, Error> {
+        let config = Self::parse(&read_config_source(source, io::stdin(), FETCH_TIMEOUT)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Catches values that parse fine but make no sense, so they're reported
+    // on startup rather than showing up as odd behavior later on
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.endpoint.is_empty() {
+            return Err(Error::Config("No endpoint to listen on".into()));
+        }
+        if self
+            .endpoint
+            .iter()
+            .any(|endpoint| endpoint.trim().is_empty())
+        {
+            return Err(Error::Config("Endpoints can't be empty".into()));
+        }
+        if self.credentials.iter().any(|c| c.username.is_empty()) {
+            return Err(Error::Config("Credentials need a username".into()));
+        }
+        let timeouts = [
+            ("bind_timeout_secs", self.bind_timeout_secs),
+            ("handshake_timeout_secs", self.handshake_timeout_secs),
+            ("idle_timeout_secs", self.idle_timeout_secs),
+            ("max_session_duration_secs", self.max_session_duration_secs),
+        ];
+        for (key, value) in timeouts.iter() {
+            if *value == Some(0) {
+                return Err(Error::Config(format!("{} must be greater than zero", key)));
+            }
+        }
+        if self.proxy_buffer_size == Some(0) {
+            return Err(Error::Config(
+                "proxy_buffer_size must be greater than zero".into(),
+            ));
+        }
+        if self.rate_limit_bytes_per_sec == Some(0) {
+            return Err(Error::Config(
+                "rate_limit_bytes_per_sec must be greater than zero".into(),
+            ));
+        }
+        let limits = [
+            ("max_connections", self.max_connections),
+            ("max_concurrent_connects", self.max_concurrent_connects),
+        ];
+        for (key, value) in limits.iter() {
+            if *value == Some(0) {
+                return Err(Error::Config(format!("{} must be greater than zero", key)));
+            }
+        }
+        if self.connect_queue_timeout_secs == 0 {
+            return Err(Error::Config(
+                "connect_queue_timeout_secs must be greater than zero".into(),
+            ));
+        }
+        // Keepalives are set through the raw socket, which is only done on unix
+        #[cfg(not(unix))]
+        if self.tcp_keepalive.as_ref().is_some_and(|k| k.enabled) {
+            return Err(Error::Config(
+                "tcp_keepalive is only supported on unix".into(),
+            ));
+        }
+        #[cfg(feature = "tls")]
+        self.validate_tls_files()?;
+        let rate_limits = [
+            ("connection_rate_limit", &self.connection_rate_limit),
+            (
+                "per_ip_connection_rate_limit",
+                &self.per_ip_connection_rate_limit,
+            ),
+        ];
+        for (key, limit) in rate_limits.iter() {
+            if let Some(limit) = limit {
+                if limit.per_second <= 0.0 || limit.burst == 0 {
+                    return Err(Error::Config(format!(
+                        "{} needs a per_second and burst greater than zero",
+                        key
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    fn validate_tls_files(&self) -> Result<(), Error> {
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(Error::Config(
+                "tls_cert and tls_key have to be set together".into(),
+            ));
+        }
+        let mut files = vec![("tls_cert", &self.tls_cert), ("tls_key", &self.tls_key)];
+        for rule in &self.destination_rules {
+            if let Some(tls) = &rule.tls {
+                if tls.ca_file.is_some() && !tls.verify_certificates {
+                    return Err(Error::Config(format!(
+                        "ca_file can't be used with verify_certificates = false for {}",
+                        rule.destination
+                    )));
+                }
+                files.push(("ca_file", &tls.ca_file));
+            }
+        }
+        for (key, path) in files {
+            if let Some(path) = path {
+                if !Path::new(path).is_file() {
+                    return Err(Error::Config(format!("{} {} doesn't exist", key, path)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn build_context(&self) -> Result<Context, Error> {
+        self.validate()?;
+        let mut context = Context::default();
+        if self.credentials.is_empty() && self.credentials_file.is_none() {
+            info!("Using no authentication");
+        }
+        if let Some(path) = &self.credentials_file {
+            info!("Using credentials from {}", path);
+            context.add_credentials_file(path)?;
+        }
+        for c in &self.credentials {
+            info!("Using credentials: {}:xxx", c.username);
+            let credentials = match (&c.password, &c.password_hash) {
+                (Some(password), None) => Credentials::new(&c.username, password),
+                (None, Some(hash)) => Credentials::with_hash(&c.username, hash)?,
+                _ => {
+                    return Err(Error::Config(format!(
+                        "Credentials for {} need either password or password_hash",
+                        c.username
+                    )))
+                }
+            };
+            context.add_credentials(credentials);
+        }
+        if let Some(max_connects) = self.max_concurrent_connects {
+            info!(
+                "Allowing up to {} concurrent upstream connects",
+                max_connects
+            );
+            context.set_connect_budget(
+                max_connects,
+                Duration::from_secs(self.connect_queue_timeout_secs),
+            );
+        }
+        if let Some(retries) = self.connect_retries {
+            context.set_connect_retries(
+                retries,
+                Duration::from_millis(self.connect_retry_backoff_ms),
+            );
+        }
+        if let Some(max_connections) = self.max_connections {
+            info!("Serving up to {} connections at once", max_connections);
+            context.set_max_connections(max_connections, self.reject_over_max_connections);
+        }
+        context.set_log_rejected_methods(self.log_rejected_auth_methods);
+        context.set_reject_non_socks_quietly(self.reject_non_socks_quietly);
+        if let Some(versions) = &self.allowed_socks_versions {
+            if let Some(version) = versions.iter().find(|version| !matches!(version, 4 | 5)) {
+                return Err(Error::Config(format!(
+                    "Unsupported SOCKS version {}",
+                    version
+                )));
+            }
+            context.set_allowed_socks_versions(versions.clone());
+        }
+        if self.connection_rate_limit.is_some() || self.per_ip_connection_rate_limit.is_some() {
+            context.set_connection_rate_limits(
+                self.connection_rate_limit.as_ref().map(RateLimit::from),
+                self.per_ip_connection_rate_limit
+                    .as_ref()
+                    .map(RateLimit::from),
+            );
+        }
+        if let Some(quota) = &self.user_quota {
+            info!(
+                "Capping each user to {} bytes every {} seconds",
+                quota.bytes, quota.reset_interval_secs
+            );
+            context.set_user_quota(quota.bytes, Duration::from_secs(quota.reset_interval_secs));
+        }
+        if let Some(max_failures) = self.max_auth_failures {
+            info!(
+                "Locking out IPs for {} seconds after {} failed logins",
+                self.auth_lockout_secs, max_failures
+            );
+            context.set_auth_lockout(max_failures, Duration::from_secs(self.auth_lockout_secs));
+        }
+        context.set_upstream_socket_options(SocketOptions {
+            reuse_address: self.upstream_reuse_address,
+            bind_address_no_port: self.upstream_bind_address_no_port,
+            bind_address: self.outgoing_bind_address,
+        });
+        if let Some(address) = self.outgoing_bind_address {
+            info!("Connecting upstream from {}", address);
+        }
+        if let Some(capacity) = self.last_good_address_cache_size {
+            context.enable_last_good_addresses(capacity);
+        }
+        if let Some(capacity) = self.dns_cache_size {
+            context.enable_dns_cache(capacity, Duration::from_secs(self.dns_cache_max_ttl_secs));
+        }
+        if let Some(load_shedding) = &self.load_shedding {
+            context.set_load_shedding(LoadShedding {
+                max_active_connections: load_shedding.max_active_connections,
+                max_connect_latency_p95: load_shedding
+                    .max_connect_latency_p95_ms
+                    .map(Duration::from_millis),
+            });
+        }
+        if let Some(grace) = self.upstream_liveness_check_ms {
+            context.set_upstream_liveness_check(Duration::from_millis(grace));
+        }
+        if let Some(seconds) = self.bind_timeout_secs {
+            context.set_bind_timeout(Duration::from_secs(seconds));
+        }
+        context.set_tcp_nodelay(self.tcp_nodelay);
+        if let Some(keepalive) = self.tcp_keepalive.as_ref().filter(|k| k.enabled) {
+            context.set_tcp_keepalive(Keepalive {
+                idle: Duration::from_secs(keepalive.idle_secs),
+                interval: Duration::from_secs(keepalive.interval_secs),
+            });
+        }
+        if let Some(seconds) = self.handshake_timeout_secs {
+            context.set_handshake_timeout(Duration::from_secs(seconds));
+        }
+        if let Some(seconds) = self.idle_timeout_secs {
+            context.set_idle_timeout(Duration::from_secs(seconds));
+        }
+        if let Some(seconds) = self.max_session_duration_secs {
+            context.set_max_session_duration(Duration::from_secs(seconds));
+        }
+        context.set_shutdown_grace_period(Duration::from_secs(self.shutdown_grace_period_secs));
+        if let Some(seconds) = self.stats_interval_secs.filter(|seconds| *seconds > 0) {
+            context.set_stats_interval(Duration::from_secs(seconds));
+        }
+        if let Some(bytes_per_second) = self.rate_limit_bytes_per_sec {
+            context.set_bandwidth_limit(bytes_per_second);
+        }
+        if let Some(size) = self.proxy_buffer_size {
+            context.set_buffer_size(size);
+        }
+        if let Some(capacity) = self.rule_decision_cache_size {
+            context.enable_rule_decision_cache(
+                capacity,
+                Duration::from_secs(self.rule_decision_cache_ttl_secs),
+            );
+        }
+        for (target, replacement) in &self.route_overrides {
+            info!("Connecting to {} instead of {}", replacement, target);
+            context.add_route_override(parse_target(target)?, parse_target(replacement)?);
+        }
+        for rule in &self.destination_rules {
+            context.add_destination_rule(build_destination_rule(rule)?);
+        }
+        context.set_destination_acl(self.build_destination_acl()?);
+        if let Some(version) = self.send_proxy_protocol {
+            context.set_proxy_protocol(version);
+        }
+        if let Some(proxy) = &self.upstream_proxy {
+            let credentials = match (&proxy.username, &proxy.password) {
+                (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+                (None, None) => None,
+                _ => {
+                    return Err(Error::Config(
+                        "upstream_proxy needs both a username and a password".into(),
+                    ))
+                }
+            };
+            info!("Connecting upstream through {}", proxy.address);
+            context.set_upstream_proxy(UpstreamProxy {
+                address: proxy.address,
+                credentials,
+            });
+        }
+        #[cfg(feature = "tls")]
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            info!("Terminating client TLS using {}", cert);
+            let tls = ClientTls::from_files(cert, key)
+                .map_err(|e| Error::Config(format!("Failed to load TLS certificate: {}", e)))?;
+            context.set_client_tls(tls);
+        }
+        for address in &self.deny_self_connect {
+            context.add_self_address(*address);
+        }
+        for cidr in &self.allowed_source_cidrs {
+            context.allow_source_cidr(cidr.parse()?);
+        }
+        for cidr in &self.anonymous_allowed_cidrs {
+            context.allow_anonymous_cidr(cidr.parse()?);
+        }
+        if let Some(allowed_ports) = &self.allowed_ports {
+            let mut ports = PortSet::default();
+            for entry in allowed_ports {
+                match entry {
+                    ConfigPorts::Port(port) => ports.add(*port..=*port),
+                    ConfigPorts::Range(range) => ports.add_range(range)?,
+                }
+            }
+            context.set_allowed_ports(ports);
+        }
+        Ok(context)
+    }
+
+    fn build_destination_acl(&self) -> Result<DestinationAcl, Error> {
+        let mut acl = DestinationAcl::default();
+        for cidr in &self.allowed_cidrs {
+            acl.allow_cidr(cidr.parse()?);
+        }
+        for cidr in &self.denied_cidrs {
+            acl.deny_cidr(cidr.parse()?);
+        }
+        for domain in &self.denied_domains {
+            acl.deny_domain(domain.parse()?);
+        }
+        Ok(acl)
+    }
+}
+
+fn build_destination_rule(config: &ConfigDestinationRule) -> Result<DestinationRule, Error> {
+    let mut rule = DestinationRule::new(&config.destination, config.port);
+    if let Some(name) = &config.name {
+        rule.set_name(name);
+    }
+    if let Some(version) = config.send_proxy_protocol {
+        rule.set_proxy_protocol(version);
+    }
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &config.tls {
+        let mut upstream = match (&tls.ca_file, tls.verify_certificates) {
+            (_, false) => UpstreamTls::without_verification(),
+            (Some(ca_file), true) => UpstreamTls::with_ca_file(ca_file).map_err(|e| {
+                Error::Config(format!("Failed to load TLS CA file {}: {}", ca_file, e))
+            })?,
+            (None, true) => UpstreamTls::new(),
+        };
+        if let Some(name) = &tls.server_name {
+            upstream.set_server_name(name);
+        }
+        rule.set_tls(upstream);
+    }
+    Ok(rule)
+}
+
+fn read_config<R: Read>(mut reader: R) -> Result<String, Error> {
+    let mut contents = String::new();
+    reader
+        .read_to_string(&mut contents)
+        .map_err(|e| Error::Config(format!("Failed to read config: {}", e)))?;
+    Ok(contents)
+}
+
+fn fetch_config(url: &str, timeout: Duration) -> Result<String, Error> {
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let response = agent
+        .get(url)
+        .call()
+        .map_err(|e| Error::Config(format!("Failed to fetch config: {}", e)))?;
+    read_config(response.into_reader())
+}
+
+fn read_config_source<R: Read>(source: &str, stdin: R, timeout: Duration) -> Result<String, Error> {
+    if source == "-" {
+        read_config(stdin)
+    } else if source.starts_with("http://") || source.starts_with("https://") {
+        fetch_config(source, timeout)
+    } else {
+        fs::read_to_string(source)
+            .map_err(|e| Error::Config(format!("Failed to load config file: {}", e)))
+    }
+}
+
+// Splits "host:port" into its parts. IPv6 addresses go in brackets, like
+// "[::1]:80".
+fn parse_target(target: &str) -> Result<(Address, u16), Error> {
+    let invalid = || Error::Config(format!("Invalid target {}, expected host:port", target));
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let address = match host.strip_prefix('[') {
+        Some(host) => {
+            let host = host.strip_suffix(']').ok_or_else(invalid)?;
+            let ip: Ipv6Addr = host.parse().map_err(|_| invalid())?;
+            Address::Ip(ip.into())
+        }
+        // Anything with colons left would be an IPv6 address without brackets
+        None if host.is_empty() || host.contains(&['[', ']', ':'][..]) => return Err(invalid()),
+        None => match host.parse() {
+            Ok(ip) => Address::Ip(ip),
+            Err(_) => Address::Domain(host.into()),
+        },
+    };
+    Ok((address, port))
+}
+
+// How long `check` waits on the upstream proxy and the resolver
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Makes sure the server could start with this config, without starting it.
+// Every problem found is returned rather than stopping at the first one.
+pub async fn check(config: &Config) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+    let context = match config.build_context() {
+        Ok(context) => Some(context),
+        Err(e) => {
+            problems.push(e.to_string());
+            None
+        }
+    };
+    // The listeners are dropped right away, releasing the endpoints
+    for endpoint in &config.endpoint {
+        if let Err(e) = Listener::bind(endpoint).await {
+            problems.push(format!("Failed to bind {}: {}", endpoint, e));
+        }
+    }
+    #[cfg_attr(not(feature = "metrics"), allow(unused_mut))]
+    let mut extra_endpoints = vec![("health_endpoint", &config.health_endpoint)];
+    #[cfg(feature = "metrics")]
+    extra_endpoints.push(("metrics_endpoint", &config.metrics_endpoint));
+    for (key, endpoint) in extra_endpoints {
+        if let Some(endpoint) = endpoint {
+            if let Err(e) = TcpListener::bind(endpoint.as_str()).await {
+                problems.push(format!("Failed to bind {} {}: {}", key, endpoint, e));
+            }
+        }
+    }
+    if let Some(proxy) = &config.upstream_proxy {
+        match timeout(PROBE_TIMEOUT, TcpStream::connect(proxy.address)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => problems.push(format!(
+                "Failed to connect to upstream_proxy {}: {}",
+                proxy.address, e
+            )),
+            Err(_) => problems.push(format!(
+                "Timed out connecting to upstream_proxy {}",
+                proxy.address
+            )),
+        }
+    }
+    // Route overrides are the only domains known up front, resolving them
+    // makes sure the resolver's working
+    if let Some(context) = &context {
+        for replacement in config.route_overrides.values() {
+            if let Ok((Address::Domain(domain), _)) = parse_target(replacement) {
+                match timeout(PROBE_TIMEOUT, context.resolver().resolve(&domain)).await {
+                    Ok(Ok(_)) => (),
+                    Ok(Err(e)) => problems.push(e.to_string()),
+                    Err(_) => problems.push(format!("Timed out resolving {}", domain)),
+                }
+            }
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net;
+    use std::thread;
+
+    const CONFIG: &str = "endpoint = \"127.0.0.1:1080\"\n";
+
+    #[test]
+    fn read_config_from_reader() {
+        let contents = read_config(CONFIG.as_bytes()).unwrap();
+        let config = Config::parse(&contents).unwrap();
+        assert_eq!(config.endpoint, vec!["127.0.0.1:1080"]);
+        assert!(config.credentials.is_empty());
+    }
+
+    #[test]
+    fn read_config_from_url() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/config.toml", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            // Consume the request headers
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                CONFIG.len(),
+                CONFIG
+            )
+            .unwrap();
+        });
+        let config = Config::load(&url).unwrap();
+        server.join().unwrap();
+        assert_eq!(config.endpoint, vec!["127.0.0.1:1080"]);
+    }
+
+    #[test]
+    fn read_config_from_stdin() {
+        let contents = read_config_source("-", CONFIG.as_bytes(), FETCH_TIMEOUT).unwrap();
+        assert_eq!(contents, CONFIG);
+    }
+
+    #[test]
+    fn fetching_config_times_out() {
+        // Accepts the connection but never answers
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/config.toml", listener.local_addr().unwrap());
+        let error = read_config_source(&url, io::empty(), Duration::from_millis(200));
+        assert!(error.is_err());
+    }
+
+    #[test]
+    fn parse_multiple_endpoints() {
+        let config = Config::parse("endpoint = [\"127.0.0.1:1080\", \"[::1]:1080\"]").unwrap();
+        assert_eq!(config.endpoint, vec!["127.0.0.1:1080", "[::1]:1080"]);
+        assert_eq!(config.ipv6_only, None);
+
+        let config = Config::parse("endpoint = \"[::]:1080\"\nipv6_only = false").unwrap();
+        assert_eq!(config.ipv6_only, Some(false));
+    }
+
+    #[test]
+    fn read_config_from_missing_file() {
+        assert!(Config::load("/nonexistent/config.toml").is_err());
+    }
+
+    #[tokio::test]
+    async fn check_valid_config() {
+        let config = Config::parse("endpoint = \"127.0.0.1:0\"").unwrap();
+        assert!(check(&config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_invalid_config() {
+        let config = Config::parse(
+            r#"
+            endpoint = "not an endpoint"
+
+            [[destination_rules]]
+            destination = "example.com"
+            [destination_rules.tls]
+            ca_file = "/nonexistent/ca.pem"
+            "#,
+        )
+        .unwrap();
+        let problems = check(&config).await.unwrap_err();
+        assert_eq!(problems.len(), if cfg!(feature = "tls") { 2 } else { 1 });
+    }
+
+    #[tokio::test]
+    async fn check_unreachable_upstream_and_busy_endpoints() {
+        // Nothing's listening on the port once it's dropped
+        let upstream = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_address = upstream.local_addr().unwrap();
+        drop(upstream);
+        let busy = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Config::parse(&format!(
+            r#"
+            endpoint = "127.0.0.1:0"
+            health_endpoint = "{}"
+            upstream_proxy = {{ address = "{}" }}
+            route_overrides = {{ "db:5432" = "nonexistent.invalid:5432" }}
+            "#,
+            busy.local_addr().unwrap(),
+            upstream_address
+        ))
+        .unwrap();
+        let problems = check(&config).await.unwrap_err();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with("Failed to bind health_endpoint"));
+        assert!(problems[1].starts_with("Failed to connect to upstream_proxy"));
+    }
+
+    #[test]
+    fn zero_buffer_size_rejected() {
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            proxy_buffer_size = 0
+            "#,
+        )
+        .unwrap();
+        assert!(config.build_context().is_err());
+    }
+
+    #[test]
+    fn invalid_values_rejected() {
+        let validate = |contents: &str| Config::parse(contents).unwrap().validate();
+        assert!(validate(CONFIG).is_ok());
+        assert!(validate("endpoint = []").is_err());
+        assert!(validate("endpoint = \"\"").is_err());
+        assert!(validate(&format!("{}idle_timeout_secs = 0", CONFIG)).is_err());
+        assert!(validate(&format!("{}handshake_timeout_secs = 0", CONFIG)).is_err());
+        assert!(validate(&format!("{}rate_limit_bytes_per_sec = 0", CONFIG)).is_err());
+        assert!(validate(&format!("{}max_connections = 0", CONFIG)).is_err());
+        assert!(validate(&format!("{}max_concurrent_connects = 0", CONFIG)).is_err());
+        assert!(validate(&format!("{}connect_queue_timeout_secs = 0", CONFIG)).is_err());
+        let credentials = "[credentials]\nusername = \"\"\npassword = \"bar\"";
+        assert!(validate(&format!("{}{}", CONFIG, credentials)).is_err());
+        let rate_limit = "per_ip_connection_rate_limit = { per_second = 5, burst = 0 }";
+        assert!(validate(&format!("{}{}", CONFIG, rate_limit)).is_err());
+    }
+
+    #[test]
+    fn parse_destination_acl() {
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            allowed_cidrs = ["10.0.0.0/8"]
+            denied_cidrs = ["10.0.0.0/24"]
+            denied_domains = [".internal"]
+            allowed_source_cidrs = ["192.168.0.0/16"]
+            "#,
+        )
+        .unwrap();
+        let context = config.build_context().unwrap();
+        let acl = context.destination_acl();
+        assert!(acl.allows_ip("10.1.0.1".parse().unwrap()));
+        assert!(!acl.allows_ip("10.0.0.1".parse().unwrap()));
+        assert!(!acl.allows_domain("db.internal"));
+        assert!(context.allows_source("192.168.1.1".parse().unwrap()));
+        assert!(!context.allows_source("10.0.0.1".parse().unwrap()));
+
+        let invalid = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            denied_cidrs = ["10.0.0.0/40"]
+            "#,
+        )
+        .unwrap();
+        assert!(invalid.build_context().is_err());
+
+        // blocked_domains works as well
+        let invalid = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            blocked_domains = ["regex:[a-"]
+            "#,
+        )
+        .unwrap();
+        assert!(invalid.build_context().is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn parse_client_tls() {
+        let config = Config::parse(concat!(
+            "endpoint = \"127.0.0.1:0\"\n",
+            "tls_cert = \"",
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/localhost.pem\"\n",
+            "tls_key = \"",
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/localhost-key.pem\"\n",
+        ))
+        .unwrap();
+        assert!(config.build_context().unwrap().client_tls().is_some());
+
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            tls_cert = "cert.pem"
+            "#,
+        )
+        .unwrap();
+        assert!(config.build_context().is_err());
+    }
+
+    #[cfg(not(unix))]
+    #[test]
+    fn tcp_keepalive_rejected() {
+        let config = format!("{}tcp_keepalive = {{ idle_secs = 30 }}", CONFIG);
+        assert!(Config::parse(&config).unwrap().validate().is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn missing_tls_files_rejected() {
+        let validate = |contents: &str| Config::parse(contents).unwrap().validate();
+        let key = concat!(
+            "tls_key = \"",
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/localhost-key.pem\"\n"
+        );
+        let error = validate(&format!(
+            "{}{}tls_cert = \"/nonexistent/cert.pem\"",
+            CONFIG, key
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config: tls_cert /nonexistent/cert.pem doesn't exist"
+        );
+        let rule = "[[destination_rules]]\ndestination = \"example.com\"\n";
+        let tls = "[destination_rules.tls]\nca_file = \"/nonexistent/ca.pem\"\n";
+        assert!(validate(&format!("{}{}{}", CONFIG, rule, tls)).is_err());
+
+        let ca_file = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/ca.pem");
+        let tls = format!(
+            "[destination_rules.tls]\nca_file = \"{}\"\nverify_certificates = false\n",
+            ca_file
+        );
+        assert!(validate(&format!("{}{}{}", CONFIG, rule, tls)).is_err());
+    }
+
+    #[test]
+    fn parse_proxy_protocol() {
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            send_proxy_protocol = "v2"
+            [[destination_rules]]
+            destination = "example.com"
+            send_proxy_protocol = "v1"
+            "#,
+        )
+        .unwrap();
+        let context = config.build_context().unwrap();
+        assert_eq!(context.proxy_protocol(), Some(ProxyProtocol::V2));
+        let rule = context
+            .find_destination_rule(&Address::Domain("example.com".into()), 80)
+            .unwrap();
+        assert_eq!(rule.proxy_protocol(), Some(ProxyProtocol::V1));
+
+        assert!(Config::parse("endpoint = \"127.0.0.1:0\"\nsend_proxy_protocol = \"v3\"").is_err());
+    }
+
+    #[test]
+    fn parse_user_quota() {
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            user_quota = { bytes = 1024, reset_interval_secs = 86400 }
+            "#,
+        )
+        .unwrap();
+        let quota = config.user_quota.as_ref().unwrap();
+        assert_eq!(quota.bytes, 1024);
+        assert_eq!(quota.reset_interval_secs, 86400);
+        let context = config.build_context().unwrap();
+        context.record_user_traffic("foo", 1024);
+        assert!(context.user_quota_exceeded("foo"));
+    }
+
+    #[test]
+    fn parse_allowed_socks_versions() {
+        let context = Config::parse("endpoint = \"127.0.0.1:0\"\nallowed_socks_versions = [5]")
+            .unwrap()
+            .build_context()
+            .unwrap();
+        assert!(context.allows_socks_version(5));
+        assert!(!context.allows_socks_version(4));
+
+        let config =
+            Config::parse("endpoint = \"127.0.0.1:0\"\nallowed_socks_versions = [6]").unwrap();
+        assert!(config.build_context().is_err());
+    }
+
+    #[test]
+    fn parse_connect_retries() {
+        let context = Config::parse("endpoint = \"127.0.0.1:0\"\nconnect_retries = 3")
+            .unwrap()
+            .build_context()
+            .unwrap();
+        assert_eq!(context.connect_retries(), 3);
+        assert_eq!(context.connect_retry_backoff(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn parse_route_overrides() {
+        let context = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            route_overrides = { "prod.db:5432" = "staging.db:5433", "[::1]:80" = "10.0.0.1:8080" }
+            "#,
+        )
+        .unwrap()
+        .build_context()
+        .unwrap();
+        assert_eq!(
+            context.route_override(&Address::Domain("prod.db".into()), 5432),
+            Some(&(Address::Domain("staging.db".into()), 5433))
+        );
+        assert_eq!(
+            context.route_override(&Address::Ip("::1".parse().unwrap()), 80),
+            Some(&(Address::Ip("10.0.0.1".parse().unwrap()), 8080))
+        );
+        assert!(context
+            .route_override(&Address::Domain("prod.db".into()), 80)
+            .is_none());
+
+        assert_eq!(
+            context.route_override(&Address::Domain("PROD.db.".into()), 5432),
+            Some(&(Address::Domain("staging.db".into()), 5433))
+        );
+
+        for target in [
+            "prod.db",
+            "::1:80",
+            "[::1:80",
+            "::1]:80",
+            "[10.0.0.1]:80",
+            "[]:80",
+        ]
+        .iter()
+        {
+            let config = format!(
+                "endpoint = \"127.0.0.1:0\"\nroute_overrides = {{ \"{}\" = \"x:1\" }}",
+                target
+            );
+            assert!(
+                Config::parse(&config).unwrap().build_context().is_err(),
+                "{} accepted",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn parse_auth_lockout() {
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            max_auth_failures = 2
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.auth_lockout_secs, 300);
+        let context = config.build_context().unwrap();
+        let address = "10.0.0.1".parse().unwrap();
+        context.record_authentication(address, false);
+        assert!(!context.is_locked_out(address));
+        context.record_authentication(address, false);
+        assert!(context.is_locked_out(address));
+    }
+
+    #[test]
+    fn parse_upstream_proxy() {
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            upstream_proxy = { address = "10.0.0.1:1080", username = "foo", password = "bar" }
+            "#,
+        )
+        .unwrap();
+        let context = config.build_context().unwrap();
+        let proxy = context.upstream_proxy().unwrap();
+        assert_eq!(proxy.address, "10.0.0.1:1080".parse().unwrap());
+        assert_eq!(proxy.credentials, Some(("foo".into(), "bar".into())));
+
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            upstream_proxy = { address = "10.0.0.1:1080", username = "foo" }
+            "#,
+        )
+        .unwrap();
+        assert!(config.build_context().is_err());
+    }
+
+    #[test]
+    fn parse_outgoing_bind_address() {
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            outgoing_bind_address = "10.0.0.5"
+            "#,
+        )
+        .unwrap();
+        let context = config.build_context().unwrap();
+        assert_eq!(
+            context.outgoing_bind_address(),
+            Some("10.0.0.5".parse().unwrap())
+        );
+
+        assert!(
+            Config::parse("endpoint = \"127.0.0.1:0\"\noutgoing_bind_address = \"nope\"").is_err()
+        );
+    }
+
+    #[test]
+    fn parse_allowed_ports() {
+        let config = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            allowed_ports = [80, 443, "1024-65535"]
+            "#,
+        )
+        .unwrap();
+        let context = config.build_context().unwrap();
+        assert!(context.allows_port(443));
+        assert!(context.allows_port(8080));
+        assert!(!context.allows_port(22));
+
+        let context = Config::parse("endpoint = \"127.0.0.1:0\"")
+            .unwrap()
+            .build_context()
+            .unwrap();
+        assert!(context.allows_port(22));
+    }
+
+    #[tokio::test]
+    async fn parse_credentials() {
+        let single = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            [credentials]
+            username = "foo"
+            password = "bar"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(single.credentials.len(), 1);
+
+        let multiple = Config::parse(
+            r#"
+            endpoint = "127.0.0.1:0"
+            [[credentials]]
+            username = "foo"
+            password = "bar"
+            [[credentials]]
+            username = "baz"
+            password = "qux"
+            "#,
+        )
+        .unwrap();
+        let context = multiple.build_context().unwrap();
+        assert!(context.authenticate("foo", "bar").await);
+        assert!(context.authenticate("baz", "qux").await);
+    }
+}
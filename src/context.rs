@@ -1,51 +1,1209 @@
-use crate::messages::AuthenticationMethod;
+use crate::acl::{Cidr, DestinationAcl, PortSet};
+pub use crate::auth::Credentials;
+use crate::auth::{Authenticator, CredentialStore};
+use crate::chain::UpstreamProxy;
+use crate::error::Error;
+use crate::events::{EventListener, NoopListener};
+use crate::messages::{Address, AuthenticationMethod};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::proxy_protocol::ProxyProtocol;
+use crate::rate_limit::{AuthLockouts, ConnectionRateLimiter, RateLimit, UserQuotas};
+use crate::resolver::{CachingResolver, Resolver, SystemResolver};
+use crate::rules::{normalize_domain, DestinationRule, RuleDecisionCache};
+use crate::stats::{LoadShedding, Stats};
+#[cfg(feature = "tls")]
+use crate::tls::ClientTls;
+use crate::upstream::{self, Keepalive, LastGoodAddresses, SocketOptions};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+use tokio::time::timeout;
+use tracing::{debug, warn};
 
-pub struct Credentials {
-    username: String,
-    password: String,
+// Limits how many upstream connects can be in flight at the same time
+struct ConnectBudget {
+    slots: Semaphore,
+    timeout: Duration,
 }
 
-impl Credentials {
-    pub fn new(username: &str, password: &str) -> Self {
-        Credentials {
-            username: username.into(),
-            password: password.into(),
-        }
-    }
+// Holds one of the connect budget's slots until dropped
+pub struct ConnectSlot<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+// Limits how many client connections can be served at the same time
+struct ConnectionLimit {
+    slots: Arc<Semaphore>,
+    reject_when_full: bool,
+}
+
+// Holds one of the connection limit's slots until dropped. It's owned so it
+// can move into the connection's task, and it's released even if that task
+// panics.
+pub struct ConnectionSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+// What to do with UDP datagrams that have a nonzero FRAG field. Fragment
+// reassembly isn't supported so these are always dropped, the policy only
+// controls whether that's reported.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum FragmentPolicy {
+    #[default]
+    Drop,
+    Reject,
 }
 
 #[derive(Default)]
 pub struct Context {
-    credentials: Option<Credentials>,
+    // Swapped as a whole when reloading, while clients are authenticated
+    // against the previous set
+    credentials: RwLock<Arc<CredentialStore>>,
+    // What the credentials are rebuilt from when reloading
+    credential_files: Vec<String>,
+    inline_credentials: Vec<Credentials>,
+    authenticator: Option<Box<dyn Authenticator>>,
+    connect_budget: Option<ConnectBudget>,
+    connect_retries: u32,
+    connect_retry_backoff: Duration,
+    connection_limit: Option<ConnectionLimit>,
+    fragment_policy: FragmentPolicy,
+    abandoned_connects: AtomicU64,
+    destination_rules: Vec<DestinationRule>,
+    route_overrides: HashMap<(Address, u16), (Address, u16)>,
+    rule_decision_cache: Option<RuleDecisionCache>,
+    rule_evaluations: AtomicU64,
+    log_rejected_methods: bool,
+    reject_non_socks_quietly: bool,
+    allowed_socks_versions: Option<Vec<u8>>,
+    connection_rate_limiter: Option<ConnectionRateLimiter>,
+    user_quotas: Option<UserQuotas>,
+    auth_lockouts: Option<AuthLockouts>,
+    upstream_socket_options: SocketOptions,
+    proxy_protocol: Option<ProxyProtocol>,
+    upstream_proxy: Option<UpstreamProxy>,
+    destination_acl: DestinationAcl,
+    allowed_source_cidrs: Vec<Cidr>,
+    self_addresses: Vec<SocketAddr>,
+    anonymous_cidrs: Vec<Cidr>,
+    allowed_ports: Option<PortSet>,
+    #[cfg(feature = "tls")]
+    client_tls: Option<ClientTls>,
+    stats: Stats,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+    resolver: Option<Box<dyn Resolver>>,
+    event_listener: Option<Box<dyn EventListener>>,
+    last_good_addresses: Option<LastGoodAddresses>,
+    load_shedding: Option<LoadShedding>,
+    upstream_liveness_check: Option<Duration>,
+    bind_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_session_duration: Option<Duration>,
+    shutdown_grace_period: Option<Duration>,
+    stats_interval: Option<Duration>,
+    bandwidth_limit: Option<u64>,
+    buffer_size: Option<usize>,
+    tcp_nodelay: Option<bool>,
+    tcp_keepalive: Option<Keepalive>,
 }
 
+// How long BIND requests wait for the incoming connection by default
+const DEFAULT_BIND_TIMEOUT: Duration = Duration::from_secs(120);
+
+// How long clients get to send each message while setting up a connection
+// by default
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+
+// How long a proxied connection can go without reading anything in one
+// direction by default
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// The size of the buffer used for each direction of a proxied connection
+const DEFAULT_BUFFER_SIZE: usize = 4096;
+
+// How long active connections get to finish on shutdown by default
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
 impl Context {
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::default()
+    }
+
     pub fn with_credentials(credentials: Credentials) -> Self {
-        Context {
-            credentials: Some(credentials),
+        Context::builder().credentials(credentials).build()
+    }
+
+    // Reads credentials from a file with one username:password per line.
+    // Blank lines and lines starting with # are skipped.
+    pub fn with_credentials_file(path: &str) -> Result<Self, Error> {
+        Ok(Context::builder().credentials_file(path)?.build())
+    }
+
+    pub fn add_credentials_file(&mut self, path: &str) -> Result<(), Error> {
+        Arc::make_mut(self.credentials.get_mut().unwrap()).add_file(path)?;
+        self.credential_files.push(path.into());
+        Ok(())
+    }
+
+    // Once any credentials are added, clients have to authenticate with one
+    // of them
+    pub fn add_credentials(&mut self, credentials: Credentials) {
+        self.inline_credentials.push(credentials.clone());
+        Arc::make_mut(self.credentials.get_mut().unwrap()).add(credentials);
+    }
+
+    // Reads the credentials files again and swaps in what they contain,
+    // along with the credentials added directly. Clients already
+    // authenticated aren't affected. If reading fails, or ends up with no
+    // credentials at all, the current ones are kept. Returns how many
+    // credentials there are now.
+    pub fn reload_credentials(&self) -> Result<usize, Error> {
+        let mut credentials = CredentialStore::default();
+        for path in &self.credential_files {
+            credentials.add_file(path)?;
+        }
+        for inline in &self.inline_credentials {
+            credentials.add(inline.clone());
+        }
+        let count = credentials.len();
+        if count == 0 {
+            return Err(Error::Config(
+                "No credentials found, keeping the current ones".into(),
+            ));
         }
+        *self.credentials.write().unwrap() = Arc::new(credentials);
+        Ok(count)
+    }
+
+    // Replaces the credentials configured on the context as the way clients
+    // are authenticated
+    pub fn set_authenticator(&mut self, authenticator: Box<dyn Authenticator>) {
+        self.authenticator = Some(authenticator);
+    }
+
+    fn credentials(&self) -> Arc<CredentialStore> {
+        Arc::clone(&self.credentials.read().unwrap())
+    }
+
+    pub fn set_connect_budget(&mut self, max_connects: usize, timeout: Duration) {
+        self.connect_budget = Some(ConnectBudget {
+            slots: Semaphore::new(max_connects),
+            timeout,
+        });
+    }
+
+    // Retries upstream connects that fail with a refused connection or a
+    // timeout. The wait before each retry starts at `backoff` and doubles
+    // every time.
+    pub fn set_connect_retries(&mut self, retries: u32, backoff: Duration) {
+        self.connect_retries = retries;
+        self.connect_retry_backoff = backoff;
+    }
+
+    pub fn connect_retries(&self) -> u32 {
+        self.connect_retries
+    }
+
+    pub fn connect_retry_backoff(&self) -> Duration {
+        self.connect_retry_backoff
+    }
+
+    // Caps the number of connections served at once. Once at the limit, new
+    // connections either wait for a slot or are rejected right away.
+    pub fn set_max_connections(&mut self, max_connections: usize, reject_when_full: bool) {
+        self.connection_limit = Some(ConnectionLimit {
+            slots: Arc::new(Semaphore::new(max_connections)),
+            reject_when_full,
+        });
+    }
+
+    // Returns None if there's no slot available and the limit rejects new
+    // connections when full, otherwise waits for one
+    pub async fn acquire_connection_slot(&self) -> Option<ConnectionSlot> {
+        let limit = match &self.connection_limit {
+            Some(limit) => limit,
+            None => return Some(ConnectionSlot { _permit: None }),
+        };
+        let slots = Arc::clone(&limit.slots);
+        let permit = if limit.reject_when_full {
+            slots.try_acquire_owned().ok()?
+        } else {
+            slots.acquire_owned().await
+        };
+        Some(ConnectionSlot {
+            _permit: Some(permit),
+        })
     }
 
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn set_load_shedding(&mut self, load_shedding: LoadShedding) {
+        self.load_shedding = Some(load_shedding);
+    }
+
+    // Whether new requests should be rejected to keep the server responsive
+    pub fn is_overloaded(&self) -> bool {
+        match &self.load_shedding {
+            Some(load_shedding) => load_shedding.is_overloaded(&self.stats),
+            None => false,
+        }
+    }
+
+    pub fn set_fragment_policy(&mut self, policy: FragmentPolicy) {
+        self.fragment_policy = policy;
+    }
+
+    // Returns whether a UDP datagram with the given FRAG field should be relayed
+    pub fn accept_udp_fragment(&self, fragment: u8) -> bool {
+        if fragment == 0 {
+            return true;
+        }
+        if self.fragment_policy == FragmentPolicy::Reject {
+            warn!("Rejecting fragmented UDP datagram (FRAG = {})", fragment);
+        } else {
+            debug!("Dropping fragmented UDP datagram (FRAG = {})", fragment);
+        }
+        false
+    }
+
+    // Limits the rate of new connections, both overall and per source IP
+    pub fn set_connection_rate_limits(
+        &mut self,
+        global: Option<RateLimit>,
+        per_ip: Option<RateLimit>,
+    ) {
+        self.connection_rate_limiter = Some(ConnectionRateLimiter::new(global, per_ip));
+    }
+
+    // Returns whether a new connection from this address is within the
+    // configured rate limits
+    pub fn allow_connection(&self, address: IpAddr) -> bool {
+        match &self.connection_rate_limiter {
+            Some(limiter) => limiter.allow(address),
+            None => true,
+        }
+    }
+
+    // Caps the bytes each authenticated user can proxy every `window`
+    pub fn set_user_quota(&mut self, bytes: u64, window: Duration) {
+        self.user_quotas = Some(UserQuotas::new(bytes, window));
+    }
+
+    pub fn user_quota_exceeded(&self, user: &str) -> bool {
+        match &self.user_quotas {
+            Some(quotas) => quotas.is_exceeded(user),
+            None => false,
+        }
+    }
+
+    pub fn record_user_traffic(&self, user: &str, bytes: u64) {
+        if let Some(quotas) = &self.user_quotas {
+            quotas.record(user, bytes);
+        }
+    }
+
+    // Refuses connections from IPs that failed to authenticate
+    // `max_failures` times in a row, until `lockout` after the last failure
+    pub fn set_auth_lockout(&mut self, max_failures: u32, lockout: Duration) {
+        self.auth_lockouts = Some(AuthLockouts::new(max_failures, lockout));
+    }
+
+    pub fn is_locked_out(&self, address: IpAddr) -> bool {
+        match &self.auth_lockouts {
+            Some(lockouts) => lockouts.is_locked_out(address),
+            None => false,
+        }
+    }
+
+    pub fn record_authentication(&self, address: IpAddr, succeeded: bool) {
+        if let Some(lockouts) = &self.auth_lockouts {
+            match succeeded {
+                true => lockouts.record_success(address),
+                false => lockouts.record_failure(address),
+            }
+        }
+    }
+
+    // Only lets clients connect to these destination ports. All of them
+    // are allowed by default.
+    pub fn set_allowed_ports(&mut self, ports: PortSet) {
+        self.allowed_ports = Some(ports);
+    }
+
+    pub fn allows_port(&self, port: u16) -> bool {
+        match &self.allowed_ports {
+            Some(ports) => ports.contains(port),
+            None => true,
+        }
+    }
+
+    // Once any networks are allowed, only clients from those can connect
+    pub fn allow_source_cidr(&mut self, cidr: Cidr) {
+        self.allowed_source_cidrs.push(cidr);
+    }
+
+    // Clients from these networks may skip authentication, as long as they
+    // don't offer username/password
+    pub fn allow_anonymous_cidr(&mut self, cidr: Cidr) {
+        self.anonymous_cidrs.push(cidr);
+    }
+
+    pub fn allows_source(&self, address: IpAddr) -> bool {
+        self.allowed_source_cidrs.is_empty()
+            || self
+                .allowed_source_cidrs
+                .iter()
+                .any(|cidr| cidr.contains(address))
+    }
+
+    // Connecting to any of these is refused, so clients can't make the proxy
+    // connect to itself over and over. The listening addresses are added
+    // here, along with any other address that ends up reaching them.
+    pub fn add_self_address(&mut self, address: SocketAddr) {
+        self.self_addresses.push(address);
+    }
+
+    pub fn is_self_address(&self, address: SocketAddr) -> bool {
+        self.self_addresses.iter().any(|own| {
+            own.port() == address.port()
+                && (own.ip() == address.ip()
+                    // When listening on every interface, loopback surely is
+                    // one of them
+                    || (own.ip().is_unspecified()
+                        && (address.ip().is_loopback() || address.ip().is_unspecified())))
+        })
+    }
+
+    // Clients have to connect over TLS, which is terminated by the proxy
+    #[cfg(feature = "tls")]
+    pub fn set_client_tls(&mut self, tls: ClientTls) {
+        self.client_tls = Some(tls);
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn client_tls(&self) -> Option<&ClientTls> {
+        self.client_tls.as_ref()
+    }
+
+    pub fn set_upstream_socket_options(&mut self, options: SocketOptions) {
+        self.upstream_socket_options = options;
+    }
+
+    // Upstream connections originate from this address, rather than the one
+    // the routing table picks
+    pub fn set_outgoing_bind_address(&mut self, address: IpAddr) {
+        self.upstream_socket_options.bind_address = Some(address);
+    }
+
+    pub fn outgoing_bind_address(&self) -> Option<IpAddr> {
+        self.upstream_socket_options.bind_address
+    }
+
+    pub fn upstream_socket_options(&self) -> &SocketOptions {
+        &self.upstream_socket_options
+    }
+
+    // Restricts which destinations clients can connect to
+    pub fn set_destination_acl(&mut self, acl: DestinationAcl) {
+        self.destination_acl = acl;
+    }
+
+    pub fn destination_acl(&self) -> &DestinationAcl {
+        &self.destination_acl
+    }
+
+    // Sends a PROXY protocol header carrying the client's address on every
+    // upstream connection, unless a destination rule says otherwise
+    pub fn set_proxy_protocol(&mut self, version: ProxyProtocol) {
+        self.proxy_protocol = Some(version);
+    }
+
+    pub fn proxy_protocol(&self) -> Option<ProxyProtocol> {
+        self.proxy_protocol
+    }
+
+    // Tunnels every upstream connection through another SOCKS5 proxy rather
+    // than connecting to destinations directly
+    pub fn set_upstream_proxy(&mut self, proxy: UpstreamProxy) {
+        self.upstream_proxy = Some(proxy);
+    }
+
+    pub fn upstream_proxy(&self) -> Option<&UpstreamProxy> {
+        self.upstream_proxy.as_ref()
+    }
+
+    // Replaces the system's resolver as the way domains clients ask for are
+    // resolved
+    pub fn set_resolver(&mut self, resolver: Box<dyn Resolver>) {
+        self.resolver = Some(resolver);
+    }
+
+    pub fn resolver(&self) -> &dyn Resolver {
+        match &self.resolver {
+            Some(resolver) => resolver.as_ref(),
+            None => &SystemResolver,
+        }
+    }
+
+    // Gets called as connections go through their states
+    pub fn set_event_listener(&mut self, listener: Box<dyn EventListener>) {
+        self.event_listener = Some(listener);
+    }
+
+    pub fn event_listener(&self) -> &dyn EventListener {
+        match &self.event_listener {
+            Some(listener) => listener.as_ref(),
+            None => &NoopListener,
+        }
+    }
+
+    // Caches what the resolver returns for up to `capacity` domains, for as
+    // long as their TTL but at most `max_ttl`. Set a custom resolver first,
+    // it's the one being cached.
+    pub fn enable_dns_cache(&mut self, capacity: usize, max_ttl: Duration) {
+        let resolver = self
+            .resolver
+            .take()
+            .unwrap_or_else(|| Box::new(SystemResolver));
+        self.resolver = Some(Box::new(CachingResolver::new(resolver, capacity, max_ttl)));
+    }
+
+    // Remembers the last address that worked for up to `capacity` domains,
+    // trying it first on later connects
+    pub fn enable_last_good_addresses(&mut self, capacity: usize) {
+        self.last_good_addresses = Some(LastGoodAddresses::new(capacity));
+    }
+
+    pub fn last_good_addresses(&self) -> Option<&LastGoodAddresses> {
+        self.last_good_addresses.as_ref()
+    }
+
+    // Before replying success to a request, wait up to this long to make sure
+    // the upstream doesn't close the connection right after accepting it
+    pub fn set_upstream_liveness_check(&mut self, grace: Duration) {
+        self.upstream_liveness_check = Some(grace);
+    }
+
+    pub fn upstream_liveness_check(&self) -> Option<Duration> {
+        self.upstream_liveness_check
+    }
+
+    pub fn set_bind_timeout(&mut self, timeout: Duration) {
+        self.bind_timeout = Some(timeout);
+    }
+
+    pub fn bind_timeout(&self) -> Duration {
+        self.bind_timeout.unwrap_or(DEFAULT_BIND_TIMEOUT)
+    }
+
+    // Applies to every message clients send before proxying starts
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = Some(timeout);
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT)
+    }
+
+    // Whether Nagle's algorithm is disabled on client and upstream sockets,
+    // which it is by default
+    pub fn set_tcp_nodelay(&mut self, nodelay: bool) {
+        self.tcp_nodelay = Some(nodelay);
+    }
+
+    pub fn tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay.unwrap_or(true)
+    }
+
+    // Enables TCP keepalive on client and upstream sockets
+    pub fn set_tcp_keepalive(&mut self, keepalive: Keepalive) {
+        self.tcp_keepalive = Some(keepalive);
+    }
+
+    pub fn tcp_keepalive(&self) -> Option<&Keepalive> {
+        self.tcp_keepalive.as_ref()
+    }
+
+    // Applies the socket options to a client or upstream TCP stream. It has
+    // to happen before the stream is split into a `Stream`.
+    pub fn configure_tcp_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.tcp_nodelay())?;
+        if let Some(keepalive) = &self.tcp_keepalive {
+            upstream::set_keepalive(stream, keepalive)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT)
+    }
+
+    // Closes proxied connections once they've lasted this long, even if
+    // they're busy
+    pub fn set_max_session_duration(&mut self, duration: Duration) {
+        self.max_session_duration = Some(duration);
+    }
+
+    pub fn max_session_duration(&self) -> Option<Duration> {
+        self.max_session_duration
+    }
+
+    // How long active connections get to finish once the server starts
+    // shutting down
+    pub fn set_shutdown_grace_period(&mut self, grace_period: Duration) {
+        self.shutdown_grace_period = Some(grace_period);
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        self.shutdown_grace_period
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD)
+    }
+
+    // Has the server log a summary of its stats this often while it's serving
+    pub fn set_stats_interval(&mut self, interval: Duration) {
+        self.stats_interval = Some(interval);
+    }
+
+    pub fn stats_interval(&self) -> Option<Duration> {
+        self.stats_interval
+    }
+
+    // Caps each direction of every connection to this many bytes per second
+    pub fn set_bandwidth_limit(&mut self, bytes_per_second: u64) {
+        self.bandwidth_limit = Some(bytes_per_second);
+    }
+
+    pub fn bandwidth_limit(&self) -> Option<u64> {
+        self.bandwidth_limit
+    }
+
+    // Zero sized buffers can't make progress, so they're ignored
+    pub fn set_buffer_size(&mut self, size: usize) {
+        if size > 0 {
+            self.buffer_size = Some(size);
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE)
+    }
+
+    // Requests for `target` connect to `replacement` instead. Clients aren't
+    // told, as far as they know they reached the target.
+    pub fn add_route_override(&mut self, target: (Address, u16), replacement: (Address, u16)) {
+        let (address, port) = target;
+        self.route_overrides
+            .insert((route_key(&address), port), replacement);
+    }
+
+    pub fn route_override(&self, address: &Address, port: u16) -> Option<&(Address, u16)> {
+        self.route_overrides.get(&(route_key(address), port))
+    }
+
+    pub fn add_destination_rule(&mut self, rule: DestinationRule) {
+        self.destination_rules.push(rule);
+        // Decisions made against the old set of rules no longer hold
+        if let Some(cache) = &self.rule_decision_cache {
+            cache.clear();
+        }
+    }
+
+    pub fn enable_rule_decision_cache(&mut self, capacity: usize, ttl: Duration) {
+        self.rule_decision_cache = Some(RuleDecisionCache::new(capacity, ttl));
+    }
+
+    // Returns the first rule, in the order they were added, that matches
+    // the destination
+    pub fn find_destination_rule(&self, address: &Address, port: u16) -> Option<&DestinationRule> {
+        if let Some(index) = self
+            .rule_decision_cache
+            .as_ref()
+            .and_then(|cache| cache.get(address, port))
+        {
+            return index.map(|index| &self.destination_rules[index]);
+        }
+        self.rule_evaluations.fetch_add(1, Ordering::Relaxed);
+        let index = self
+            .destination_rules
+            .iter()
+            .position(|rule| rule.matches(address, port));
+        if let Some(cache) = &self.rule_decision_cache {
+            cache.insert(address, port, index);
+        }
+        index.map(|index| &self.destination_rules[index])
+    }
+
+    // How many times the destination rules have been evaluated, as opposed
+    // to answered from the decision cache
+    pub fn rule_evaluations(&self) -> u64 {
+        self.rule_evaluations.load(Ordering::Relaxed)
+    }
+
+    // Counts a client going away while its upstream connect was in flight,
+    // returning how many times it's happened so far
+    pub fn record_abandoned_connect(&self) -> u64 {
+        self.abandoned_connects.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Waits for a slot to connect upstream. Returns None if none became
+    // available before the budget's timeout.
+    pub async fn acquire_connect_slot(&self) -> Option<ConnectSlot<'_>> {
+        match &self.connect_budget {
+            Some(budget) => timeout(budget.timeout, budget.slots.acquire())
+                .await
+                .ok()
+                .map(|permit| ConnectSlot {
+                    _permit: Some(permit),
+                }),
+            None => Some(ConnectSlot { _permit: None }),
+        }
+    }
+
+    // Whether to log the methods offered by clients we find no acceptable
+    // authentication method for
+    pub fn set_log_rejected_methods(&mut self, enabled: bool) {
+        self.log_rejected_methods = enabled;
+    }
+
+    pub fn log_rejected_methods(&self) -> bool {
+        self.log_rejected_methods
+    }
+
+    // Closes connections that don't start like SOCKS, like port scanners or
+    // HTTP clients, logging them at debug level rather than as errors
+    pub fn set_reject_non_socks_quietly(&mut self, enabled: bool) {
+        self.reject_non_socks_quietly = enabled;
+    }
+
+    pub fn reject_non_socks_quietly(&self) -> bool {
+        self.reject_non_socks_quietly
+    }
+
+    // Only serves clients speaking these SOCKS versions. Both 4 and 5 are
+    // allowed by default.
+    pub fn set_allowed_socks_versions(&mut self, versions: Vec<u8>) {
+        self.allowed_socks_versions = Some(versions);
+    }
+
+    pub fn allows_socks_version(&self, version: u8) -> bool {
+        match &self.allowed_socks_versions {
+            Some(versions) => versions.contains(&version),
+            None => true,
+        }
+    }
+
+    // Picks the method for a client coming from `client`, which is unknown
+    // for unix sockets. Clients allowed to be anonymous still authenticate
+    // when they offer to.
     pub fn select_authentication(
         &self,
-        methods: Vec<AuthenticationMethod>,
+        methods: &[AuthenticationMethod],
+        client: Option<IpAddr>,
     ) -> Option<AuthenticationMethod> {
-        let expected_method = match self.credentials {
-            Some(_) => AuthenticationMethod::UsernamePassword,
-            None => AuthenticationMethod::NoAuthentication,
+        let expected_method = match &self.authenticator {
+            Some(authenticator) => authenticator.supported_method(),
+            None => self.credentials().supported_method(),
         };
         if methods.contains(&expected_method) {
             return Some(expected_method);
         }
+        let anonymous_allowed = client.is_some_and(|address| {
+            self.anonymous_cidrs
+                .iter()
+                .any(|cidr| cidr.contains(address))
+        });
+        if anonymous_allowed && methods.contains(&AuthenticationMethod::NoAuthentication) {
+            return Some(AuthenticationMethod::NoAuthentication);
+        }
         None
     }
 
-    pub fn authenticate(&self, username: &str, password: &str) -> bool {
-        match &self.credentials {
-            Some(credentials) => {
-                credentials.username == username && credentials.password == password
-            }
-            None => true,
+    pub async fn authenticate(&self, username: &str, password: &str) -> bool {
+        match &self.authenticator {
+            Some(authenticator) => authenticator.authenticate(username, password).await,
+            None => self.credentials().authenticate(username, password).await,
+        }
+    }
+}
+
+// Route overrides are keyed by the normalized domain, so they apply however
+// clients write it
+fn route_key(address: &Address) -> Address {
+    match address {
+        Address::Domain(domain) => Address::Domain(normalize_domain(domain)),
+        Address::Ip(ip) => Address::Ip(*ip),
+    }
+}
+
+// Sets up a context through chained calls, each one doing what the setter
+// with the same name does
+#[derive(Default)]
+pub struct ContextBuilder {
+    context: Context,
+}
+
+impl ContextBuilder {
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.context.add_credentials(credentials);
+        self
+    }
+
+    pub fn credentials_file(mut self, path: &str) -> Result<Self, Error> {
+        self.context.add_credentials_file(path)?;
+        Ok(self)
+    }
+
+    pub fn authenticator(mut self, authenticator: Box<dyn Authenticator>) -> Self {
+        self.context.set_authenticator(authenticator);
+        self
+    }
+
+    pub fn connect_budget(mut self, max_connects: usize, timeout: Duration) -> Self {
+        self.context.set_connect_budget(max_connects, timeout);
+        self
+    }
+
+    pub fn connect_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.context.set_connect_retries(retries, backoff);
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize, reject_when_full: bool) -> Self {
+        self.context
+            .set_max_connections(max_connections, reject_when_full);
+        self
+    }
+
+    pub fn connection_rate_limits(
+        mut self,
+        global: Option<RateLimit>,
+        per_ip: Option<RateLimit>,
+    ) -> Self {
+        self.context.set_connection_rate_limits(global, per_ip);
+        self
+    }
+
+    pub fn user_quota(mut self, bytes: u64, window: Duration) -> Self {
+        self.context.set_user_quota(bytes, window);
+        self
+    }
+
+    pub fn auth_lockout(mut self, max_failures: u32, lockout: Duration) -> Self {
+        self.context.set_auth_lockout(max_failures, lockout);
+        self
+    }
+
+    pub fn allowed_ports(mut self, ports: PortSet) -> Self {
+        self.context.set_allowed_ports(ports);
+        self
+    }
+
+    pub fn destination_acl(mut self, acl: DestinationAcl) -> Self {
+        self.context.set_destination_acl(acl);
+        self
+    }
+
+    pub fn destination_rule(mut self, rule: DestinationRule) -> Self {
+        self.context.add_destination_rule(rule);
+        self
+    }
+
+    pub fn resolver(mut self, resolver: Box<dyn Resolver>) -> Self {
+        self.context.set_resolver(resolver);
+        self
+    }
+
+    pub fn event_listener(mut self, listener: Box<dyn EventListener>) -> Self {
+        self.context.set_event_listener(listener);
+        self
+    }
+
+    pub fn upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.context.set_upstream_proxy(proxy);
+        self
+    }
+
+    pub fn upstream_socket_options(mut self, options: SocketOptions) -> Self {
+        self.context.set_upstream_socket_options(options);
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn client_tls(mut self, tls: ClientTls) -> Self {
+        self.context.set_client_tls(tls);
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.context.set_handshake_timeout(timeout);
+        self
+    }
+
+    pub fn bind_timeout(mut self, timeout: Duration) -> Self {
+        self.context.set_bind_timeout(timeout);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.context.set_idle_timeout(timeout);
+        self
+    }
+
+    pub fn max_session_duration(mut self, duration: Duration) -> Self {
+        self.context.set_max_session_duration(duration);
+        self
+    }
+
+    pub fn shutdown_grace_period(mut self, grace_period: Duration) -> Self {
+        self.context.set_shutdown_grace_period(grace_period);
+        self
+    }
+
+    pub fn stats_interval(mut self, interval: Duration) -> Self {
+        self.context.set_stats_interval(interval);
+        self
+    }
+
+    pub fn bandwidth_limit(mut self, bytes_per_second: u64) -> Self {
+        self.context.set_bandwidth_limit(bytes_per_second);
+        self
+    }
+
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.context.set_buffer_size(size);
+        self
+    }
+
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.context.set_tcp_nodelay(nodelay);
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.context.set_tcp_keepalive(keepalive);
+        self
+    }
+
+    pub fn build(self) -> Context {
+        self.context
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tcp_pair;
+    use argon2::Argon2;
+    use std::fs;
+
+    #[tokio::test]
+    async fn build_context() {
+        let mut ports = PortSet::default();
+        ports.add(443..=443);
+        let context = Context::builder()
+            .credentials(Credentials::new("foo", "bar"))
+            .allowed_ports(ports)
+            .handshake_timeout(Duration::from_secs(5))
+            .max_session_duration(Duration::from_secs(60))
+            .tcp_nodelay(false)
+            .build();
+        assert!(context.authenticate("foo", "bar").await);
+        assert!(context.allows_port(443));
+        assert!(!context.allows_port(80));
+        assert_eq!(context.handshake_timeout(), Duration::from_secs(5));
+        assert_eq!(
+            context.max_session_duration(),
+            Some(Duration::from_secs(60))
+        );
+        assert!(!context.tcp_nodelay());
+    }
+
+    #[tokio::test]
+    async fn tcp_nodelay_applied() {
+        let (stream, _) = tcp_pair().await;
+        let mut context = Context::default();
+        context.configure_tcp_stream(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        context.set_tcp_nodelay(false);
+        context.configure_tcp_stream(&stream).unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn authenticate_any_credentials() {
+        let mut context = Context::with_credentials(Credentials::new("foo", "bar"));
+        context.add_credentials(Credentials::new("baz", "qux"));
+        assert!(context.authenticate("foo", "bar").await);
+        assert!(context.authenticate("baz", "qux").await);
+        assert!(!context.authenticate("foo", "qux").await);
+        assert!(!context.authenticate("quux", "bar").await);
+        assert_eq!(
+            context.select_authentication(&[AuthenticationMethod::UsernamePassword], None),
+            Some(AuthenticationMethod::UsernamePassword)
+        );
+    }
+
+    #[tokio::test]
+    async fn credentials_file() {
+        let path = std::env::temp_dir().join(format!("rusty-socks-{}.passwd", std::process::id()));
+        fs::write(&path, "# users\nfoo:bar\n\nbaz:qux:quux\n").unwrap();
+        let context = Context::with_credentials_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(context.authenticate("foo", "bar").await);
+        assert!(context.authenticate("baz", "qux:quux").await);
+        assert!(!context.authenticate("foo", "qux").await);
+    }
+
+    #[tokio::test]
+    async fn reload_credentials_file() {
+        let path =
+            std::env::temp_dir().join(format!("rusty-socks-reload-{}.passwd", std::process::id()));
+        fs::write(&path, "foo:bar\n").unwrap();
+        let mut context = Context::with_credentials_file(path.to_str().unwrap()).unwrap();
+        context.add_credentials(Credentials::new("admin", "secret"));
+        fs::write(&path, "baz:qux\n").unwrap();
+        assert_eq!(context.reload_credentials().unwrap(), 2);
+        assert!(!context.authenticate("foo", "bar").await);
+        assert!(context.authenticate("baz", "qux").await);
+        assert!(context.authenticate("admin", "secret").await);
+
+        // A file that can't be read keeps the current credentials
+        fs::remove_file(&path).unwrap();
+        assert!(context.reload_credentials().is_err());
+        assert!(context.authenticate("baz", "qux").await);
+    }
+
+    #[tokio::test]
+    async fn empty_reload_keeps_credentials() {
+        let path = std::env::temp_dir().join(format!(
+            "rusty-socks-truncated-{}.passwd",
+            std::process::id()
+        ));
+        fs::write(&path, "foo:bar\n").unwrap();
+        let context = Context::with_credentials_file(path.to_str().unwrap()).unwrap();
+        // Caught halfway through being rewritten
+        fs::write(&path, "").unwrap();
+        assert!(context.reload_credentials().is_err());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            context.select_authentication(&[AuthenticationMethod::NoAuthentication], None),
+            None
+        );
+        assert!(context.authenticate("foo", "bar").await);
+    }
+
+    #[tokio::test]
+    async fn hashed_credentials() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+
+        let bcrypt_hash = bcrypt::hash("bar", 4).unwrap();
+        let mut context =
+            Context::with_credentials(Credentials::with_hash("foo", &bcrypt_hash).unwrap());
+        let salt = SaltString::encode_b64(b"saltsalt").unwrap();
+        let argon2_hash = Argon2::default()
+            .hash_password(b"qux", &salt)
+            .unwrap()
+            .to_string();
+        context.add_credentials(Credentials::with_hash("baz", &argon2_hash).unwrap());
+        assert!(context.authenticate("foo", "bar").await);
+        assert!(!context.authenticate("foo", "qux").await);
+        assert!(context.authenticate("baz", "qux").await);
+        assert!(!context.authenticate("baz", "bar").await);
+        assert!(!context.authenticate("quux", "bar").await);
+        assert!(Credentials::with_hash("foo", "$1$md5").is_err());
+    }
+
+    #[tokio::test]
+    async fn no_credentials_no_authentication() {
+        let context = Context::default();
+        assert!(context.authenticate("foo", "bar").await);
+        assert_eq!(
+            context.select_authentication(&[AuthenticationMethod::NoAuthentication], None),
+            Some(AuthenticationMethod::NoAuthentication)
+        );
+    }
+
+    struct OnlyAdmin;
+
+    #[async_trait::async_trait]
+    impl Authenticator for OnlyAdmin {
+        async fn authenticate(&self, username: &str, _password: &str) -> bool {
+            username == "admin"
+        }
+
+        fn supported_method(&self) -> AuthenticationMethod {
+            AuthenticationMethod::UsernamePassword
         }
     }
+
+    #[tokio::test]
+    async fn custom_authenticator() {
+        let mut context = Context::with_credentials(Credentials::new("foo", "bar"));
+        context.set_authenticator(Box::new(OnlyAdmin));
+        assert!(context.authenticate("admin", "anything").await);
+        assert!(!context.authenticate("foo", "bar").await);
+        assert_eq!(
+            context.select_authentication(
+                &[
+                    AuthenticationMethod::NoAuthentication,
+                    AuthenticationMethod::UsernamePassword
+                ],
+                None
+            ),
+            Some(AuthenticationMethod::UsernamePassword)
+        );
+    }
+
+    #[test]
+    fn anonymous_cidrs() {
+        let mut context = Context::with_credentials(Credentials::new("foo", "bar"));
+        context.allow_anonymous_cidr("10.0.0.0/8".parse().unwrap());
+        let trusted = Some("10.0.0.1".parse().unwrap());
+        let untrusted = Some("192.168.0.1".parse().unwrap());
+        let anonymous = [AuthenticationMethod::NoAuthentication];
+        let both = [
+            AuthenticationMethod::NoAuthentication,
+            AuthenticationMethod::UsernamePassword,
+        ];
+        assert_eq!(
+            context.select_authentication(&anonymous, trusted),
+            Some(AuthenticationMethod::NoAuthentication)
+        );
+        assert_eq!(
+            context.select_authentication(&both, trusted),
+            Some(AuthenticationMethod::UsernamePassword)
+        );
+        assert_eq!(context.select_authentication(&anonymous, untrusted), None);
+        assert_eq!(context.select_authentication(&anonymous, None), None);
+    }
+
+    #[test]
+    fn self_addresses() {
+        let mut context = Context::default();
+        context.add_self_address("0.0.0.0:1080".parse().unwrap());
+        context.add_self_address("10.0.0.1:8080".parse().unwrap());
+        assert!(context.is_self_address("127.0.0.1:1080".parse().unwrap()));
+        assert!(context.is_self_address("0.0.0.0:1080".parse().unwrap()));
+        assert!(context.is_self_address("10.0.0.1:8080".parse().unwrap()));
+        assert!(!context.is_self_address("127.0.0.1:8080".parse().unwrap()));
+        assert!(!context.is_self_address("10.0.0.2:1080".parse().unwrap()));
+    }
+
+    #[test]
+    fn allowed_sources() {
+        let mut context = Context::default();
+        assert!(context.allows_source("192.168.0.1".parse().unwrap()));
+        context.allow_source_cidr("10.0.0.0/8".parse().unwrap());
+        context.allow_source_cidr("::1".parse().unwrap());
+        assert!(context.allows_source("10.0.0.1".parse().unwrap()));
+        assert!(context.allows_source("::1".parse().unwrap()));
+        assert!(!context.allows_source("192.168.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cached_rule_decisions() {
+        let mut context = Context::default();
+        context.enable_rule_decision_cache(16, Duration::from_secs(60));
+        context.add_destination_rule(DestinationRule::new("example.com", None));
+        let address = Address::Domain("example.com".into());
+
+        assert!(context.find_destination_rule(&address, 80).is_some());
+        assert!(context.find_destination_rule(&address, 80).is_some());
+        assert_eq!(context.rule_evaluations(), 1);
+
+        // Changing the rules drops what was cached
+        context.add_destination_rule(DestinationRule::new("example.org", None));
+        assert!(context.find_destination_rule(&address, 80).is_some());
+        assert_eq!(context.rule_evaluations(), 2);
+    }
+
+    #[test]
+    fn fragmented_datagrams_dropped_by_default() {
+        let context = Context::default();
+        assert!(context.accept_udp_fragment(0));
+        assert!(!context.accept_udp_fragment(1));
+    }
+
+    #[test]
+    fn fragmented_datagrams_rejected() {
+        let mut context = Context::default();
+        context.set_fragment_policy(FragmentPolicy::Reject);
+        assert!(!context.accept_udp_fragment(3));
+    }
+
+    #[tokio::test]
+    async fn connect_slots_without_budget() {
+        let context = Context::default();
+        let _first = context.acquire_connect_slot().await.unwrap();
+        let _second = context.acquire_connect_slot().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_slots_queue_until_released() {
+        let mut context = Context::default();
+        context.set_connect_budget(1, Duration::from_secs(5));
+        let first = context.acquire_connect_slot().await.unwrap();
+        let second = context.acquire_connect_slot();
+        tokio::pin!(second);
+        assert!(timeout(Duration::from_millis(50), &mut second)
+            .await
+            .is_err());
+        drop(first);
+        let second = timeout(Duration::from_millis(50), second).await.unwrap();
+        assert!(second.is_some());
+    }
+
+    #[tokio::test]
+    async fn connect_slot_times_out() {
+        let mut context = Context::default();
+        context.set_connect_budget(1, Duration::from_millis(10));
+        let _first = context.acquire_connect_slot().await.unwrap();
+        assert!(context.acquire_connect_slot().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn connection_slots_rejected_when_full() {
+        let mut context = Context::default();
+        context.set_max_connections(1, true);
+        let first = context.acquire_connection_slot().await;
+        assert!(first.is_some());
+        assert!(context.acquire_connection_slot().await.is_none());
+        drop(first);
+        assert!(context.acquire_connection_slot().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn connection_slot_released_on_panic() {
+        let mut context = Context::default();
+        context.set_max_connections(1, false);
+        let slot = context.acquire_connection_slot().await.unwrap();
+        let task = tokio::spawn(async move {
+            let _slot = slot;
+            panic!("connection task failed");
+        });
+        assert!(task.await.is_err());
+        let slot = timeout(Duration::from_millis(50), context.acquire_connection_slot())
+            .await
+            .unwrap();
+        assert!(slot.is_some());
+    }
 }
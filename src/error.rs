@@ -1,3 +1,4 @@
+use crate::messages::ResponseCode;
 use std::borrow::Cow;
 use std::io;
 use thiserror::Error;
@@ -13,9 +14,110 @@ pub enum Error {
     #[error("io: {0}")]
     Io(#[from] io::Error),
 
+    #[error("config: {0}")]
+    Config(String),
+
     #[error("DNS: {0}")]
     DnsError(String),
 
     #[error("stream finished")]
     Finished,
+
+    #[error("idle timeout")]
+    IdleTimeout,
+
+    #[error("session timeout")]
+    SessionTimeout,
+
+    #[error("handshake timeout")]
+    HandshakeTimeout,
+
+    #[error("user quota exceeded")]
+    QuotaExceeded,
+
+    #[error("upstream proxy replied {0}")]
+    UpstreamProxyRejected(ResponseCode),
+}
+
+impl Error {
+    // The reply code that best describes the error to a client whose
+    // request failed because of it
+    pub fn to_response_code(&self) -> ResponseCode {
+        match self {
+            Error::Io(e) => match e.kind() {
+                io::ErrorKind::ConnectionRefused => ResponseCode::ConnectionRefused,
+                io::ErrorKind::TimedOut | io::ErrorKind::HostUnreachable => {
+                    ResponseCode::HostUnreachable
+                }
+                // Resolution failures and domains without addresses
+                io::ErrorKind::NotFound => ResponseCode::HostUnreachable,
+                io::ErrorKind::NetworkUnreachable => ResponseCode::NetworkUnreachable,
+                // Denied by the destination ACL or a local firewall
+                io::ErrorKind::PermissionDenied => ResponseCode::ConnectionNotAllowed,
+                _ => ResponseCode::GeneralFailure,
+            },
+            Error::DnsError(_) => ResponseCode::HostUnreachable,
+            // Whatever the upstream proxy said is passed on
+            Error::UpstreamProxyRejected(code) => *code,
+            _ => ResponseCode::GeneralFailure,
+        }
+    }
+
+    // Whether a connect that failed with this error may succeed if tried
+    // again shortly after
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_codes() {
+        let code = |kind| Error::from(io::Error::from(kind)).to_response_code();
+        assert_eq!(
+            code(io::ErrorKind::ConnectionRefused),
+            ResponseCode::ConnectionRefused
+        );
+        assert_eq!(code(io::ErrorKind::TimedOut), ResponseCode::HostUnreachable);
+        assert_eq!(code(io::ErrorKind::NotFound), ResponseCode::HostUnreachable);
+        assert_eq!(
+            code(io::ErrorKind::NetworkUnreachable),
+            ResponseCode::NetworkUnreachable
+        );
+        assert_eq!(
+            code(io::ErrorKind::PermissionDenied),
+            ResponseCode::ConnectionNotAllowed
+        );
+        assert_eq!(code(io::ErrorKind::Other), ResponseCode::GeneralFailure);
+        assert_eq!(
+            Error::DnsError("no addresses".into()).to_response_code(),
+            ResponseCode::HostUnreachable
+        );
+        assert_eq!(
+            Error::UpstreamProxyRejected(ResponseCode::TtlExpired).to_response_code(),
+            ResponseCode::TtlExpired
+        );
+        assert_eq!(
+            Error::MalformedMessage("bad".into()).to_response_code(),
+            ResponseCode::GeneralFailure
+        );
+    }
+
+    #[test]
+    fn transient_errors() {
+        let error = |kind| Error::from(io::Error::from(kind));
+        assert!(error(io::ErrorKind::ConnectionRefused).is_transient());
+        assert!(error(io::ErrorKind::TimedOut).is_transient());
+        assert!(!error(io::ErrorKind::PermissionDenied).is_transient());
+        assert!(!Error::UpstreamProxyRejected(ResponseCode::ConnectionRefused).is_transient());
+    }
 }
@@ -0,0 +1,39 @@
+use crate::error::Error;
+use crate::messages::Address;
+use crate::states::{LiveStats, ProxyStats, Session};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+
+// Gets told about each connection as it goes through its states. Implement
+// it to audit, notify or authorize connections from outside the crate. Every
+// hook does nothing by default.
+#[async_trait]
+pub trait EventListener: Send + Sync {
+    // A client connected. Unix socket clients have no address.
+    async fn on_connect(&self, _client: Option<SocketAddr>) {}
+
+    async fn on_authenticated(&self, _username: &str) {}
+
+    // A client asked to connect somewhere. Returning an error refuses the
+    // request with ConnectionNotAllowed.
+    async fn on_request(
+        &self,
+        _address: &Address,
+        _port: u16,
+        _user: Option<&str>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // A session started proxying. The stats are updated as data flows, keep
+    // a clone of them to sample the session's throughput.
+    async fn on_proxy_start(&self, _session: &Session, _stats: &LiveStats) {}
+
+    // A proxied session finished
+    async fn on_close(&self, _session: &Session, _stats: &ProxyStats) {}
+}
+
+// Used when no listener is set
+pub struct NoopListener;
+
+impl EventListener for NoopListener {}
@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+use tracing::{debug, warn};
+
+// How long to wait after failing to accept before trying again
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+// Health checkers that take longer than this to send their request are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Serves health checks over HTTP. /healthz succeeds as long as the process
+// is up, /ready only while `ready` is set.
+pub async fn serve(listener: TcpListener, ready: Arc<AtomicBool>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept health check connection: {}", e);
+                sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let ready = Arc::clone(&ready);
+        tokio::spawn(async move {
+            match timeout(REQUEST_TIMEOUT, handle_request(stream, &ready)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => debug!("Health check request failed: {}", e),
+                Err(_) => debug!("Health check request timed out"),
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, ready: &AtomicBool) -> std::io::Result<()> {
+    // Only the request line matters, the rest of the request is ignored
+    let mut buffer = [0; 1024];
+    let size = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..size]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let status = match path {
+        "/healthz" => "200 OK",
+        "/ready" if ready.load(Ordering::Relaxed) => "200 OK",
+        "/ready" => "503 Service Unavailable",
+        _ => "404 Not Found",
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown(std::net::Shutdown::Write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    async fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serve_health_checks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let ready = Arc::new(AtomicBool::new(false));
+        tokio::spawn(serve(listener, Arc::clone(&ready)));
+
+        assert!(get(address, "/healthz")
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(address, "/ready")
+            .await
+            .starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        ready.store(true, Ordering::Relaxed);
+        assert!(get(address, "/ready")
+            .await
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(address, "/")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -1,8 +1,33 @@
 #[macro_use]
 extern crate enum_primitive_derive;
 
+pub mod acl;
+pub mod auth;
+pub mod chain;
+pub mod client;
+pub mod config;
 pub mod context;
 pub mod error;
+pub mod events;
+pub mod health;
+pub mod listener;
+mod lru;
 pub mod messages;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(unix)]
+pub mod privileges;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod resolver;
+pub mod rules;
+pub mod server;
 pub mod states;
+pub mod stats;
 pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod upstream;
+
+#[cfg(test)]
+mod testing;
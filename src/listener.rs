@@ -0,0 +1,397 @@
+use crate::context::Context;
+use crate::error::Error;
+use crate::stream::Stream;
+use async_trait::async_trait;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(feature = "tls")]
+use tokio::time::timeout;
+
+// Endpoints starting with this are paths to unix sockets
+#[cfg(unix)]
+const UNIX_PREFIX: &str = "unix:";
+
+// The first file descriptor systemd passes when socket activating
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+// Where clients connect to, either a TCP address or a unix socket
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+// A unix socket listener. The socket file is removed when it's dropped.
+#[cfg(unix)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// A connection accepted by a listener
+pub enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    // Streams that are ready to use as they are, like in-memory ones
+    Stream(Stream, Option<SocketAddr>),
+}
+
+// Where the server accepts clients from. `Listener` is used for sockets, but
+// anything else, like a listener handing out in-memory streams in tests, can
+// implement it.
+#[async_trait]
+pub trait Accept: Send + Sync {
+    async fn accept(&self) -> io::Result<Connection>;
+}
+
+impl Listener {
+    // Binds either a TCP address or, for endpoints like "unix:/path/to.sock",
+    // a unix socket. A socket file left behind by a previous run is removed.
+    pub async fn bind(endpoint: &str) -> io::Result<Self> {
+        Listener::bind_with_ipv6_only(endpoint, None).await
+    }
+
+    // Like `bind`, but when `ipv6_only` is set, IPv6 endpoints accept IPv4
+    // clients only if it's false, rather than depending on the OS default
+    pub async fn bind_with_ipv6_only(endpoint: &str, ipv6_only: Option<bool>) -> io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = endpoint.strip_prefix(UNIX_PREFIX) {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)?;
+            return Ok(Listener::Unix(UnixSocket {
+                listener,
+                path: path.into(),
+            }));
+        }
+        match (endpoint.parse::<SocketAddr>(), ipv6_only) {
+            (Ok(address @ SocketAddr::V6(_)), Some(ipv6_only)) => {
+                Ok(Listener::Tcp(bind_ipv6(address, ipv6_only)?))
+            }
+            _ => Ok(Listener::Tcp(TcpListener::bind(endpoint).await?)),
+        }
+    }
+
+    // How many sockets systemd passed if the process was socket activated,
+    // following the LISTEN_FDS protocol. The variables are cleared so child
+    // processes don't think the sockets are theirs, which isn't safe to do
+    // once other threads are running, so this has to be called before the
+    // runtime is started.
+    #[cfg(unix)]
+    pub fn take_systemd_fd_count() -> io::Result<usize> {
+        let count = activated_fd_count(
+            std::env::var("LISTEN_PID").ok().as_deref(),
+            std::env::var("LISTEN_FDS").ok().as_deref(),
+            std::process::id(),
+        );
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+        count
+    }
+
+    // Takes the `count` sockets systemd passed, as returned by
+    // `take_systemd_fd_count`. Only TCP sockets are supported.
+    #[cfg(unix)]
+    pub fn from_systemd(count: usize) -> io::Result<Vec<Self>> {
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count as i32)
+            .map(|fd| Ok(Listener::Tcp(tcp_listener_from_fd(fd)?)))
+            .collect()
+    }
+
+    // Unix sockets have no address clients could ask the proxy to connect to
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                Ok(Connection::Tcp(stream, peer_addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(socket) => {
+                let (stream, _) = socket.listener.accept().await?;
+                Ok(Connection::Unix(stream))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Accept for Listener {
+    async fn accept(&self) -> io::Result<Connection> {
+        Listener::accept(self).await
+    }
+}
+
+#[async_trait]
+impl Accept for TcpListener {
+    async fn accept(&self) -> io::Result<Connection> {
+        let (stream, peer_addr) = TcpListener::accept(self).await?;
+        Ok(Connection::Tcp(stream, peer_addr))
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => write!(f, "{}", address),
+                Err(_) => write!(f, "unknown address"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(socket) => write!(f, "{}{}", UNIX_PREFIX, socket.path.display()),
+        }
+    }
+}
+
+impl Connection {
+    // Unix socket clients have no IP address
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Connection::Tcp(_, peer_addr) => Some(peer_addr.ip()),
+            #[cfg(unix)]
+            Connection::Unix(_) => None,
+            Connection::Stream(_, peer_addr) => peer_addr.map(|address| address.ip()),
+        }
+    }
+
+    // Builds the client's stream, performing the TLS handshake if the
+    // context requires it. TLS is only used over TCP, and the handshake is
+    // subject to the context's handshake timeout.
+    pub async fn into_stream(self, context: &Context) -> Result<Stream, Error> {
+        match self {
+            Connection::Tcp(stream, _) => {
+                context.configure_tcp_stream(&stream)?;
+                #[cfg(feature = "tls")]
+                if let Some(tls) = context.client_tls() {
+                    return timeout(context.handshake_timeout(), tls.accept(stream))
+                        .await
+                        .map_err(|_| Error::HandshakeTimeout)?;
+                }
+                Ok(Stream::buffered(stream))
+            }
+            #[cfg(unix)]
+            Connection::Unix(stream) => Ok(Stream::buffered_unix(stream)),
+            Connection::Stream(stream, _) => Ok(stream),
+        }
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Connection::Tcp(_, peer_addr) => write!(f, "{}", peer_addr),
+            #[cfg(unix)]
+            Connection::Unix(_) => write!(f, "unix socket"),
+            Connection::Stream(_, Some(peer_addr)) => write!(f, "{}", peer_addr),
+            Connection::Stream(_, None) => write!(f, "stream"),
+        }
+    }
+}
+
+// How many sockets systemd passed, given LISTEN_PID and LISTEN_FDS. They're
+// only meant for this process if LISTEN_PID is its own.
+#[cfg(unix)]
+fn activated_fd_count(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> io::Result<usize> {
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(0),
+    };
+    if pid.parse() != Ok(own_pid) {
+        return Ok(0);
+    }
+    fds.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid LISTEN_FDS: {}", fds),
+        )
+    })
+}
+
+#[cfg(unix)]
+fn tcp_listener_from_fd(fd: i32) -> io::Result<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: systemd hands over the fds starting at SD_LISTEN_FDS_START and
+    // nothing else in the process uses them
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    let is_tcp = socket.r#type().ok() == Some(Type::STREAM)
+        && socket
+            .local_addr()
+            .is_ok_and(|address| address.as_socket().is_some());
+    if !is_tcp {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("File descriptor {} is not a TCP socket", fd),
+        ));
+    }
+    socket.set_nonblocking(true)?;
+    let listener = std::net::TcpListener::from(socket);
+    TcpListener::from_std(listener)
+}
+
+fn bind_ipv6(address: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(ipv6_only)?;
+    // Same as what tokio does when it binds
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+// Removes the file at the path if it's a socket nothing's listening on, as
+// a listener that wasn't shut down cleanly leaves it behind. Anything else is
+// left alone, so that binding fails.
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixStream;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+            _ => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn bind_tcp() {
+        let listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.to_string();
+        let mut client = TcpStream::connect(&address).await.unwrap();
+        client.write_all(&[5]).await.unwrap();
+        let connection = listener.accept().await.unwrap();
+        assert_eq!(
+            connection.peer_ip(),
+            Some(client.local_addr().unwrap().ip())
+        );
+        let mut stream = connection.into_stream(&Context::default()).await.unwrap();
+        assert_eq!(stream.peek_u8().await.unwrap(), Some(5));
+    }
+
+    #[tokio::test]
+    async fn bind_ipv6_only() {
+        let listener = Listener::bind_with_ipv6_only("[::]:0", Some(true))
+            .await
+            .unwrap();
+        let port = listener.to_string().rsplit(':').next().unwrap().to_string();
+        assert!(TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .is_err());
+        assert!(TcpStream::connect(format!("[::1]:{}", port)).await.is_ok());
+
+        let listener = Listener::bind_with_ipv6_only("[::]:0", Some(false))
+            .await
+            .unwrap();
+        let port = listener.to_string().rsplit(':').next().unwrap().to_string();
+        assert!(TcpStream::connect(format!("127.0.0.1:{}", port))
+            .await
+            .is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_unix_socket() {
+        let path = std::env::temp_dir().join(format!("rusty-socks-{}.sock", std::process::id()));
+        let endpoint = format!("unix:{}", path.display());
+        // A socket left behind by a previous listener is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind(&endpoint).await.unwrap();
+        assert_eq!(listener.to_string(), endpoint);
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client.write_all(&[5]).await.unwrap();
+        let connection = listener.accept().await.unwrap();
+        assert_eq!(connection.peer_ip(), None);
+        let mut stream = connection.into_stream(&Context::default()).await.unwrap();
+        assert_eq!(stream.peek_u8().await.unwrap(), Some(5));
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn systemd_fd_count() {
+        assert_eq!(activated_fd_count(None, None, 10).unwrap(), 0);
+        assert_eq!(activated_fd_count(Some("10"), Some("2"), 10).unwrap(), 2);
+        // Meant for another process
+        assert_eq!(activated_fd_count(Some("11"), Some("2"), 10).unwrap(), 0);
+        assert!(activated_fd_count(Some("10"), Some("two"), 10).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn listener_from_inherited_fd() {
+        use std::os::unix::io::IntoRawFd;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let listener = Listener::Tcp(tcp_listener_from_fd(listener.into_raw_fd()).unwrap());
+        let _client = TcpStream::connect(address).await.unwrap();
+        assert!(listener.accept().await.is_ok());
+
+        // A UDP socket has an address too, but can't be listened on
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(tcp_listener_from_fd(socket.into_raw_fd()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn live_socket_not_replaced() {
+        let path = std::env::temp_dir().join(format!("rusty-socks-{}.live", std::process::id()));
+        let endpoint = format!("unix:{}", path.display());
+        let listener = Listener::bind(&endpoint).await.unwrap();
+        assert!(Listener::bind(&endpoint).await.is_err());
+        drop(listener);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn regular_file_not_replaced() {
+        let path = std::env::temp_dir().join(format!("rusty-socks-{}.file", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let result = Listener::bind(&format!("unix:{}", path.display())).await;
+        assert!(result.is_err());
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// A map holding up to a fixed number of entries, evicting the least recently
+// used one when full. Entries live in a slab linked by index in the order
+// they were used, so lookups, inserts and evictions are all constant time.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    indexes: HashMap<K, usize>,
+    nodes: Vec<Node<K, V>>,
+    // Most and least recently used nodes
+    head: Option<usize>,
+    tail: Option<usize>,
+}
+
+struct Node<K, V> {
+    key: K,
+    value: V,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+impl<K: Eq + Hash + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            indexes: HashMap::new(),
+            nodes: Vec::new(),
+            head: None,
+            tail: None,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let index = *self.indexes.get(key)?;
+        self.move_to_front(index);
+        Some(&self.nodes[index].value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(&index) = self.indexes.get(&key) {
+            self.nodes[index].value = value;
+            self.move_to_front(index);
+            return;
+        }
+        let index = if self.nodes.len() < self.capacity {
+            self.nodes.push(Node {
+                key: key.clone(),
+                value,
+                prev: None,
+                next: None,
+            });
+            self.nodes.len() - 1
+        } else {
+            // Full, so reuse the least recently used node
+            let index = self.tail.expect("full cache has a tail");
+            self.unlink(index);
+            let node = &mut self.nodes[index];
+            self.indexes.remove(&node.key);
+            node.key = key.clone();
+            node.value = value;
+            index
+        };
+        self.indexes.insert(key, index);
+        self.push_front(index);
+    }
+
+    pub fn clear(&mut self) {
+        self.indexes.clear();
+        self.nodes.clear();
+        self.head = None;
+        self.tail = None;
+    }
+
+    fn move_to_front(&mut self, index: usize) {
+        if self.head != Some(index) {
+            self.unlink(index);
+            self.push_front(index);
+        }
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let (prev, next) = (self.nodes[index].prev, self.nodes[index].next);
+        match prev {
+            Some(prev) => self.nodes[prev].next = next,
+            None => self.head = next,
+        }
+        match next {
+            Some(next) => self.nodes[next].prev = prev,
+            None => self.tail = prev,
+        }
+    }
+
+    fn push_front(&mut self, index: usize) {
+        self.nodes[index].prev = None;
+        self.nodes[index].next = self.head;
+        match self.head {
+            Some(head) => self.nodes[head].prev = Some(index),
+            None => self.tail = Some(index),
+        }
+        self.head = Some(index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.get(&"c"), Some(&3));
+    }
+
+    #[test]
+    fn replace_existing_entry() {
+        let mut cache = LruCache::new(1);
+        cache.insert("a", 1);
+        cache.insert("a", 2);
+        assert_eq!(cache.get(&"a"), Some(&2));
+    }
+
+    #[test]
+    fn evict_in_usage_order() {
+        let mut cache = LruCache::new(3);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+        cache.insert("a", 4);
+        cache.get(&"b");
+        cache.insert("d", 5);
+        assert_eq!(cache.get(&"c"), None);
+        cache.insert("e", 6);
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), Some(&2));
+        assert_eq!(cache.get(&"d"), Some(&5));
+        assert_eq!(cache.get(&"e"), Some(&6));
+
+        cache.clear();
+        assert_eq!(cache.get(&"b"), None);
+        cache.insert("f", 7);
+        assert_eq!(cache.get(&"f"), Some(&7));
+    }
+}
@@ -1,83 +1,198 @@
-use log::{info, warn, Level};
-use rusty_socks::context::{Context, Credentials};
-use rusty_socks::states::State;
-use rusty_socks::stream::Stream;
-use serde::Deserialize;
+use rusty_socks::config::{self, Config};
+use rusty_socks::context::Context;
+use rusty_socks::health;
+use rusty_socks::listener::Listener;
+#[cfg(feature = "metrics")]
+use rusty_socks::metrics;
+#[cfg(unix)]
+use rusty_socks::privileges;
+use rusty_socks::server;
 use std::env;
-use std::fs;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::net::TcpListener;
+use tokio::signal::ctrl_c;
+use tracing::Level;
+use tracing::{info, warn};
 
-#[derive(Deserialize)]
-struct Config {
-    endpoint: String,
-    credentials: Option<ConfigCredentials>,
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {:} [--check] <toml-file-path|-|url>", program);
+    exit(1);
 }
 
-#[derive(Deserialize)]
-struct ConfigCredentials {
-    username: String,
-    password: String,
-}
+// Resolves on Ctrl-C, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-fn load_config(filename: &str) -> Config {
-    let config_contents = fs::read_to_string(filename);
-    if config_contents.is_err() {
-        eprintln!("Failed to load config file");
-        exit(1);
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
     }
-    match toml::from_str(&config_contents.unwrap()) {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Failed to parse config: {:}", e);
-            exit(1)
+    #[cfg(not(unix))]
+    {
+        let _ = ctrl_c().await;
+    }
+}
+
+// Reloads the credentials every time SIGHUP is received
+#[cfg(unix)]
+async fn reload_credentials_on_hangup(context: Arc<Context>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        match context.reload_credentials() {
+            Ok(count) => info!("Reloaded credentials, {} loaded", count),
+            Err(e) => warn!(
+                "Failed to reload credentials, keeping the current ones: {}",
+                e
+            ),
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    simple_logger::init_with_level(Level::Debug).unwrap();
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Records from the log macros are picked up as well
+    tracing_subscriber::fmt()
+        .with_max_level(Level::DEBUG)
+        .init();
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!(
-            "Usage: {:} <toml-file-path>",
-            args.get(0).map(|s| s.as_str()).unwrap_or("rusty-socks")
-        );
-        exit(1);
-    }
-    let config = load_config(&args[1]);
-    let listener = TcpListener::bind(&config.endpoint).await?;
-    let context = match config.credentials {
-        Some(c) => {
-            info!("Using credentials: {}:xxx", c.username);
-            Context::with_credentials(Credentials::new(&c.username, &c.password))
+    let program = args.first().map(|s| s.as_str()).unwrap_or("rusty-socks");
+    let (check_only, source) = match args.as_slice() {
+        [_, source] => (false, source),
+        [_, flag, source] if flag == "--check" => (true, source),
+        _ => usage(program),
+    };
+    // Loaded before the runtime starts, as reading stdin or fetching the
+    // config over http blocks
+    let config = match Config::load(source) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
         }
-        None => {
-            info!("Using no authentication");
-            Context::default()
+    };
+    // Taken before the runtime starts its threads, as it changes the
+    // environment
+    #[cfg(unix)]
+    let systemd_fds = match Listener::take_systemd_fd_count() {
+        Ok(count) => count,
+        Err(e) => {
+            eprintln!("Failed to use the sockets passed by systemd: {}", e);
+            exit(1);
         }
     };
-    let context = Arc::new(context);
-    info!("Server running on endpoint {}", config.endpoint);
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let context = Arc::clone(&context);
-        tokio::spawn(async move {
-            let stream = Stream::buffered(stream);
-            let mut state = State::new(stream);
-            loop {
-                let result = state.process(&context).await;
-                if result.is_err() {
-                    warn!("Stream finished with error: {:?}", result.err().unwrap());
-                    break;
+    #[cfg(not(unix))]
+    let systemd_fds = 0;
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(config, check_only, systemd_fds))
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn run(
+    config: Config,
+    check_only: bool,
+    systemd_fds: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if check_only {
+        match config::check(&config).await {
+            Ok(()) => {
+                println!("Config is valid");
+                return Ok(());
+            }
+            Err(problems) => {
+                for problem in problems {
+                    eprintln!("{}", problem);
                 }
-                state = result.unwrap();
-                if state.is_finished() {
-                    break;
+                exit(1);
+            }
+        }
+    }
+    // Health checks are served before binding, /ready succeeds once the
+    // listeners are up
+    let ready = Arc::new(AtomicBool::new(false));
+    if let Some(endpoint) = &config.health_endpoint {
+        let health_listener = TcpListener::bind(endpoint).await?;
+        info!("Serving health checks on http://{}", endpoint);
+        tokio::spawn(health::serve(health_listener, Arc::clone(&ready)));
+    }
+    // Sockets passed by systemd take the place of the endpoints
+    #[cfg(unix)]
+    let mut listeners = match Listener::from_systemd(systemd_fds) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            eprintln!("Failed to use the sockets passed by systemd: {}", e);
+            exit(1);
+        }
+    };
+    #[cfg(not(unix))]
+    let mut listeners = Vec::new();
+    if listeners.is_empty() {
+        for endpoint in &config.endpoint {
+            match Listener::bind_with_ipv6_only(endpoint, config.ipv6_only).await {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => {
+                    eprintln!("Failed to bind {}: {}", endpoint, e);
+                    exit(1);
                 }
             }
-        });
+        }
+    }
+    let mut context = match config.build_context() {
+        Ok(context) => context,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    for address in listeners.iter().filter_map(Listener::local_addr) {
+        context.add_self_address(address);
+    }
+    let context = Arc::new(context);
+    #[cfg(unix)]
+    if config.credentials_file.is_some() {
+        tokio::spawn(reload_credentials_on_hangup(Arc::clone(&context)));
     }
+    #[cfg(feature = "metrics")]
+    if let Some(endpoint) = &config.metrics_endpoint {
+        let metrics_listener = TcpListener::bind(endpoint).await?;
+        info!("Serving metrics on http://{}/metrics", endpoint);
+        tokio::spawn(metrics::serve(
+            metrics_listener,
+            Arc::clone(context.metrics()),
+        ));
+    }
+    // Everything that might need root is bound by now
+    #[cfg(unix)]
+    if config.run_as_user.is_some() || config.run_as_group.is_some() {
+        let user = config.run_as_user.as_deref();
+        let group = config.run_as_group.as_deref();
+        if let Err(e) = privileges::drop_privileges(user, group) {
+            eprintln!("Failed to drop privileges: {}", e);
+            exit(1);
+        }
+        info!(
+            "Running as user {} and group {}",
+            user.unwrap_or("(unchanged)"),
+            group.unwrap_or("(user's)")
+        );
+    }
+    for listener in &listeners {
+        info!("Server running on endpoint {}", listener);
+    }
+    ready.store(true, Ordering::Relaxed);
+    let shutdown = async {
+        shutdown_signal().await;
+        // So load balancers stop sending clients while connections drain
+        ready.store(false, Ordering::Relaxed);
+    };
+    server::serve_all(listeners, context, shutdown).await?;
+    Ok(())
 }
@@ -2,16 +2,27 @@ use crate::error::Error;
 use async_trait::async_trait;
 use num_traits::FromPrimitive;
 use std::fmt;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use tokio::io::{AsyncRead, AsyncWrite};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::prelude::*;
+use tracing::{enabled, trace, Level};
+
+// The longest name DNS allows, in its textual form
+const MAX_DOMAIN_LENGTH: usize = 253;
 
 // Common types
 
 #[derive(Primitive, PartialEq, Debug, Copy, Clone)]
 pub enum AuthenticationMethod {
     NoAuthentication = 0,
+    // Known so it shows up among the offered methods, but never selected
+    Gssapi = 1,
     UsernamePassword = 2,
+    // Only ever sent by the server, when none of the offered methods work
+    NoAcceptableMethods = 0xff,
 }
 
 impl fmt::Display for AuthenticationMethod {
@@ -20,12 +31,14 @@ impl fmt::Display for AuthenticationMethod {
     }
 }
 
-#[derive(Debug, PartialEq, Primitive)]
+#[derive(Debug, PartialEq, Primitive, Copy, Clone)]
 pub enum Command {
     Connect = 1,
+    Bind = 2,
+    UdpAssociate = 3,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Address {
     Ip(IpAddr),
     Domain(String),
@@ -38,13 +51,43 @@ pub enum AddressType {
     Ipv6 = 4,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Primitive, PartialEq, Debug, Copy, Clone)]
 pub enum ResponseCode {
     Success = 0,
     GeneralFailure = 1,
+    ConnectionNotAllowed = 2,
+    NetworkUnreachable = 3,
+    HostUnreachable = 4,
+    ConnectionRefused = 5,
+    TtlExpired = 6,
+    CommandNotSupported = 7,
+    AddressTypeNotSupported = 8,
+}
+
+impl fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            ResponseCode::Success => "succeeded",
+            ResponseCode::GeneralFailure => "general failure",
+            ResponseCode::ConnectionNotAllowed => "connection not allowed by ruleset",
+            ResponseCode::NetworkUnreachable => "network unreachable",
+            ResponseCode::HostUnreachable => "host unreachable",
+            ResponseCode::ConnectionRefused => "connection refused",
+            ResponseCode::TtlExpired => "TTL expired",
+            ResponseCode::CommandNotSupported => "command not supported",
+            ResponseCode::AddressTypeNotSupported => "address type not supported",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Socks4ResponseCode {
+    Granted = 0x5a,
+    Rejected = 0x5b,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum AuthStatusCode {
     Success = 0,
     Failure = 1,
@@ -52,27 +95,33 @@ pub enum AuthStatusCode {
 
 // Messages
 
+#[derive(Debug, PartialEq)]
 pub struct HelloRequest {
     pub version: u8,
-    pub methods: Vec<AuthenticationMethod>,
+    // The method bytes as offered, including ones we don't know about
+    pub methods: Vec<u8>,
 }
 
+#[derive(Debug, PartialEq)]
 pub struct HelloResponse {
     pub version: u8,
     pub method: AuthenticationMethod,
 }
 
+#[derive(Debug, PartialEq)]
 pub struct AuthRequest {
     pub version: u8,
     pub username: String,
     pub password: String,
 }
 
+#[derive(Debug, PartialEq)]
 pub struct AuthResponse {
     pub version: u8,
     pub status: AuthStatusCode,
 }
 
+#[derive(Debug, PartialEq)]
 pub struct ClientRequest {
     pub version: u8,
     pub command: Command,
@@ -80,6 +129,7 @@ pub struct ClientRequest {
     pub port: u16,
 }
 
+#[derive(Debug, PartialEq)]
 pub struct RequestResponse {
     pub version: u8,
     pub response_code: ResponseCode,
@@ -87,10 +137,37 @@ pub struct RequestResponse {
     pub port: u16,
 }
 
+// SOCKS4 requests have no handshake, the request comes in right away. SOCKS4a
+// requests carry a domain instead, signaled by an address of 0.0.0.x.
+pub struct Socks4Request {
+    pub version: u8,
+    pub command: Command,
+    pub address: Address,
+    pub port: u16,
+    pub user_id: String,
+}
+
+pub struct Socks4Response {
+    pub response_code: Socks4ResponseCode,
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+// Prefixes every datagram relayed through a UDP association
+#[derive(Debug, PartialEq)]
+pub struct UdpHeader {
+    pub fragment: u8,
+    pub address: Address,
+    pub port: u16,
+}
+
 // Traits
 
 #[async_trait]
 pub trait Parseable {
+    // Messages carrying secrets aren't included in byte traces
+    const SENSITIVE: bool = false;
+
     async fn new<T>(input: &mut T) -> Result<Self, Error>
     where
         Self: Sized,
@@ -104,11 +181,103 @@ pub trait Writeable {
         T: AsyncWrite + Send + Unpin;
 }
 
+// Byte traces, logged at trace level to debug interop with odd clients
+
+// Formats bytes as space separated hex pairs, like "05 01 00"
+pub fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn message_name<M>() -> &'static str {
+    let name = std::any::type_name::<M>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+// Whether trace events end up anywhere, either on a tracing subscriber or,
+// without one, on the log crate's logger
+fn trace_enabled() -> bool {
+    enabled!(Level::TRACE) || log::log_enabled!(log::Level::Trace)
+}
+
+// Parses a message, tracing the bytes it was parsed from. Nothing's recorded
+// unless trace logging is enabled.
+pub async fn read_message<M, T>(input: &mut T) -> Result<M, Error>
+where
+    M: Parseable,
+    T: AsyncRead + Send + Unpin,
+{
+    if !trace_enabled() {
+        return M::new(input).await;
+    }
+    let mut recorder = RecordingReader {
+        input,
+        bytes: Vec::new(),
+    };
+    let result = M::new(&mut recorder).await;
+    if M::SENSITIVE {
+        trace!(
+            "Received {} ({} bytes, redacted)",
+            message_name::<M>(),
+            recorder.bytes.len()
+        );
+    } else {
+        trace!(
+            "Received {}: {}",
+            message_name::<M>(),
+            hex_dump(&recorder.bytes)
+        );
+    }
+    result
+}
+
+// Writes a message, tracing its bytes if trace logging is enabled
+pub async fn write_message<M, T>(message: &M, output: &mut T) -> Result<(), Error>
+where
+    M: Writeable + Sync,
+    T: AsyncWrite + Send + Unpin,
+{
+    if !trace_enabled() {
+        return message.write(output).await;
+    }
+    let mut buffer = Vec::new();
+    message.write(&mut buffer).await?;
+    trace!("Sending {}: {}", message_name::<M>(), hex_dump(&buffer));
+    output.write_all(&buffer).await?;
+    output.flush().await?;
+    Ok(())
+}
+
+// Keeps a copy of everything read through it
+struct RecordingReader<'a, T> {
+    input: &'a mut T,
+    bytes: Vec<u8>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RecordingReader<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        let result = Pin::new(&mut *self.input).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.bytes.extend_from_slice(&buf.filled()[start..]);
+        }
+        result
+    }
+}
+
 // Allow reading strings from an AsyncRead
 
 #[async_trait]
 trait ReadString {
     async fn read_string(&mut self) -> Result<String, Error>;
+    async fn read_null_terminated_string(&mut self) -> Result<String, Error>;
 }
 
 #[async_trait]
@@ -123,6 +292,85 @@ impl<T: AsyncRead + Send + Unpin> ReadString for T {
         }
         Ok(parsed_string.unwrap())
     }
+
+    async fn read_null_terminated_string(&mut self) -> Result<String, Error> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.read_u8().await? {
+                0 => break,
+                byte => bytes.push(byte),
+            }
+            if bytes.len() > 255 {
+                return Err(Error::MalformedMessage("String in stream too long".into()));
+            }
+        }
+        String::from_utf8(bytes)
+            .map_err(|_| Error::MalformedMessage("Invalid string in stream".into()))
+    }
+}
+
+async fn read_address<T>(input: &mut T) -> Result<Address, Error>
+where
+    T: AsyncRead + Send + Unpin,
+{
+    let address_type = AddressType::from_u8(input.read_u8().await?)
+        .ok_or_else(|| Error::MalformedMessage("Invalid address type".into()))?;
+    let address = match address_type {
+        AddressType::Ipv4 => {
+            let addr = input.read_u32().await?;
+            Address::Ip(IpAddr::V4(Ipv4Addr::from(addr)))
+        }
+        AddressType::Ipv6 => {
+            let mut buf = [0; 16];
+            input.read_exact(&mut buf).await?;
+            Address::Ip(IpAddr::V6(Ipv6Addr::from(buf)))
+        }
+        AddressType::Domain => {
+            let domain = input.read_string().await?;
+            if domain.is_empty() {
+                return Err(Error::MalformedMessage("Empty domain".into()));
+            }
+            if domain.len() > MAX_DOMAIN_LENGTH {
+                return Err(Error::MalformedMessage("Domain too long".into()));
+            }
+            Address::Domain(domain)
+        }
+    };
+    Ok(address)
+}
+
+async fn write_address<T>(output: &mut T, address: &Address) -> Result<(), Error>
+where
+    T: AsyncWrite + Send + Unpin,
+{
+    match address {
+        Address::Ip(IpAddr::V4(address)) => {
+            output.write_u8(AddressType::Ipv4 as u8).await?;
+            output.write_all(&address.octets()).await?;
+        }
+        Address::Ip(IpAddr::V6(address)) => {
+            output.write_u8(AddressType::Ipv6 as u8).await?;
+            output.write_all(&address.octets()).await?;
+        }
+        Address::Domain(domain) => {
+            output.write_u8(AddressType::Domain as u8).await?;
+            write_string(output, domain).await?;
+        }
+    };
+    Ok(())
+}
+
+// Writes a string prefixed by its length, which has to fit in a byte
+async fn write_string<T>(output: &mut T, value: &str) -> Result<(), Error>
+where
+    T: AsyncWrite + Send + Unpin,
+{
+    if value.len() > 255 {
+        return Err(Error::MalformedMessage("String too long".into()));
+    }
+    output.write_u8(value.len() as u8).await?;
+    output.write_all(value.as_bytes()).await?;
+    Ok(())
 }
 
 // Request impls
@@ -134,15 +382,49 @@ impl Parseable for HelloRequest {
         T: AsyncRead + Send + Unpin,
     {
         let version = input.read_u8().await?;
-        let method_count = input.read_u8().await?;
-        let mut methods = Vec::new();
-        for _i in 0..method_count {
-            match AuthenticationMethod::from_u8(input.read_u8().await?) {
-                Some(method) => methods.push(method),
-                None => return Err(Error::MalformedMessage("Unsupported method".into())),
-            };
+        let method_count = input.read_u8().await? as usize;
+        if method_count == 0 {
+            return Err(Error::MalformedMessage("No methods provided".into()));
         }
-        Ok(HelloRequest { version, methods })
+        let mut offered = vec![0; method_count];
+        input
+            .read_exact(&mut offered)
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => Error::MalformedMessage(
+                    format!("Expected {} methods, stream ended early", method_count).into(),
+                ),
+                _ => Error::Io(e),
+            })?;
+        Ok(HelloRequest {
+            version,
+            methods: offered,
+        })
+    }
+}
+
+impl HelloRequest {
+    // The offered methods we know about. Clients can offer unknown ones
+    // alongside ones we do support, so they're skipped rather than rejected.
+    pub fn known_methods(&self) -> Vec<AuthenticationMethod> {
+        self.methods
+            .iter()
+            .filter_map(|method| AuthenticationMethod::from_u8(*method))
+            .filter(|method| *method != AuthenticationMethod::NoAcceptableMethods)
+            .collect()
+    }
+
+    // The offered methods, by name when they're known
+    pub fn describe_methods(&self) -> String {
+        let methods: Vec<String> = self
+            .methods
+            .iter()
+            .map(|method| match AuthenticationMethod::from_u8(*method) {
+                Some(known) => format!("{:?}", known),
+                None => format!("{:#04x}", method),
+            })
+            .collect();
+        methods.join(", ")
     }
 }
 
@@ -155,23 +437,17 @@ impl Parseable for ClientRequest {
         let version = input.read_u8().await?;
         let command = Command::from_u8(input.read_u8().await?)
             .ok_or_else(|| Error::MalformedMessage("Unsupported command".into()))?;
-        // Skip reserved byte
-        input.read_u8().await?;
-        let address_type = AddressType::from_u8(input.read_u8().await?)
-            .ok_or_else(|| Error::MalformedMessage("Invalid address type".into()))?;
-        let address = match address_type {
-            AddressType::Ipv4 => {
-                let addr = input.read_u32().await?;
-                Address::Ip(IpAddr::V4(Ipv4Addr::from(addr)))
-            }
-            AddressType::Ipv6 => {
-                let mut buf = [0; 16];
-                input.read_exact(&mut buf).await?;
-                Address::Ip(IpAddr::V6(Ipv6Addr::from(buf)))
-            }
-            AddressType::Domain => Address::Domain(input.read_string().await?),
-        };
+        if input.read_u8().await? != 0 {
+            return Err(Error::MalformedMessage("Nonzero reserved byte".into()));
+        }
+        let address = read_address(input).await?;
         let port = input.read_u16().await?;
+        // Other commands use port 0 to say the port isn't known yet
+        if command == Command::Connect && port == 0 {
+            return Err(Error::MalformedMessage(
+                "Port 0 requested for CONNECT".into(),
+            ));
+        }
         Ok(ClientRequest {
             version,
             command,
@@ -183,13 +459,17 @@ impl Parseable for ClientRequest {
 
 #[async_trait]
 impl Parseable for AuthRequest {
+    const SENSITIVE: bool = true;
+
     async fn new<T>(input: &mut T) -> Result<Self, Error>
     where
         T: AsyncRead + Send + Unpin,
     {
         let version = input.read_u8().await?;
         if version != 1 {
-            return Err(Error::Generic("Unsupported auth version".into()));
+            return Err(Error::MalformedMessage(
+                format!("Unsupported auth version {}", version).into(),
+            ));
         }
         let username = input.read_string().await?;
         let password = input.read_string().await?;
@@ -201,8 +481,156 @@ impl Parseable for AuthRequest {
     }
 }
 
+#[async_trait]
+impl Parseable for Socks4Request {
+    async fn new<T>(input: &mut T) -> Result<Self, Error>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        let version = input.read_u8().await?;
+        let command = Command::from_u8(input.read_u8().await?)
+            .ok_or_else(|| Error::MalformedMessage("Unsupported command".into()))?;
+        let port = input.read_u16().await?;
+        let ip = Ipv4Addr::from(input.read_u32().await?);
+        let user_id = input.read_null_terminated_string().await?;
+        let octets = ip.octets();
+        let address = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+            Address::Domain(input.read_null_terminated_string().await?)
+        } else {
+            Address::Ip(IpAddr::V4(ip))
+        };
+        Ok(Socks4Request {
+            version,
+            command,
+            address,
+            port,
+            user_id,
+        })
+    }
+}
+
+#[async_trait]
+impl Parseable for UdpHeader {
+    async fn new<T>(input: &mut T) -> Result<Self, Error>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        // Skip reserved bytes
+        input.read_u16().await?;
+        let fragment = input.read_u8().await?;
+        let address = read_address(input).await?;
+        let port = input.read_u16().await?;
+        Ok(UdpHeader {
+            fragment,
+            address,
+            port,
+        })
+    }
+}
+
+#[async_trait]
+impl Writeable for HelloRequest {
+    async fn write<T>(&self, output: &mut T) -> Result<(), Error>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        if self.methods.len() > 255 {
+            return Err(Error::MalformedMessage("Too many methods".into()));
+        }
+        output.write_u8(self.version).await?;
+        output.write_u8(self.methods.len() as u8).await?;
+        for method in &self.methods {
+            output.write_u8(*method).await?;
+        }
+        output.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Writeable for AuthRequest {
+    async fn write<T>(&self, output: &mut T) -> Result<(), Error>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        output.write_u8(self.version).await?;
+        write_string(output, &self.username).await?;
+        write_string(output, &self.password).await?;
+        output.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Writeable for ClientRequest {
+    async fn write<T>(&self, output: &mut T) -> Result<(), Error>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        output.write_u8(self.version).await?;
+        output.write_u8(self.command as u8).await?;
+        // Reserved byte
+        output.write_u8(0).await?;
+        write_address(output, &self.address).await?;
+        output.write_u16(self.port).await?;
+        output.flush().await?;
+        Ok(())
+    }
+}
+
 // Response impls
 
+#[async_trait]
+impl Parseable for HelloResponse {
+    async fn new<T>(input: &mut T) -> Result<Self, Error>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        let version = input.read_u8().await?;
+        let method = AuthenticationMethod::from_u8(input.read_u8().await?)
+            .ok_or_else(|| Error::MalformedMessage("Unsupported method".into()))?;
+        Ok(HelloResponse { version, method })
+    }
+}
+
+#[async_trait]
+impl Parseable for AuthResponse {
+    async fn new<T>(input: &mut T) -> Result<Self, Error>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        let version = input.read_u8().await?;
+        // Anything other than 0 is a failure
+        let status = match input.read_u8().await? {
+            0 => AuthStatusCode::Success,
+            _ => AuthStatusCode::Failure,
+        };
+        Ok(AuthResponse { version, status })
+    }
+}
+
+#[async_trait]
+impl Parseable for RequestResponse {
+    async fn new<T>(input: &mut T) -> Result<Self, Error>
+    where
+        T: AsyncRead + Send + Unpin,
+    {
+        let version = input.read_u8().await?;
+        let response_code = ResponseCode::from_u8(input.read_u8().await?)
+            .ok_or_else(|| Error::MalformedMessage("Invalid response code".into()))?;
+        // Skip reserved byte
+        input.read_u8().await?;
+        let bind_address = read_address(input).await?;
+        let port = input.read_u16().await?;
+        Ok(RequestResponse {
+            version,
+            response_code,
+            bind_address,
+            port,
+        })
+    }
+}
+
 impl HelloResponse {
     pub fn new(version: u8, method: AuthenticationMethod) -> Self {
         HelloResponse { version, method }
@@ -248,19 +676,49 @@ impl Writeable for RequestResponse {
         output.write_u8(self.response_code as u8).await?;
         // Reserved byte
         output.write_u8(0).await?;
-        match self.bind_address {
-            Address::Ip(IpAddr::V4(address)) => {
-                output.write_u8(AddressType::Ipv4 as u8).await?;
-                output.write_all(&address.octets()).await?;
-            }
-            Address::Ip(IpAddr::V6(address)) => {
-                output.write_u8(AddressType::Ipv6 as u8).await?;
-                output.write_all(&address.octets()).await?;
-            }
-            Address::Domain(ref _domain) => {
-                panic!("Domain used for bind address");
-            }
-        };
+        write_address(output, &self.bind_address).await?;
+        output.write_u16(self.port).await?;
+        output.flush().await?;
+        Ok(())
+    }
+}
+
+impl Socks4Response {
+    pub fn new(response_code: Socks4ResponseCode, address: Ipv4Addr, port: u16) -> Self {
+        Socks4Response {
+            response_code,
+            address,
+            port,
+        }
+    }
+}
+
+#[async_trait]
+impl Writeable for Socks4Response {
+    async fn write<T>(&self, output: &mut T) -> Result<(), Error>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        // Replies carry a null version byte
+        output.write_u8(0).await?;
+        output.write_u8(self.response_code as u8).await?;
+        output.write_u16(self.port).await?;
+        output.write_all(&self.address.octets()).await?;
+        output.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Writeable for UdpHeader {
+    async fn write<T>(&self, output: &mut T) -> Result<(), Error>
+    where
+        T: AsyncWrite + Send + Unpin,
+    {
+        // Reserved bytes
+        output.write_u16(0).await?;
+        output.write_u8(self.fragment).await?;
+        write_address(output, &self.address).await?;
         output.write_u16(self.port).await?;
         output.flush().await?;
         Ok(())
@@ -309,7 +767,7 @@ mod tests {
         let message = make_message::<HelloRequest>(&[5, 2, 0, 2]).await;
         assert_eq!(message.version, 5);
         assert_eq!(
-            message.methods,
+            message.known_methods(),
             vec!(
                 AuthenticationMethod::NoAuthentication,
                 AuthenticationMethod::UsernamePassword
@@ -317,6 +775,34 @@ mod tests {
         );
     }
 
+    #[async_test]
+    async fn parse_hello_request_unknown_methods() {
+        let message = make_message::<HelloRequest>(&[5, 4, 1, 0x80, 0xff, 2]).await;
+        assert_eq!(message.methods, vec![1, 0x80, 0xff, 2]);
+        assert_eq!(
+            message.known_methods(),
+            vec![
+                AuthenticationMethod::Gssapi,
+                AuthenticationMethod::UsernamePassword
+            ]
+        );
+        assert_eq!(
+            message.describe_methods(),
+            "Gssapi, 0x80, NoAcceptableMethods, UsernamePassword"
+        );
+    }
+
+    #[async_test]
+    async fn parse_malformed_hello_request() {
+        let mut cursor = BufReader::new(&[5, 0][..]);
+        let result = HelloRequest::new(&mut cursor).await;
+        assert!(matches!(result, Err(Error::MalformedMessage(_))));
+
+        let mut cursor = BufReader::new(&[5, 3, 0, 2][..]);
+        let result = HelloRequest::new(&mut cursor).await;
+        assert!(matches!(result, Err(Error::MalformedMessage(_))));
+    }
+
     #[async_test]
     async fn parse_auth_request() {
         let message = make_message::<AuthRequest>(&[1, 3, 102, 111, 111, 3, 98, 97, 114]).await;
@@ -325,6 +811,38 @@ mod tests {
         assert_eq!(message.password, "bar");
     }
 
+    #[test]
+    fn hex_dumps() {
+        assert_eq!(hex_dump(&[5, 1, 0, 0xff]), "05 01 00 ff");
+        assert_eq!(hex_dump(&[]), "");
+    }
+
+    #[async_test]
+    async fn messages_traced() {
+        crate::testing::capture_logs();
+        let mut cursor = BufReader::new(&[5, 1, 2][..]);
+        let request: HelloRequest = read_message(&mut cursor).await.unwrap();
+        assert_eq!(request.version, 5);
+        let mut cursor = BufReader::new(&[1, 3, 102, 111, 111, 3, 98, 97, 114][..]);
+        let _: AuthRequest = read_message(&mut cursor).await.unwrap();
+        let mut output = Vec::new();
+        let response = HelloResponse::new(5, AuthenticationMethod::UsernamePassword);
+        write_message(&response, &mut output).await.unwrap();
+        assert_eq!(output, [5, 2]);
+
+        let messages = crate::testing::logged_messages("rusty_socks::messages");
+        assert!(messages.contains(&"Received HelloRequest: 05 01 02".to_string()));
+        assert!(messages.contains(&"Received AuthRequest (9 bytes, redacted)".to_string()));
+        assert!(messages.contains(&"Sending HelloResponse: 05 02".to_string()));
+    }
+
+    #[async_test]
+    async fn parse_auth_request_unsupported_version() {
+        let mut cursor = BufReader::new(&[2, 3, 102, 111, 111, 3, 98, 97, 114][..]);
+        let result = AuthRequest::new(&mut cursor).await;
+        assert!(matches!(result, Err(Error::MalformedMessage(_))));
+    }
+
     #[async_test]
     async fn parse_client_request_connect_ipv4() {
         let message = make_message::<ClientRequest>(&[5, 1, 0, 1, 1, 2, 3, 4, 31, 144]).await;
@@ -358,12 +876,130 @@ mod tests {
         assert_eq!(message.port, 8080);
     }
 
+    #[async_test]
+    async fn parse_malformed_client_request() {
+        // Nonzero reserved byte
+        let mut cursor = BufReader::new(&[5, 1, 1, 1, 1, 2, 3, 4, 31, 144][..]);
+        let result = ClientRequest::new(&mut cursor).await;
+        assert!(matches!(result, Err(Error::MalformedMessage(_))));
+
+        // CONNECT to port 0
+        let mut cursor = BufReader::new(&[5, 1, 0, 1, 1, 2, 3, 4, 0, 0][..]);
+        let result = ClientRequest::new(&mut cursor).await;
+        assert!(matches!(result, Err(Error::MalformedMessage(_))));
+    }
+
+    #[async_test]
+    async fn parse_domain_lengths() {
+        let request = |domain: &str| {
+            let mut bytes = vec![5, 1, 0, 3, domain.len() as u8];
+            bytes.extend_from_slice(domain.as_bytes());
+            bytes.extend_from_slice(&[0, 80]);
+            bytes
+        };
+        let longest = format!("{}.com", "a".repeat(249));
+        let message = make_message::<ClientRequest>(&request(&longest)).await;
+        assert_eq!(message.address, Address::Domain(longest));
+        let message = make_message::<ClientRequest>(&request("xn--bcher-kva.example")).await;
+        assert_eq!(
+            message.address,
+            Address::Domain("xn--bcher-kva.example".into())
+        );
+
+        for domain in ["", &format!("{}.com", "a".repeat(250))] {
+            let bytes = request(domain);
+            let mut cursor = BufReader::new(&bytes[..]);
+            let result = ClientRequest::new(&mut cursor).await;
+            assert!(matches!(result, Err(Error::MalformedMessage(_))));
+        }
+    }
+
+    #[async_test]
+    async fn parse_socks4_request() {
+        let message =
+            make_message::<Socks4Request>(&[4, 1, 31, 144, 1, 2, 3, 4, 102, 111, 111, 0]).await;
+        assert_eq!(message.version, 4);
+        assert_eq!(message.command, Command::Connect);
+        assert_eq!(message.address, Address::Ip("1.2.3.4".parse().unwrap()));
+        assert_eq!(message.port, 8080);
+        assert_eq!(message.user_id, "foo");
+    }
+
+    #[async_test]
+    async fn parse_socks4a_request() {
+        let message = make_message::<Socks4Request>(&[
+            4, 1, 31, 144, 0, 0, 0, 1, 0, 102, 111, 111, 46, 99, 111, 109, 0,
+        ])
+        .await;
+        assert_eq!(message.address, Address::Domain("foo.com".into()));
+        assert_eq!(message.port, 8080);
+        assert_eq!(message.user_id, "");
+    }
+
+    #[async_test]
+    async fn serialize_socks4_response() {
+        let message = Socks4Response::new(
+            Socks4ResponseCode::Granted,
+            "1.2.3.4".parse().unwrap(),
+            8080,
+        );
+        expect_serialization(&message, &[0, 0x5a, 31, 144, 1, 2, 3, 4]).await;
+    }
+
+    #[async_test]
+    async fn parse_client_request_bind() {
+        let message = make_message::<ClientRequest>(&[5, 2, 0, 1, 1, 2, 3, 4, 0, 0]).await;
+        assert_eq!(message.command, Command::Bind);
+        assert_eq!(message.address, Address::Ip("1.2.3.4".parse().unwrap()));
+    }
+
+    #[async_test]
+    async fn parse_udp_header() {
+        let message = make_message::<UdpHeader>(&[0, 0, 0, 1, 1, 2, 3, 4, 0, 53]).await;
+        assert_eq!(message.fragment, 0);
+        assert_eq!(message.address, Address::Ip("1.2.3.4".parse().unwrap()));
+        assert_eq!(message.port, 53);
+    }
+
+    #[async_test]
+    async fn serialize_udp_header_domain() {
+        let message = UdpHeader {
+            fragment: 0,
+            address: Address::Domain("foo.com".into()),
+            port: 53,
+        };
+        expect_serialization(
+            &message,
+            &[0, 0, 0, 3, 7, 102, 111, 111, 46, 99, 111, 109, 0, 53],
+        )
+        .await;
+    }
+
+    #[test]
+    fn response_code_from_u8() {
+        assert_eq!(
+            ResponseCode::from_u8(5),
+            Some(ResponseCode::ConnectionRefused)
+        );
+        assert_eq!(ResponseCode::from_u8(9), None);
+        assert_eq!(
+            ResponseCode::HostUnreachable.to_string(),
+            "host unreachable"
+        );
+    }
+
     #[async_test]
     async fn serialize_hello_reply() {
         let message = HelloResponse::new(1, AuthenticationMethod::NoAuthentication);
         expect_serialization(&message, &[1, 0]).await;
     }
 
+    #[async_test]
+    async fn serialize_no_acceptable_methods_reply() {
+        let message = HelloResponse::new(5, AuthenticationMethod::NoAcceptableMethods);
+        expect_serialization(&message, &[5, 0xff]).await;
+    }
+
     #[async_test]
     async fn serialize_auth_response() {
         let message = AuthResponse::new(1, AuthStatusCode::Success);
@@ -397,4 +1033,120 @@ mod tests {
         )
         .await;
     }
+
+    async fn round_trip<T: Parseable + Writeable>(message: &T) -> T {
+        let mut buffer = Vec::new();
+        message.write(&mut buffer).await.unwrap();
+        make_message(&buffer).await
+    }
+
+    #[async_test]
+    async fn parse_hello_response() {
+        let message = make_message::<HelloResponse>(&[5, 2]).await;
+        assert_eq!(
+            message,
+            HelloResponse::new(5, AuthenticationMethod::UsernamePassword)
+        );
+        let message = HelloResponse::new(5, AuthenticationMethod::NoAcceptableMethods);
+        assert_eq!(round_trip(&message).await, message);
+    }
+
+    #[async_test]
+    async fn parse_auth_response() {
+        let message = make_message::<AuthResponse>(&[1, 0]).await;
+        assert_eq!(message, AuthResponse::new(1, AuthStatusCode::Success));
+        let message = make_message::<AuthResponse>(&[1, 0xff]).await;
+        assert_eq!(message.status, AuthStatusCode::Failure);
+        let message = AuthResponse::new(1, AuthStatusCode::Failure);
+        assert_eq!(round_trip(&message).await, message);
+    }
+
+    #[async_test]
+    async fn parse_request_response() {
+        let message = make_message::<RequestResponse>(&[5, 5, 0, 1, 1, 2, 3, 4, 31, 144]).await;
+        assert_eq!(message.response_code, ResponseCode::ConnectionRefused);
+        assert_eq!(
+            message.bind_address,
+            Address::Ip("1.2.3.4".parse().unwrap())
+        );
+        assert_eq!(message.port, 8080);
+        let message = RequestResponse::new(
+            5,
+            ResponseCode::Success,
+            Address::Ip("dead::beef".parse().unwrap()),
+            8080,
+        );
+        assert_eq!(round_trip(&message).await, message);
+    }
+
+    #[async_test]
+    async fn serialize_hello_request() {
+        let message = HelloRequest {
+            version: 5,
+            methods: vec![
+                AuthenticationMethod::NoAuthentication as u8,
+                AuthenticationMethod::UsernamePassword as u8,
+            ],
+        };
+        expect_serialization(&message, &[5, 2, 0, 2]).await;
+        assert_eq!(round_trip(&message).await, message);
+    }
+
+    #[async_test]
+    async fn serialize_auth_request() {
+        let message = AuthRequest {
+            version: 1,
+            username: "foo".into(),
+            password: "bar".into(),
+        };
+        expect_serialization(&message, &[1, 3, 102, 111, 111, 3, 98, 97, 114]).await;
+        assert_eq!(round_trip(&message).await, message);
+
+        let message = AuthRequest {
+            version: 1,
+            username: "a".repeat(256),
+            password: "bar".into(),
+        };
+        assert!(message.write(&mut Vec::new()).await.is_err());
+    }
+
+    #[async_test]
+    async fn serialize_client_request() {
+        let message = ClientRequest {
+            version: 5,
+            command: Command::Connect,
+            address: Address::Domain("foo.com".into()),
+            port: 8080,
+        };
+        expect_serialization(
+            &message,
+            &[5, 1, 0, 3, 7, 102, 111, 111, 46, 99, 111, 109, 31, 144],
+        )
+        .await;
+        assert_eq!(round_trip(&message).await, message);
+
+        let message = ClientRequest {
+            version: 5,
+            command: Command::Bind,
+            address: Address::Ip("dead::beef".parse().unwrap()),
+            port: 8080,
+        };
+        assert_eq!(round_trip(&message).await, message);
+    }
+
+    #[async_test]
+    async fn serialize_request_response_domain() {
+        let message = RequestResponse::new(
+            5,
+            ResponseCode::Success,
+            Address::Domain("foo.com".into()),
+            8080,
+        );
+        expect_serialization(
+            &message,
+            &[5, 0, 0, 3, 7, 102, 111, 111, 46, 99, 111, 109, 31, 144],
+        )
+        .await;
+        assert_eq!(round_trip(&message).await, message);
+    }
 }
@@ -0,0 +1,231 @@
+use crate::messages::ResponseCode;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+use tracing::{debug, warn};
+
+// How long to wait after failing to accept, as whatever caused it, like
+// running out of file descriptors, is unlikely to go away right away
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+// Clients that take longer than this to get their request through are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Labels = Vec<(&'static str, String)>;
+
+// Prometheus metrics for the whole server
+pub struct Metrics {
+    registry: Registry,
+    connections: Counter,
+    active_connections: Gauge,
+    auth_successes: Counter,
+    auth_failures: Counter,
+    bytes: Family<Labels, Counter>,
+    connect_errors: Family<Labels, Counter>,
+}
+
+// Keeps a connection counted as active until dropped
+pub struct ActiveConnection<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.metrics.active_connections.dec();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let mut registry = Registry::with_prefix("rusty_socks");
+        let connections = Counter::default();
+        registry.register("connections", "Connections accepted", connections.clone());
+        let active_connections = Gauge::default();
+        registry.register(
+            "active_connections",
+            "Connections being served",
+            active_connections.clone(),
+        );
+        let auth_successes = Counter::default();
+        registry.register(
+            "auth_successes",
+            "Successful authentications",
+            auth_successes.clone(),
+        );
+        let auth_failures = Counter::default();
+        registry.register(
+            "auth_failures",
+            "Failed authentications",
+            auth_failures.clone(),
+        );
+        let bytes = Family::default();
+        registry.register(
+            "proxied_bytes",
+            "Bytes proxied, by direction",
+            bytes.clone(),
+        );
+        let connect_errors = Family::default();
+        registry.register(
+            "connect_errors",
+            "Failed upstream connects, by reply code",
+            connect_errors.clone(),
+        );
+        Metrics {
+            registry,
+            connections,
+            active_connections,
+            auth_successes,
+            auth_failures,
+            bytes,
+            connect_errors,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) -> ActiveConnection<'_> {
+        self.connections.inc();
+        self.active_connections.inc();
+        ActiveConnection { metrics: self }
+    }
+
+    pub fn record_authentication(&self, success: bool) {
+        match success {
+            true => self.auth_successes.inc(),
+            false => self.auth_failures.inc(),
+        };
+    }
+
+    // Bytes sent by clients and relayed upstream
+    pub fn record_client_to_server(&self, bytes: u64) {
+        self.record_bytes("client_to_server", bytes);
+    }
+
+    // Bytes sent by upstreams and relayed to clients
+    pub fn record_server_to_client(&self, bytes: u64) {
+        self.record_bytes("server_to_client", bytes);
+    }
+
+    fn record_bytes(&self, direction: &str, bytes: u64) {
+        self.bytes
+            .get_or_create(&vec![("direction", direction.into())])
+            .inc_by(bytes);
+    }
+
+    pub fn record_connect_error(&self, code: ResponseCode) {
+        self.connect_errors
+            .get_or_create(&vec![("code", format!("{:?}", code))])
+            .inc();
+    }
+
+    // The metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut output = String::new();
+        // Writing into a String can't fail
+        encode(&mut output, &self.registry).unwrap();
+        output
+    }
+}
+
+// Serves the metrics over HTTP on /metrics
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {}", e);
+                sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            match timeout(REQUEST_TIMEOUT, handle_request(stream, &metrics)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => debug!("Metrics request failed: {}", e),
+                Err(_) => debug!("Metrics request timed out"),
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    // Only the request line matters, the rest of the request is ignored
+    let mut buffer = [0; 1024];
+    let size = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..size]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", metrics.encode()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown(std::net::Shutdown::Write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_metrics() {
+        let metrics = Metrics::default();
+        let first = metrics.connection_opened();
+        let _second = metrics.connection_opened();
+        drop(first);
+        metrics.record_authentication(true);
+        metrics.record_authentication(false);
+        metrics.record_client_to_server(10);
+        metrics.record_server_to_client(20);
+        metrics.record_connect_error(ResponseCode::ConnectionRefused);
+        let output = metrics.encode();
+        assert!(output.contains("rusty_socks_connections_total 2\n"));
+        assert!(output.contains("rusty_socks_active_connections 1\n"));
+        assert!(output.contains("rusty_socks_auth_successes_total 1\n"));
+        assert!(output.contains("rusty_socks_auth_failures_total 1\n"));
+        assert!(
+            output.contains("rusty_socks_proxied_bytes_total{direction=\"client_to_server\"} 10\n")
+        );
+        assert!(output.contains("rusty_socks_connect_errors_total{code=\"ConnectionRefused\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn serve_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        metrics.record_authentication(true);
+        tokio::spawn(serve(listener, Arc::clone(&metrics)));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("rusty_socks_auth_successes_total 1\n"));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
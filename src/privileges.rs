@@ -0,0 +1,92 @@
+use crate::error::Error;
+use nix::unistd::{setgid, setuid, Gid, Group, Uid, User};
+
+// The account the server switches to
+#[derive(Debug, PartialEq)]
+struct Account {
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+}
+
+// Switches to the given user and/or group, meant to be called once the
+// listeners are bound. Without a group, the user's primary group is used.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), Error> {
+    let account = lookup_account(user, group)?;
+    // The group goes first, as changing it needs the privileges being dropped
+    if let Some(gid) = account.gid {
+        set_supplementary_groups(gid)?;
+        setgid(gid).map_err(|e| Error::Generic(format!("Failed to set group: {}", e)))?;
+    }
+    if let Some(uid) = account.uid {
+        setuid(uid).map_err(|e| Error::Generic(format!("Failed to set user: {}", e)))?;
+    }
+    Ok(())
+}
+
+fn lookup_account(user: Option<&str>, group: Option<&str>) -> Result<Account, Error> {
+    let user = match user {
+        Some(name) => Some(
+            User::from_name(name)
+                .map_err(|e| Error::Config(format!("Failed to look up user {}: {}", name, e)))?
+                .ok_or_else(|| Error::Config(format!("Unknown user {}", name)))?,
+        ),
+        None => None,
+    };
+    let gid = match group {
+        Some(name) => Some(
+            Group::from_name(name)
+                .map_err(|e| Error::Config(format!("Failed to look up group {}: {}", name, e)))?
+                .ok_or_else(|| Error::Config(format!("Unknown group {}", name)))?
+                .gid,
+        ),
+        None => user.as_ref().map(|user| user.gid),
+    };
+    Ok(Account {
+        uid: user.map(|user| user.uid),
+        gid,
+    })
+}
+
+// Leaves the group as the only supplementary one, so none of root's are kept
+#[cfg(not(target_vendor = "apple"))]
+fn set_supplementary_groups(gid: Gid) -> Result<(), Error> {
+    nix::unistd::setgroups(&[gid])
+        .map_err(|e| Error::Generic(format!("Failed to set supplementary groups: {}", e)))
+}
+
+#[cfg(target_vendor = "apple")]
+fn set_supplementary_groups(_gid: Gid) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_accounts() {
+        let root = lookup_account(Some("root"), None).unwrap();
+        assert_eq!(
+            root,
+            Account {
+                uid: Some(Uid::from_raw(0)),
+                gid: Some(Gid::from_raw(0)),
+            }
+        );
+        assert_eq!(
+            lookup_account(None, None).unwrap(),
+            Account {
+                uid: None,
+                gid: None
+            }
+        );
+        assert!(matches!(
+            lookup_account(Some("rusty-socks-no-such-user"), None),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            lookup_account(None, Some("rusty-socks-no-such-group")),
+            Err(Error::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,146 @@
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+
+// The signature every PROXY protocol v2 header starts with
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+
+// The version of the HAProxy PROXY protocol header sent to upstreams, which
+// tells them the address of the client behind the proxy
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    // Human readable
+    V1,
+    // Binary
+    V2,
+}
+
+impl ProxyProtocol {
+    // Builds the header for a connection from `source` to `destination`. If
+    // the source isn't known, like for unix socket clients, the header says
+    // so and upstreams use the proxy's address instead.
+    pub fn header(&self, source: Option<SocketAddr>, destination: SocketAddr) -> Vec<u8> {
+        let addresses = source.map(|source| same_family(source, destination));
+        match self {
+            ProxyProtocol::V1 => v1_header(addresses),
+            ProxyProtocol::V2 => v2_header(addresses),
+        }
+    }
+}
+
+// Both addresses in the same family, mapping IPv4 ones into IPv6 if needed
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_ipv6 = |address: SocketAddr| match address.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), address.port()),
+        IpAddr::V6(_) => address,
+    };
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (to_ipv6(source), to_ipv6(destination))
+    }
+}
+
+fn v1_header(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    match addresses {
+        Some((source, destination)) => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source.is_ipv4() { "TCP4" } else { "TCP6" },
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        )
+        .into_bytes(),
+        None => b"PROXY UNKNOWN\r\n".to_vec(),
+    }
+}
+
+fn v2_header(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let (source, destination) = match addresses {
+        Some(addresses) => addresses,
+        None => {
+            // Version 2, LOCAL command, unspecified family and no addresses
+            header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+            return header;
+        }
+    };
+    let mut body = Vec::with_capacity(36);
+    let family = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            body.extend_from_slice(&source.octets());
+            body.extend_from_slice(&destination.octets());
+            // TCP over IPv4
+            0x11
+        }
+        (source, destination) => {
+            body.extend_from_slice(&to_ipv6_octets(source));
+            body.extend_from_slice(&to_ipv6_octets(destination));
+            // TCP over IPv6
+            0x21
+        }
+    };
+    body.extend_from_slice(&source.port().to_be_bytes());
+    body.extend_from_slice(&destination.port().to_be_bytes());
+    // Version 2, PROXY command
+    header.push(0x21);
+    header.push(family);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(&body);
+    header
+}
+
+fn to_ipv6_octets(address: IpAddr) -> [u8; 16] {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped().octets(),
+        IpAddr::V6(address) => address.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_headers() {
+        let destination = address("10.0.0.1:443");
+        assert_eq!(
+            ProxyProtocol::V1.header(Some(address("192.168.0.1:56324")), destination),
+            b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\n"
+        );
+        assert_eq!(
+            ProxyProtocol::V1.header(Some(address("[2001:db8::1]:56324")), destination),
+            b"PROXY TCP6 2001:db8::1 ::ffff:10.0.0.1 56324 443\r\n"
+        );
+        assert_eq!(
+            ProxyProtocol::V1.header(None, destination),
+            b"PROXY UNKNOWN\r\n"
+        );
+    }
+
+    #[test]
+    fn v2_headers() {
+        let header =
+            ProxyProtocol::V2.header(Some(address("192.168.0.1:56324")), address("10.0.0.1:443"));
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 168, 0, 1, 10, 0, 0, 1]);
+        expected.extend_from_slice(&56324u16.to_be_bytes());
+        expected.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(header, expected);
+
+        let header =
+            ProxyProtocol::V2.header(Some(address("[::1]:56324")), address("10.0.0.1:443"));
+        assert_eq!(&header[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(header.len(), 16 + 36);
+
+        let header = ProxyProtocol::V2.header(None, address("10.0.0.1:443"));
+        assert_eq!(&header[12..], &[0x20, 0x00, 0, 0]);
+    }
+}
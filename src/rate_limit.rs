@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Per-IP entries that no longer matter, like buckets that have fully
+// refilled, are pruned once there's at least this many
+const PRUNE_THRESHOLD: usize = 4096;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    fn try_take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        self.tokens >= limit.burst as f64
+    }
+
+    // Takes `amount` tokens even if there aren't enough, returning how long
+    // it takes for the bucket to get out of debt
+    fn take(&mut self, limit: &RateLimit, amount: f64, now: Instant) -> Duration {
+        self.refill(limit, now);
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / limit.per_second)
+        }
+    }
+}
+
+// Caps the throughput of one direction of a connection. Up to a second's
+// worth of bytes can go through in a burst.
+pub struct BandwidthLimiter {
+    limit: RateLimit,
+    bucket: TokenBucket,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        // The bucket would never refill with no bytes per second
+        let bytes_per_second = bytes_per_second.max(1);
+        let limit = RateLimit {
+            per_second: bytes_per_second as f64,
+            burst: bytes_per_second.min(u32::MAX as u64) as u32,
+        };
+        BandwidthLimiter {
+            bucket: TokenBucket::new(&limit),
+            limit,
+        }
+    }
+
+    // Accounts for `bytes` having been sent, returning how long to wait
+    // before sending anything else
+    pub fn consume(&mut self, bytes: usize) -> Duration {
+        self.bucket.take(&self.limit, bytes as f64, Instant::now())
+    }
+}
+
+// Limits the rate at which new connections are accepted
+pub struct ConnectionRateLimiter {
+    global: Option<(RateLimit, Mutex<TokenBucket>)>,
+    per_ip: Option<(RateLimit, Mutex<PerIpBuckets>)>,
+}
+
+struct PerIpBuckets {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    // How many entries there have to be for the next prune
+    prune_at: usize,
+}
+
+impl PerIpBuckets {
+    fn prune(&mut self, limit: &RateLimit, now: Instant) {
+        if self.buckets.len() < self.prune_at {
+            return;
+        }
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(limit, now);
+            !bucket.is_full(limit)
+        });
+        // When most of them are still in use, going through all of them again
+        // on the next connection would find nothing to prune either
+        self.prune_at = (self.buckets.len() * 2).max(PRUNE_THRESHOLD);
+    }
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(global: Option<RateLimit>, per_ip: Option<RateLimit>) -> Self {
+        let per_ip_buckets = PerIpBuckets {
+            buckets: HashMap::new(),
+            prune_at: PRUNE_THRESHOLD,
+        };
+        ConnectionRateLimiter {
+            global: global.map(|limit| (limit, Mutex::new(TokenBucket::new(&limit)))),
+            per_ip: per_ip.map(|limit| (limit, Mutex::new(per_ip_buckets))),
+        }
+    }
+
+    // Takes a token for a new connection from the given address, returning
+    // false if it's over either limit. No tokens are taken for connections
+    // that get rejected.
+    pub fn allow(&self, address: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut global = match &self.global {
+            Some((limit, bucket)) => {
+                let mut bucket = bucket.lock().unwrap();
+                bucket.refill(limit, now);
+                if bucket.tokens < 1.0 {
+                    return false;
+                }
+                Some((limit, bucket))
+            }
+            None => None,
+        };
+        if let Some((limit, buckets)) = &self.per_ip {
+            let mut buckets = buckets.lock().unwrap();
+            buckets.prune(limit, now);
+            let bucket = buckets
+                .buckets
+                .entry(address)
+                .or_insert_with(|| TokenBucket::new(limit));
+            if !bucket.try_take(limit, now) {
+                return false;
+            }
+        }
+        if let Some((limit, bucket)) = &mut global {
+            bucket.try_take(limit, now);
+        }
+        true
+    }
+}
+
+// Caps the bytes each user can proxy over a window. Once it's over,
+// everyone's usage starts from zero again.
+pub struct UserQuotas {
+    limit: u64,
+    window: Duration,
+    usage: Mutex<QuotaUsage>,
+}
+
+struct QuotaUsage {
+    window_start: Instant,
+    bytes: HashMap<String, u64>,
+}
+
+impl UserQuotas {
+    pub fn new(limit: u64, window: Duration) -> Self {
+        UserQuotas {
+            limit,
+            window,
+            usage: Mutex::new(QuotaUsage {
+                window_start: Instant::now(),
+                bytes: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn record(&self, user: &str, bytes: u64) {
+        let mut usage = self.current_usage(Instant::now());
+        let used = usage.bytes.entry(user.into()).or_insert(0);
+        *used = used.saturating_add(bytes);
+    }
+
+    pub fn is_exceeded(&self, user: &str) -> bool {
+        let usage = self.current_usage(Instant::now());
+        usage
+            .bytes
+            .get(user)
+            .is_some_and(|used| *used >= self.limit)
+    }
+
+    // The usage for the window `now` falls in
+    fn current_usage(&self, now: Instant) -> MutexGuard<'_, QuotaUsage> {
+        let mut usage = self.usage.lock().unwrap();
+        if now.duration_since(usage.window_start) >= self.window {
+            usage.window_start = now;
+            usage.bytes.clear();
+        }
+        usage
+    }
+}
+
+// Locks out source IPs that fail to authenticate too often. Failures count
+// towards the lockout as long as each comes within `lockout` of the previous
+// one, and the lockout lasts until `lockout` after the last of them.
+pub struct AuthLockouts {
+    max_failures: u32,
+    lockout: Duration,
+    failures: Mutex<HashMap<IpAddr, FailedAttempts>>,
+}
+
+struct FailedAttempts {
+    count: u32,
+    last_attempt: Instant,
+}
+
+impl AuthLockouts {
+    pub fn new(max_failures: u32, lockout: Duration) -> Self {
+        AuthLockouts {
+            max_failures,
+            lockout,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_failure(&self, address: IpAddr) {
+        self.record_failure_at(address, Instant::now());
+    }
+
+    pub fn record_success(&self, address: IpAddr) {
+        self.failures.lock().unwrap().remove(&address);
+    }
+
+    pub fn is_locked_out(&self, address: IpAddr) -> bool {
+        self.is_locked_out_at(address, Instant::now())
+    }
+
+    fn record_failure_at(&self, address: IpAddr, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= PRUNE_THRESHOLD {
+            failures.retain(|_, attempts| !self.expired(attempts, now));
+        }
+        let attempts = failures.entry(address).or_insert(FailedAttempts {
+            count: 0,
+            last_attempt: now,
+        });
+        if self.expired(attempts, now) {
+            attempts.count = 0;
+        }
+        attempts.count = attempts.count.saturating_add(1);
+        attempts.last_attempt = now;
+    }
+
+    fn is_locked_out_at(&self, address: IpAddr, now: Instant) -> bool {
+        match self.failures.lock().unwrap().get(&address) {
+            Some(attempts) => attempts.count >= self.max_failures && !self.expired(attempts, now),
+            None => false,
+        }
+    }
+
+    fn expired(&self, attempts: &FailedAttempts, now: Instant) -> bool {
+        now.duration_since(attempts.last_attempt) >= self.lockout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(burst: u32) -> RateLimit {
+        RateLimit {
+            per_second: 1.0,
+            burst,
+        }
+    }
+
+    #[test]
+    fn per_ip_burst_throttled() {
+        let limiter = ConnectionRateLimiter::new(None, Some(limit(3)));
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+        for _ in 0..3 {
+            assert!(limiter.allow(first));
+        }
+        assert!(!limiter.allow(first));
+        assert!(limiter.allow(second));
+    }
+
+    #[test]
+    fn global_burst_throttled() {
+        let limiter = ConnectionRateLimiter::new(Some(limit(2)), None);
+        assert!(limiter.allow("10.0.0.1".parse().unwrap()));
+        assert!(limiter.allow("10.0.0.2".parse().unwrap()));
+        assert!(!limiter.allow("10.0.0.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn per_ip_token_kept_when_over_global_limit() {
+        let limiter = ConnectionRateLimiter::new(Some(limit(1)), Some(limit(2)));
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(limiter.allow(address));
+        assert!(!limiter.allow(address));
+        let (_, buckets) = limiter.per_ip.as_ref().unwrap();
+        let tokens = buckets.lock().unwrap().buckets[&address].tokens;
+        assert!(tokens >= 1.0);
+    }
+
+    #[test]
+    fn busy_per_ip_entries_not_pruned_every_time() {
+        let limiter = ConnectionRateLimiter::new(None, Some(limit(2)));
+        for i in 0..PRUNE_THRESHOLD as u32 {
+            assert!(limiter.allow(IpAddr::from(i.to_be_bytes())));
+        }
+        assert!(limiter.allow("10.0.0.1".parse().unwrap()));
+        let (_, buckets) = limiter.per_ip.as_ref().unwrap();
+        let buckets = buckets.lock().unwrap();
+        // None of them had refilled, so the next prune waits for twice as many
+        assert_eq!(buckets.buckets.len(), PRUNE_THRESHOLD + 1);
+        assert_eq!(buckets.prune_at, PRUNE_THRESHOLD * 2);
+    }
+
+    #[test]
+    fn tokens_refill() {
+        let limit = RateLimit {
+            per_second: 1000.0,
+            burst: 1,
+        };
+        let limiter = ConnectionRateLimiter::new(None, Some(limit));
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(limiter.allow(address));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(limiter.allow(address));
+    }
+
+    #[test]
+    fn bandwidth_limited() {
+        let mut limiter = BandwidthLimiter::new(1000);
+        assert_eq!(limiter.consume(1000), Duration::from_secs(0));
+        let delay = limiter.consume(500);
+        assert!(delay > Duration::from_millis(400));
+        assert!(delay <= Duration::from_millis(500));
+
+        // Treated as a byte per second rather than never refilling
+        let mut limiter = BandwidthLimiter::new(0);
+        assert_eq!(limiter.consume(1), Duration::from_secs(0));
+        assert!(limiter.consume(2) > Duration::from_millis(1900));
+    }
+
+    #[test]
+    fn auth_lockouts() {
+        let lockouts = AuthLockouts::new(3, Duration::from_secs(60));
+        let address: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        lockouts.record_failure_at(address, now);
+        lockouts.record_failure_at(address, now);
+        assert!(!lockouts.is_locked_out_at(address, now));
+        lockouts.record_failure_at(address, now);
+        assert!(lockouts.is_locked_out_at(address, now));
+        assert!(!lockouts.is_locked_out_at("10.0.0.2".parse().unwrap(), now));
+
+        // The lockout ends on its own, and failures that old stop counting
+        let later = now + Duration::from_secs(61);
+        assert!(!lockouts.is_locked_out_at(address, later));
+        lockouts.record_failure_at(address, later);
+        assert!(!lockouts.is_locked_out_at(address, later));
+
+        // A successful login clears the failures
+        lockouts.record_failure_at(address, later);
+        lockouts.record_failure_at(address, later);
+        lockouts.record_success(address);
+        assert!(!lockouts.is_locked_out_at(address, later));
+    }
+
+    #[test]
+    fn user_quotas() {
+        let quotas = UserQuotas::new(100, Duration::from_secs(60));
+        quotas.record("alice", 60);
+        assert!(!quotas.is_exceeded("alice"));
+        quotas.record("alice", 40);
+        assert!(quotas.is_exceeded("alice"));
+        assert!(!quotas.is_exceeded("bob"));
+
+        // Usage is forgotten once the window is over
+        let later = Instant::now() + Duration::from_secs(61);
+        assert!(quotas.current_usage(later).bytes.is_empty());
+        assert!(!quotas.is_exceeded("alice"));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::error::Error;
+use crate::lru::LruCache;
+use async_trait::async_trait;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+
+// Turns the domains clients ask for into addresses. Implement it to resolve
+// through something other than the system's resolver.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error>;
+
+    // Also returns how long the addresses can be cached for, for resolvers
+    // that know their records' TTLs
+    async fn resolve_with_ttl(&self, host: &str) -> Result<(Vec<IpAddr>, Option<Duration>), Error> {
+        Ok((self.resolve(host).await?, None))
+    }
+}
+
+// Resolves using the system's resolver
+#[derive(Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        let addresses: Vec<IpAddr> = lookup_host((host, 0))
+            .await
+            .map_err(|e| Error::DnsError(format!("Failed to resolve {}: {}", host, e)))?
+            .map(|address| address.ip())
+            .collect();
+        if addresses.is_empty() {
+            return Err(Error::DnsError(format!("No addresses found for {}", host)));
+        }
+        Ok(addresses)
+    }
+}
+
+// Remembers what another resolver returned for up to a fixed number of
+// domains. Entries are kept for as long as their TTL says, but never longer
+// than `max_ttl`, which is also used when the TTL isn't known.
+pub struct CachingResolver {
+    resolver: Box<dyn Resolver>,
+    max_ttl: Duration,
+    entries: Mutex<LruCache<String, CachedAddresses>>,
+}
+
+struct CachedAddresses {
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+}
+
+impl CachingResolver {
+    pub fn new(resolver: Box<dyn Resolver>, capacity: usize, max_ttl: Duration) -> Self {
+        CachingResolver {
+            resolver,
+            max_ttl,
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&host.into()) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.addresses.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl Resolver for CachingResolver {
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        let host = host.to_lowercase();
+        if let Some(addresses) = self.cached(&host) {
+            return Ok(addresses);
+        }
+        // Failures aren't cached
+        let (addresses, ttl) = self.resolver.resolve_with_ttl(&host).await?;
+        let ttl = ttl.map_or(self.max_ttl, |ttl| ttl.min(self.max_ttl));
+        self.entries.lock().unwrap().insert(
+            host,
+            CachedAddresses {
+                addresses: addresses.clone(),
+                expires: Instant::now() + ttl,
+            },
+        );
+        Ok(addresses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Counts lookups, "short.test" expires right away
+    struct CountingResolver {
+        lookups: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Resolver for CountingResolver {
+        async fn resolve(&self, _host: &str) -> Result<Vec<IpAddr>, Error> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            Ok(vec!["10.0.0.1".parse().unwrap()])
+        }
+
+        async fn resolve_with_ttl(
+            &self,
+            host: &str,
+        ) -> Result<(Vec<IpAddr>, Option<Duration>), Error> {
+            let ttl = match host {
+                "short.test" => Some(Duration::from_millis(0)),
+                _ => None,
+            };
+            Ok((self.resolve(host).await?, ttl))
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_lookups() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = CountingResolver {
+            lookups: Arc::clone(&lookups),
+        };
+        let resolver = CachingResolver::new(Box::new(resolver), 16, Duration::from_secs(60));
+        let expected: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap()];
+        assert_eq!(resolver.resolve("example.test").await.unwrap(), expected);
+        assert_eq!(resolver.resolve("EXAMPLE.test").await.unwrap(), expected);
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+
+        // The record's TTL is shorter than the maximum, so it's respected
+        resolver.resolve("short.test").await.unwrap();
+        resolver.resolve("short.test").await.unwrap();
+        assert_eq!(lookups.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_resolved_again() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = CountingResolver {
+            lookups: Arc::clone(&lookups),
+        };
+        let resolver = CachingResolver::new(Box::new(resolver), 16, Duration::from_millis(0));
+        resolver.resolve("example.test").await.unwrap();
+        resolver.resolve("example.test").await.unwrap();
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn resolve_localhost() {
+        let addresses = SystemResolver.resolve("localhost").await.unwrap();
+        assert!(addresses.iter().all(|address| address.is_loopback()));
+    }
+
+    #[tokio::test]
+    async fn resolution_failure() {
+        let result = SystemResolver.resolve("does-not-exist.invalid").await;
+        assert!(matches!(result, Err(Error::DnsError(_))));
+    }
+}
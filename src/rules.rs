@@ -0,0 +1,189 @@
+use crate::lru::LruCache;
+use crate::messages::Address;
+use crate::proxy_protocol::ProxyProtocol;
+#[cfg(feature = "tls")]
+use crate::tls::UpstreamTls;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Settings applied to connections towards a set of destinations
+pub struct DestinationRule {
+    name: String,
+    destination: String,
+    port: Option<u16>,
+    proxy_protocol: Option<ProxyProtocol>,
+    #[cfg(feature = "tls")]
+    tls: Option<UpstreamTls>,
+}
+
+impl DestinationRule {
+    // The destination is either a domain, an IP address or a domain starting
+    // with a dot, which matches that domain and all of its subdomains. If no
+    // port is given, the rule applies to all of them. The rule is named after
+    // the destination unless given another name.
+    pub fn new(destination: &str, port: Option<u16>) -> Self {
+        DestinationRule {
+            name: destination.into(),
+            destination: normalize_domain(destination),
+            port,
+            proxy_protocol: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.into();
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Overrides the context's PROXY protocol setting for these destinations
+    pub fn set_proxy_protocol(&mut self, version: ProxyProtocol) {
+        self.proxy_protocol = Some(version);
+    }
+
+    pub fn proxy_protocol(&self) -> Option<ProxyProtocol> {
+        self.proxy_protocol
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, tls: UpstreamTls) {
+        self.tls = Some(tls);
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<&UpstreamTls> {
+        self.tls.as_ref()
+    }
+
+    pub fn matches(&self, address: &Address, port: u16) -> bool {
+        if self.port.is_some_and(|rule_port| rule_port != port) {
+            return false;
+        }
+        match address {
+            Address::Ip(address) => self.destination == address.to_string(),
+            Address::Domain(domain) => domain_matches(&self.destination, &normalize_domain(domain)),
+        }
+    }
+}
+
+// Lowercases the domain and drops the trailing dot of a fully qualified one,
+// so all of the ways of writing it compare equal
+pub(crate) fn normalize_domain(domain: &str) -> String {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    domain.to_lowercase()
+}
+
+// Whether the normalized domain matches the pattern, which is either a domain
+// or one starting with a dot that matches that domain and all of its
+// subdomains
+pub(crate) fn domain_matches(pattern: &str, domain: &str) -> bool {
+    match pattern.strip_prefix('.') {
+        Some(suffix) => domain == suffix || domain.ends_with(pattern),
+        None => domain == pattern,
+    }
+}
+
+// The index of the matching rule, if any, and when that was decided
+type CachedDecision = (Option<usize>, Instant);
+
+// Remembers which rule, by index, each destination matched for a short while
+// so repeated requests don't walk through all of the rules again
+pub struct RuleDecisionCache {
+    ttl: Duration,
+    entries: Mutex<LruCache<(Address, u16), CachedDecision>>,
+}
+
+impl RuleDecisionCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        RuleDecisionCache {
+            ttl,
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    // Returns the cached decision unless it's missing or expired
+    pub fn get(&self, address: &Address, port: u16) -> Option<Option<usize>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&cache_key(address, port)) {
+            Some((index, created)) if created.elapsed() < self.ttl => Some(*index),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, address: &Address, port: u16, index: Option<usize>) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(cache_key(address, port), (index, Instant::now()));
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+// Domains are matched case insensitively, so spellings of the same one share
+// an entry
+fn cache_key(address: &Address, port: u16) -> (Address, u16) {
+    match address {
+        Address::Domain(domain) => (Address::Domain(normalize_domain(domain)), port),
+        _ => (address.clone(), port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain(name: &str) -> Address {
+        Address::Domain(name.into())
+    }
+
+    #[test]
+    fn match_exact_domain() {
+        let rule = DestinationRule::new("example.com", None);
+        assert!(rule.matches(&domain("example.com"), 443));
+        assert!(rule.matches(&domain("EXAMPLE.com"), 443));
+        assert!(rule.matches(&domain("example.com."), 443));
+        assert!(!rule.matches(&domain("www.example.com"), 443));
+    }
+
+    #[test]
+    fn match_domain_suffix() {
+        let rule = DestinationRule::new(".example.com", None);
+        assert!(rule.matches(&domain("example.com"), 80));
+        assert!(rule.matches(&domain("www.example.com"), 80));
+        assert!(!rule.matches(&domain("badexample.com"), 80));
+    }
+
+    #[test]
+    fn match_port() {
+        let rule = DestinationRule::new("example.com", Some(443));
+        assert!(rule.matches(&domain("example.com"), 443));
+        assert!(!rule.matches(&domain("example.com"), 80));
+    }
+
+    #[test]
+    fn match_ip() {
+        let rule = DestinationRule::new("10.0.0.1", None);
+        assert!(rule.matches(&Address::Ip("10.0.0.1".parse().unwrap()), 22));
+        assert!(!rule.matches(&Address::Ip("10.0.0.2".parse().unwrap()), 22));
+    }
+
+    #[test]
+    fn cached_decision_expires() {
+        let cache = RuleDecisionCache::new(16, Duration::from_millis(0));
+        cache.insert(&domain("example.com"), 80, Some(0));
+        assert_eq!(cache.get(&domain("example.com"), 80), None);
+
+        let cache = RuleDecisionCache::new(16, Duration::from_secs(60));
+        cache.insert(&domain("example.com"), 80, Some(0));
+        assert_eq!(cache.get(&domain("example.com"), 80), Some(Some(0)));
+        assert_eq!(cache.get(&domain("example.com"), 443), None);
+        assert_eq!(cache.get(&domain("EXAMPLE.com."), 80), Some(Some(0)));
+    }
+}
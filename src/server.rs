@@ -0,0 +1,526 @@
+use crate::context::Context;
+use crate::error::Error;
+use crate::listener::Accept;
+use crate::states::State;
+use futures::future::{pending, try_join_all};
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::{interval, sleep, timeout};
+use tracing::field::Empty;
+use tracing::{info, warn};
+use tracing::{info_span, Instrument};
+
+// How long to wait after failing to accept before trying again
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+// Serves clients on the listener until accepting fails for good
+pub async fn serve(listener: impl Accept, context: Arc<Context>) -> Result<(), Error> {
+    serve_with_shutdown(listener, context, pending()).await
+}
+
+// Serves clients on the listener until `shutdown` resolves. See `serve_all`.
+pub async fn serve_with_shutdown<F>(
+    listener: impl Accept,
+    context: Arc<Context>,
+    shutdown: F,
+) -> Result<(), Error>
+where
+    F: Future<Output = ()>,
+{
+    serve_all(vec![listener], context, shutdown).await
+}
+
+// Serves clients on all of the listeners until `shutdown` resolves or
+// accepting on any of them fails for good. Errors that may go away on their
+// own, like running out of file descriptors, are logged and retried. The
+// stats are logged every so often while serving, if the context asks for it. On shutdown the listeners are closed and
+// active connections get up to the context's grace period to finish, after
+// which the remaining ones are closed.
+pub async fn serve_all<L, F>(
+    listeners: Vec<L>,
+    context: Arc<Context>,
+    shutdown: F,
+) -> Result<(), Error>
+where
+    L: Accept,
+    F: Future<Output = ()>,
+{
+    // Every connection task holds a receiver, the sender sees the channel
+    // closed once all of them are done
+    let (tasks_done, task_handle) = watch::channel(());
+    // Sending on this closes every connection that's still active
+    let (close_connections, close_signal) = watch::channel(());
+    let connection_ids = AtomicU64::new(1);
+    let serving = try_join_all(listeners.into_iter().map(|listener| {
+        accept_loop(
+            listener,
+            &context,
+            &task_handle,
+            &close_signal,
+            &connection_ids,
+        )
+    }));
+    // Dropping the accept loops on shutdown closes the listeners
+    tokio::select! {
+        result = serving => {
+            result?;
+        }
+        _ = shutdown => (),
+        _ = log_stats_periodically(&context) => (),
+    };
+    drop(task_handle);
+    let grace_period = context.shutdown_grace_period();
+    info!(
+        "Shutting down, waiting up to {:?} for active connections to finish",
+        grace_period
+    );
+    if timeout(grace_period, tasks_done.closed()).await.is_err() {
+        warn!(
+            "Grace period expired with {} connections still active, closing them",
+            context.stats().snapshot().active_connections
+        );
+        let _ = close_connections.send(());
+        tasks_done.closed().await;
+    }
+    Ok(())
+}
+
+// Logs a summary of the stats at the context's interval. Never returns,
+// without an interval it does nothing at all.
+async fn log_stats_periodically(context: &Context) {
+    let period = match context.stats_interval() {
+        Some(period) => period,
+        None => return pending().await,
+    };
+    let mut ticker = interval(period);
+    // The first tick completes right away
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        info!("Stats: {}", context.stats().snapshot());
+    }
+}
+
+// Whether accepting may succeed if tried again later, as opposed to the
+// listener being unusable
+fn is_transient_accept_error(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        use nix::errno::Errno;
+
+        if let Some(code) = error.raw_os_error() {
+            let errno = Errno::from_raw(code);
+            if matches!(
+                errno,
+                Errno::EMFILE | Errno::ENFILE | Errno::ENOBUFS | Errno::ENOMEM | Errno::EPROTO
+            ) {
+                return true;
+            }
+        }
+    }
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::OutOfMemory
+    )
+}
+
+// Accepts connections and serves each of them on its own task, until
+// accepting fails for good
+async fn accept_loop(
+    listener: impl Accept,
+    context: &Arc<Context>,
+    task_handle: &watch::Receiver<()>,
+    close_signal: &watch::Receiver<()>,
+    connection_ids: &AtomicU64,
+) -> Result<(), Error> {
+    let mut rate_limited = DroppedConnections::default();
+    loop {
+        let connection = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) if is_transient_accept_error(&e) => {
+                warn!("Failed to accept connection: {}", e);
+                sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let peer_ip = connection.peer_ip();
+        if peer_ip.is_some_and(|ip| !context.allows_source(ip)) {
+            warn!(
+                "Rejecting connection from {}: source not allowed",
+                connection
+            );
+            continue;
+        }
+        // Throttled before waiting for a slot, so a flood of connections
+        // doesn't hold up everyone else
+        if peer_ip.is_some_and(|ip| !context.allow_connection(ip)) {
+            if let Some(count) = rate_limited.record(Instant::now()) {
+                warn!(
+                    "Dropping connection from {}: over rate limit ({} dropped since the last warning)",
+                    connection, count
+                );
+            }
+            continue;
+        }
+        if peer_ip.is_some_and(|ip| context.is_locked_out(ip)) {
+            warn!(
+                "Dropping connection from {}: too many failed logins",
+                connection
+            );
+            continue;
+        }
+        // When waiting for a slot, nothing else is accepted on this listener
+        // until one frees up
+        let slot = match context.acquire_connection_slot().await {
+            Some(slot) => slot,
+            None => {
+                warn!(
+                    "Dropping connection from {}: too many connections",
+                    connection
+                );
+                continue;
+            }
+        };
+        let context = Arc::clone(context);
+        let task_handle = task_handle.clone();
+        let mut close_signal = close_signal.clone();
+        // Everything logged while serving the connection is tagged with its
+        // span, the states fill in the rest of the fields as they go
+        let span = info_span!(
+            "connection",
+            id = connection_ids.fetch_add(1, Ordering::Relaxed),
+            client = %connection,
+            auth_method = Empty,
+            target = Empty,
+            reply = Empty,
+            bytes_in = Empty,
+            bytes_out = Empty,
+        );
+        let task = async move {
+            let _task_handle = task_handle;
+            let _slot = slot;
+            let _active = context.stats().connection_opened();
+            #[cfg(feature = "metrics")]
+            let _active_metric = context.metrics().connection_opened();
+            let serve_connection = async {
+                let stream = match connection.into_stream(&context).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Failed to set up client stream: {:?}", e);
+                        return;
+                    }
+                };
+                let mut state = State::new(stream);
+                loop {
+                    let result = state.process(&context).await;
+                    if result.is_err() {
+                        warn!("Stream finished with error: {:?}", result.err().unwrap());
+                        break;
+                    }
+                    state = result.unwrap();
+                    if state.is_finished() {
+                        break;
+                    }
+                }
+                info!("Connection closed");
+            };
+            // Dropping the connection's future closes its streams
+            tokio::select! {
+                _ = serve_connection => (),
+                Ok(()) = close_signal.changed() => {
+                    info!("Connection closed on shutdown");
+                }
+            }
+        };
+        tokio::spawn(task.instrument(span));
+    }
+}
+
+// Throttles the warnings about dropped connections to one a second, so a
+// flood of connections doesn't flood the logs as well
+#[derive(Default)]
+struct DroppedConnections {
+    count: u64,
+    last_warning: Option<Instant>,
+}
+
+impl DroppedConnections {
+    // Counts a dropped connection, returning how many to warn about if it's
+    // time for a warning
+    fn record(&mut self, now: Instant) -> Option<u64> {
+        self.count += 1;
+        if self
+            .last_warning
+            .is_some_and(|last| now.duration_since(last) < Duration::from_secs(1))
+        {
+            return None;
+        }
+        self.last_warning = Some(now);
+        Some(std::mem::take(&mut self.count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Connection;
+    use crate::rate_limit::RateLimit;
+    use crate::stream::Stream;
+    use async_trait::async_trait;
+    use std::io;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{mpsc, oneshot, Mutex};
+
+    #[test]
+    fn dropped_connection_warnings_throttled() {
+        let mut dropped = DroppedConnections::default();
+        let start = Instant::now();
+        assert_eq!(dropped.record(start), Some(1));
+        assert_eq!(dropped.record(start + Duration::from_millis(10)), None);
+        assert_eq!(dropped.record(start + Duration::from_millis(500)), None);
+        assert_eq!(dropped.record(start + Duration::from_secs(1)), Some(3));
+    }
+
+    #[tokio::test]
+    async fn serve_connect_request() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(Context::default())));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [5, 0]);
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&backend_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], 0);
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    // Hands out the streams sent through the channel, then waits forever
+    struct MemoryListener(Mutex<mpsc::Receiver<DuplexStream>>);
+
+    #[async_trait]
+    impl Accept for MemoryListener {
+        async fn accept(&self) -> io::Result<Connection> {
+            match self.0.lock().await.recv().await {
+                Some(stream) => Ok(Connection::Stream(Stream::from_io(stream), None)),
+                None => pending().await,
+            }
+        }
+    }
+
+    // Fails with the given errors before handing out any streams
+    struct FailingListener(Mutex<Vec<io::ErrorKind>>, MemoryListener);
+
+    #[async_trait]
+    impl Accept for FailingListener {
+        async fn accept(&self) -> io::Result<Connection> {
+            if let Some(kind) = self.0.lock().await.pop() {
+                return Err(kind.into());
+            }
+            self.1.accept().await
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_accept_errors_retried() {
+        let (connections, receiver) = mpsc::channel(1);
+        let listener = FailingListener(
+            Mutex::new(vec![io::ErrorKind::ConnectionAborted]),
+            MemoryListener(Mutex::new(receiver)),
+        );
+        tokio::spawn(serve(listener, Arc::new(Context::default())));
+
+        // The connection's only served if the loop kept going
+        let (mut client, server_side) = duplex(64);
+        connections.send(server_side).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [5, 0]);
+    }
+
+    #[tokio::test]
+    async fn unrecoverable_accept_errors_stop_serving() {
+        let (_connections, receiver) = mpsc::channel(1);
+        let listener = FailingListener(
+            Mutex::new(vec![io::ErrorKind::InvalidInput]),
+            MemoryListener(Mutex::new(receiver)),
+        );
+        let result = timeout(
+            Duration::from_secs(1),
+            serve(listener, Arc::new(Context::default())),
+        )
+        .await
+        .unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn in_memory_connections_served() {
+        let (connections, receiver) = mpsc::channel(1);
+        let mut context = Context::default();
+        context.set_shutdown_grace_period(Duration::from_millis(100));
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+        let listener = MemoryListener(Mutex::new(receiver));
+        let server = tokio::spawn(serve_with_shutdown(listener, Arc::new(context), async {
+            let _ = shutdown_signal.await;
+        }));
+
+        let (mut client, server_side) = duplex(64);
+        connections.send(server_side).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [5, 0]);
+
+        // The client never sends its request, so it's closed on shutdown
+        shutdown.send(()).unwrap();
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).await.unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connections_over_rate_limit_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut context = Context::default();
+        let limit = RateLimit {
+            per_second: 0.001,
+            burst: 1,
+        };
+        context.set_connection_rate_limits(None, Some(limit));
+        tokio::spawn(serve(listener, Arc::new(context)));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [5, 0]);
+
+        // The second one from the same address is closed right away
+        let mut client = TcpStream::connect(address).await.unwrap();
+        let mut data = Vec::new();
+        timeout(Duration::from_secs(1), client.read_to_end(&mut data))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stats_logged_while_serving() {
+        crate::testing::capture_logs();
+        let stats_logged = || {
+            crate::testing::logged_messages("rusty_socks::server")
+                .iter()
+                .filter(|message| message.starts_with("Stats: "))
+                .count()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut context = Context::default();
+        context.set_stats_interval(Duration::from_millis(20));
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_with_shutdown(listener, Arc::new(context), async {
+            let _ = shutdown_signal.await;
+        }));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(stats_logged() > 0);
+        shutdown.send(()).unwrap();
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        // Nothing's logged once the server's done
+        let logged = stats_logged();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(stats_logged(), logged);
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut context = Context::default();
+        context.set_shutdown_grace_period(Duration::from_secs(5));
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_with_shutdown(listener, Arc::new(context), async {
+            let _ = shutdown_signal.await;
+        }));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        shutdown.send(()).unwrap();
+        // The connection's still being served, so the server waits for it
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(address).await.is_err());
+        drop(client);
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_connections_after_grace_period() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut context = Context::default();
+        context.set_shutdown_grace_period(Duration::from_millis(100));
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_with_shutdown(listener, Arc::new(context), async {
+            let _ = shutdown_signal.await;
+        }));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        shutdown.send(()).unwrap();
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        // The server closed the connection on its own
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).await.unwrap();
+        assert!(data.is_empty());
+    }
+}
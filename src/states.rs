@@ -1,19 +1,89 @@
-use crate::context::Context;
+use crate::chain;
+use crate::context::{ConnectSlot, Context};
 use crate::error::Error;
 use crate::messages::*;
+use crate::rate_limit::BandwidthLimiter;
+use crate::rules::DestinationRule;
 use crate::stream::Stream;
-use futures::try_join;
-use log::{debug, info};
-use std::net::{IpAddr, Ipv4Addr};
+use crate::upstream;
+use futures::future::{select, Either, TryFutureExt};
+use futures::pin_mut;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{split, ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::prelude::*;
+use tokio::time::{sleep, timeout};
+use tracing::field::{self, debug};
+use tracing::{debug, info, warn};
+use tracing::{info_span, Instrument, Span};
+use zeroize::Zeroize;
+
+// Target used for authentication audit records, so they can be routed
+// separately from the regular logs
+pub const AUDIT_LOG_TARGET: &str = "rusty_socks::audit";
+
+// What's known about a session by the time it starts proxying
+#[derive(Default)]
+pub struct Session {
+    // The name of the destination rule the request matched, if any
+    pub rule: Option<String>,
+    // The user the client authenticated as, if it did
+    pub user: Option<String>,
+    // The method negotiated in the hello. SOCKS4 clients don't negotiate one.
+    pub auth_method: Option<AuthenticationMethod>,
+}
+
+// Bytes proxied in each direction over a session
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ProxyStats {
+    pub client_to_server: u64,
+    pub server_to_client: u64,
+}
+
+// Bytes proxied so far over a session that's still going, updated after
+// every write. Clones share the same counters.
+#[derive(Clone, Debug, Default)]
+pub struct LiveStats {
+    pub client_to_server: Arc<AtomicU64>,
+    pub server_to_client: Arc<AtomicU64>,
+}
+
+impl LiveStats {
+    pub fn snapshot(&self) -> ProxyStats {
+        ProxyStats {
+            client_to_server: self.client_to_server.load(Ordering::Relaxed),
+            server_to_client: self.server_to_client.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for ProxyStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "client_to_server={} server_to_client={}",
+            self.client_to_server, self.server_to_client
+        )
+    }
+}
 
 pub enum State {
     AwaitingHello(Stream),
     AwaitingAuth(Stream),
-    AwaitingClientRequest(Stream),
-    Proxying(Stream, Stream),
+    // Holds the negotiated method and the user the client authenticated
+    // as, if it did
+    AwaitingClientRequest(Stream, AuthenticationMethod, Option<String>),
+    // A BIND request was accepted, waiting for the peer to connect back. Holds
+    // the addresses the peer may connect from, any of them if empty, and the
+    // session the connection will be proxied as.
+    AwaitingBindConnection(Stream, TcpListener, Vec<IpAddr>, Session),
+    Proxying(Stream, Stream, Session),
     Finished,
 }
 
@@ -27,118 +97,3043 @@ impl State {
     }
 
     pub async fn process(self, context: &Context) -> Result<Self, Error> {
+        let connection = Span::current();
+        CONNECTION_SPAN
+            .scope(connection, self.process_phase(context))
+            .await
+    }
+
+    async fn process_phase(self, context: &Context) -> Result<Self, Error> {
         match self {
+            // Each phase gets its own span, nested in the connection's
             State::AwaitingHello(client_stream) => {
-                State::process_await_hello(client_stream, context).await
+                State::process_await_hello(client_stream, context)
+                    .instrument(info_span!("awaiting_hello"))
+                    .await
             }
             State::AwaitingAuth(client_stream) => {
-                State::process_await_auth(client_stream, context).await
+                State::process_await_auth(client_stream, context)
+                    .instrument(info_span!("awaiting_auth"))
+                    .await
             }
-            State::AwaitingClientRequest(client_stream) => {
-                State::process_await_client_request(client_stream).await
+            State::AwaitingClientRequest(client_stream, method, user) => {
+                let span = info_span!(
+                    "awaiting_request",
+                    user = user.as_deref(),
+                    target = field::Empty
+                );
+                State::process_await_client_request(client_stream, method, user, context)
+                    .instrument(span)
+                    .await
             }
-            State::Proxying(client_stream, output_stream) => {
-                State::do_proxy(client_stream, output_stream).await
+            State::AwaitingBindConnection(client_stream, listener, peers, session) => {
+                State::process_await_bind(client_stream, listener, peers, session, context)
+                    .instrument(info_span!("awaiting_bind"))
+                    .await
+            }
+            State::Proxying(client_stream, output_stream, session) => {
+                let span = info_span!(
+                    "proxying",
+                    user = session.user.as_deref(),
+                    rule = session.rule.as_deref()
+                );
+                State::do_proxy(client_stream, output_stream, session, context)
+                    .instrument(span)
+                    .await
             }
             State::Finished => Err(Error::Finished),
         }
     }
 
     async fn process_await_hello(mut stream: Stream, context: &Context) -> Result<Self, Error> {
-        let request = HelloRequest::new(&mut stream).await?;
+        context
+            .event_listener()
+            .on_connect(stream.peer_addr())
+            .await;
+        // SOCKS4 clients skip the handshake, tell them apart by the version
+        let first_byte = timeout(context.handshake_timeout(), stream.peek_u8())
+            .await
+            .map_err(|_| Error::HandshakeTimeout)?;
+        match first_byte {
+            Ok(Some(version @ 4..=5)) if !context.allows_socks_version(version) => {
+                warn!(
+                    "Rejecting client using disallowed SOCKS version {}",
+                    version
+                );
+                if version == 4 {
+                    return Self::reply_socks4_failure(stream).await;
+                }
+                return Ok(State::Finished);
+            }
+            Ok(Some(4)) => return State::process_socks4_request(stream, context).await,
+            Ok(Some(byte)) if byte != 5 && context.reject_non_socks_quietly() => {
+                debug!("Closing non-SOCKS connection starting with {:#04x}", byte);
+                return Ok(State::Finished);
+            }
+            _ => (),
+        }
+        let request: HelloRequest = read_handshake(&mut stream, context).await?;
         if request.version != 5 {
             return Err(Error::MalformedMessage(
                 format!("Unsupported socks version {}", request.version).into(),
             ));
         }
-        if request.methods.is_empty() {
-            return Err(Error::MalformedMessage("No methods provided".into()));
-        }
-        let selected_method = match context.select_authentication(request.methods) {
+        let selected_method = match context.select_authentication(
+            &request.known_methods(),
+            stream.peer_addr().map(|address| address.ip()),
+        ) {
             Some(method) => method,
-            None => return Ok(State::Finished),
+            None => {
+                if context.log_rejected_methods() {
+                    warn!(
+                        "No acceptable authentication method, client offered: {}",
+                        request.describe_methods()
+                    );
+                }
+                let response =
+                    HelloResponse::new(request.version, AuthenticationMethod::NoAcceptableMethods);
+                write_message(&response, &mut stream).await?;
+                return Ok(State::Finished);
+            }
         };
         info!("Received new client using auth {}", selected_method);
+        record_on_connection("auth_method", debug(selected_method));
         let response = HelloResponse::new(request.version, selected_method);
-        response.write(&mut stream).await?;
+        write_message(&response, &mut stream).await?;
         match selected_method {
-            AuthenticationMethod::NoAuthentication => Ok(State::AwaitingClientRequest(stream)),
+            AuthenticationMethod::NoAuthentication => {
+                Ok(State::AwaitingClientRequest(stream, selected_method, None))
+            }
             AuthenticationMethod::UsernamePassword => Ok(State::AwaitingAuth(stream)),
+            // There's no GSSAPI support, even if an authenticator asks for it
+            AuthenticationMethod::Gssapi | AuthenticationMethod::NoAcceptableMethods => {
+                Ok(State::Finished)
+            }
         }
     }
 
     async fn process_await_auth(mut stream: Stream, context: &Context) -> Result<Self, Error> {
-        let request = AuthRequest::new(&mut stream).await?;
-        let status = match context.authenticate(&request.username, &request.password) {
+        let mut request: AuthRequest = match read_handshake(&mut stream, context).await {
+            Ok(request) => request,
+            Err(Error::MalformedMessage(reason)) => {
+                warn!("Malformed authentication request: {}", reason);
+                // It counts as a failed login, the username being unknown
+                record_auth_result(&stream, context, "", AuthStatusCode::Failure);
+                // Clients still expect a reply, and there's only one version
+                // of it to send
+                let response = AuthResponse::new(1, AuthStatusCode::Failure);
+                write_message(&response, &mut stream).await?;
+                return Ok(State::Finished);
+            }
+            Err(e) => return Err(e),
+        };
+        // Another connection from the same client may have used up its
+        // attempts since this one was accepted
+        if let Some(address) = stream
+            .peer_addr()
+            .filter(|address| context.is_locked_out(address.ip()))
+        {
+            request.password.zeroize();
+            warn!("Refusing login from {}: too many failed logins", address);
+            let response = AuthResponse::new(request.version, AuthStatusCode::Failure);
+            write_message(&response, &mut stream).await?;
+            return Ok(State::Finished);
+        }
+        let status = match context
+            .authenticate(&request.username, &request.password)
+            .await
+        {
             true => AuthStatusCode::Success,
             false => AuthStatusCode::Failure,
         };
+        // It's not needed anymore
+        request.password.zeroize();
         debug!("Authentication request finished with status: {:?}", status);
+        record_auth_result(&stream, context, &request.username, status);
         let response = AuthResponse::new(request.version, status);
-        response.write(&mut stream).await?;
-        Ok(State::AwaitingClientRequest(stream))
+        write_message(&response, &mut stream).await?;
+        // RFC 1929 has the server close the connection after a failure
+        if status == AuthStatusCode::Failure {
+            return Ok(State::Finished);
+        }
+        context
+            .event_listener()
+            .on_authenticated(&request.username)
+            .await;
+        Ok(State::AwaitingClientRequest(
+            stream,
+            AuthenticationMethod::UsernamePassword,
+            Some(request.username),
+        ))
     }
 
-    async fn process_await_client_request(mut client_stream: Stream) -> Result<Self, Error> {
-        let request = ClientRequest::new(&mut client_stream).await?;
+    async fn process_await_client_request(
+        mut client_stream: Stream,
+        auth_method: AuthenticationMethod,
+        user: Option<String>,
+        context: &Context,
+    ) -> Result<Self, Error> {
+        #[cfg_attr(not(feature = "idna"), allow(unused_mut))]
+        let mut request: ClientRequest = read_handshake(&mut client_stream, context).await?;
         if request.version != 5 {
             return Err(Error::MalformedMessage("Invalid socks version".into()));
         }
-        let output_stream = match request.address {
-            Address::Ip(address) => {
-                let endpoint = (address, request.port);
-                info!("Establishing connection with {:?}", endpoint);
-                TcpStream::connect(endpoint).await
+        record_target(&request.address, request.port);
+        #[cfg(feature = "idna")]
+        if let Err(e) = to_ascii_domain(&mut request.address) {
+            warn!("Invalid domain {:?}: {}", request.address, e);
+            return Self::reply_failure(
+                client_stream,
+                request.version,
+                ResponseCode::HostUnreachable,
+            )
+            .await;
+        }
+        if request.command != Command::Connect {
+            // CONNECT requests check it along with the rest of the policies
+            if let Some(user) = user
+                .as_deref()
+                .filter(|user| context.user_quota_exceeded(user))
+            {
+                warn!("User {} is over their quota", user);
+                return Self::reply_failure(
+                    client_stream,
+                    request.version,
+                    ResponseCode::ConnectionNotAllowed,
+                )
+                .await;
             }
-            Address::Domain(ref domain) => {
-                let endpoint = (domain.as_str(), request.port);
-                info!("Establishing connection with {:?}", endpoint);
-                TcpStream::connect(endpoint).await
+        }
+        match request.command {
+            Command::Connect => (),
+            Command::Bind => {
+                return Self::process_bind_request(
+                    client_stream,
+                    request,
+                    auth_method,
+                    user,
+                    context,
+                )
+                .await
             }
-        }?;
+            Command::UdpAssociate => {
+                return Self::process_udp_associate(
+                    client_stream,
+                    request,
+                    user.as_deref(),
+                    context,
+                )
+                .await
+            }
+        }
+        let session = Session {
+            user,
+            auth_method: Some(auth_method),
+            ..Session::default()
+        };
+        let (output_stream, session) = match connect_upstream(
+            &mut client_stream,
+            &request.address,
+            request.port,
+            session,
+            context,
+        )
+        .await?
+        {
+            ConnectOutcome::Connected(output_stream, session) => (*output_stream, session),
+            ConnectOutcome::Failed(code) => {
+                #[cfg(feature = "metrics")]
+                context.metrics().record_connect_error(code);
+                return Self::reply_failure(client_stream, request.version, code).await;
+            }
+            ConnectOutcome::Abandoned => return Ok(State::Finished),
+        };
+        // The address the proxy uses for this connection, as RFC 1928 asks
+        let bound_address = output_stream
+            .local_addr()
+            .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0)), 0));
+        let response = RequestResponse::new(
+            request.version,
+            ResponseCode::Success,
+            Address::Ip(bound_address.ip()),
+            bound_address.port(),
+        );
+        write_message(&response, &mut client_stream).await?;
+        record_reply(ResponseCode::Success);
+        Ok(Self::Proxying(client_stream, output_stream, session))
+    }
+
+    // Listens for the connection the client expects from `request.address` on
+    // the interface the client reached us through. The first of the two
+    // replies tells the client where it should tell its peer to connect to.
+    // The peer goes through the same policies as a CONNECT destination.
+    async fn process_bind_request(
+        mut client_stream: Stream,
+        request: ClientRequest,
+        auth_method: AuthenticationMethod,
+        user: Option<String>,
+        context: &Context,
+    ) -> Result<Self, Error> {
+        if let Some(code) =
+            check_request(&request.address, request.port, user.as_deref(), context).await
+        {
+            return Self::reply_failure(client_stream, request.version, code).await;
+        }
+        // Only connections from the addresses the domain resolves to are
+        // accepted. An unspecified address accepts any peer.
+        let peers = match &request.address {
+            Address::Ip(ip) if ip.is_unspecified() => Vec::new(),
+            Address::Ip(ip) => vec![*ip],
+            Address::Domain(domain) => match resolve(domain, request.port, context).await {
+                Ok(addresses) => addresses.iter().map(SocketAddr::ip).collect(),
+                Err(e) => {
+                    warn!("{}", e);
+                    return Self::reply_failure(
+                        client_stream,
+                        request.version,
+                        e.to_response_code(),
+                    )
+                    .await;
+                }
+            },
+        };
+        if peers
+            .iter()
+            .any(|ip| !context.destination_acl().allows_ip(*ip))
+        {
+            warn!("BIND peer {:?} not allowed", request.address);
+            return Self::reply_failure(
+                client_stream,
+                request.version,
+                ResponseCode::ConnectionNotAllowed,
+            )
+            .await;
+        }
+        let local_ip = client_stream
+            .local_addr()
+            .map(|address| address.ip())
+            .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::from(0)));
+        let listener = match TcpListener::bind((local_ip, 0)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Failed to listen for BIND request: {}", e);
+                return Self::reply_failure(
+                    client_stream,
+                    request.version,
+                    ResponseCode::GeneralFailure,
+                )
+                .await;
+            }
+        };
+        let bound_address = listener.local_addr()?;
+        info!(
+            "Waiting for connection from {:?} on {}",
+            (&request.address, request.port),
+            bound_address
+        );
         let response = RequestResponse::new(
             request.version,
             ResponseCode::Success,
-            Address::Ip(IpAddr::V4(Ipv4Addr::from(0))),
-            0, // Port?
+            Address::Ip(bound_address.ip()),
+            bound_address.port(),
+        );
+        write_message(&response, &mut client_stream).await?;
+        let session = Session {
+            rule: context
+                .find_destination_rule(&request.address, request.port)
+                .map(|rule| rule.name().into()),
+            user,
+            auth_method: Some(auth_method),
+        };
+        Ok(State::AwaitingBindConnection(
+            client_stream,
+            listener,
+            peers,
+            session,
+        ))
+    }
+
+    // Accepts a single connection and sends the second reply, carrying the
+    // address of the peer that connected
+    async fn process_await_bind(
+        mut client_stream: Stream,
+        listener: TcpListener,
+        peers: Vec<IpAddr>,
+        session: Session,
+        context: &Context,
+    ) -> Result<Self, Error> {
+        let accepted = tokio::select! {
+            _ = client_stream.closed() => {
+                info!("Client went away while waiting for BIND connection");
+                return Ok(State::Finished);
+            }
+            accepted = timeout(context.bind_timeout(), listener.accept()) => accepted,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(accepted) => accepted?,
+            Err(_) => {
+                warn!("Timed out waiting for BIND connection");
+                return Self::reply_failure(client_stream, 5, ResponseCode::TtlExpired).await;
+            }
+        };
+        let peer_ip = peer_addr.ip().to_canonical();
+        let expected = peers.is_empty() || peers.iter().any(|ip| ip.to_canonical() == peer_ip);
+        if !expected || !context.destination_acl().allows_ip(peer_ip) {
+            warn!(
+                "Rejecting BIND connection from {}, expected {:?}",
+                peer_addr, peers
+            );
+            return Self::reply_failure(client_stream, 5, ResponseCode::ConnectionNotAllowed).await;
+        }
+        let response = RequestResponse::new(
+            5,
+            ResponseCode::Success,
+            Address::Ip(peer_addr.ip()),
+            peer_addr.port(),
         );
-        response.write(&mut client_stream).await?;
+        write_message(&response, &mut client_stream).await?;
         Ok(Self::Proxying(
             client_stream,
-            Stream::unbuffered(output_stream),
+            Stream::unbuffered(stream),
+            session,
         ))
     }
 
-    async fn do_proxy(client_stream: Stream, output_stream: Stream) -> Result<Self, Error> {
-        let (client_reader, client_writer) = split(client_stream.into_unbuffered());
-        let (output_reader, output_writer) = split(output_stream.into_unbuffered());
-        let mut client_proxier = Proxier::new(client_reader, output_writer);
-        let mut output_proxier = Proxier::new(output_reader, client_writer);
-        // We don't really care what happened, we're done anyway
-        let _result = try_join!(client_proxier.run(), output_proxier.run());
-        Ok(Self::Finished)
+    // Relays datagrams between the client and any destination it asks for
+    // until the client closes the control connection, or the user goes over
+    // their quota
+    async fn process_udp_associate(
+        mut client_stream: Stream,
+        request: ClientRequest,
+        user: Option<&str>,
+        context: &Context,
+    ) -> Result<Self, Error> {
+        let local_ip = client_stream
+            .local_addr()
+            .map(|address| address.ip())
+            .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::from(0)));
+        let socket = match UdpSocket::bind((local_ip, 0)).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Failed to bind UDP socket for association: {}", e);
+                return Self::reply_failure(
+                    client_stream,
+                    request.version,
+                    ResponseCode::GeneralFailure,
+                )
+                .await;
+            }
+        };
+        let relay_address = socket.local_addr()?;
+        info!("Relaying UDP datagrams on {}", relay_address);
+        let response = RequestResponse::new(
+            request.version,
+            ResponseCode::Success,
+            Address::Ip(relay_address.ip()),
+            relay_address.port(),
+        );
+        write_message(&response, &mut client_stream).await?;
+
+        // The client's port is only known in advance if it told us
+        let mut client_address = match request.address {
+            Address::Ip(ip) if !ip.is_unspecified() && request.port != 0 => {
+                Some(SocketAddr::new(ip, request.port))
+            }
+            _ => None,
+        };
+        let client_ip = client_stream.peer_addr().map(|address| address.ip());
+        let mut buffer = vec![0; 65535];
+        loop {
+            let (size, source) = tokio::select! {
+                result = client_stream.closed() => {
+                    result?;
+                    info!("Control connection closed, ending UDP association");
+                    return Ok(State::Finished);
+                }
+                received = socket.recv_from(&mut buffer) => received?,
+            };
+            let datagram = &buffer[..size];
+            let from_client = match client_address {
+                Some(address) => address == source,
+                None => Some(source.ip()) == client_ip,
+            };
+            let result = if from_client {
+                client_address = Some(source);
+                relay_from_client(&socket, datagram, context).await
+            } else if let Some(client_address) = client_address {
+                relay_to_client(&socket, datagram, source, client_address, context).await
+            } else {
+                debug!("Dropping UDP datagram from unknown source {}", source);
+                Ok(0)
+            };
+            let relayed = match result {
+                Ok(relayed) => relayed,
+                Err(e) => {
+                    debug!("Failed to relay UDP datagram from {}: {:?}", source, e);
+                    0
+                }
+            };
+            if let Some(user) = user.filter(|_| relayed > 0) {
+                context.record_user_traffic(user, relayed as u64);
+                if context.user_quota_exceeded(user) {
+                    info!(
+                        "User {} went over their quota, ending UDP association",
+                        user
+                    );
+                    return Ok(State::Finished);
+                }
+            }
+        }
     }
+
+    async fn process_socks4_request(
+        mut client_stream: Stream,
+        context: &Context,
+    ) -> Result<Self, Error> {
+        let request: Socks4Request = read_handshake(&mut client_stream, context).await?;
+        record_target(&request.address, request.port);
+        if request.command != Command::Connect {
+            warn!("Rejecting unsupported SOCKS4 command {:?}", request.command);
+            record_reply(ResponseCode::CommandNotSupported);
+            return Self::reply_socks4_failure(client_stream).await;
+        }
+        // There's no way to authenticate SOCKS4 clients
+        if context
+            .select_authentication(
+                &[AuthenticationMethod::NoAuthentication],
+                client_stream.peer_addr().map(|address| address.ip()),
+            )
+            .is_none()
+        {
+            warn!("Rejecting SOCKS4 request, authentication is required");
+            record_reply(ResponseCode::ConnectionNotAllowed);
+            return Self::reply_socks4_failure(client_stream).await;
+        }
+        info!(
+            "Received new SOCKS4 client with user id {:?}",
+            request.user_id
+        );
+        let connect = connect_upstream(
+            &mut client_stream,
+            &request.address,
+            request.port,
+            Session::default(),
+            context,
+        );
+        match connect.await? {
+            ConnectOutcome::Connected(output_stream, session) => {
+                let response =
+                    Socks4Response::new(Socks4ResponseCode::Granted, Ipv4Addr::from(0), 0);
+                write_message(&response, &mut client_stream).await?;
+                record_reply(ResponseCode::Success);
+                Ok(Self::Proxying(client_stream, *output_stream, session))
+            }
+            ConnectOutcome::Failed(code) => {
+                #[cfg(feature = "metrics")]
+                context.metrics().record_connect_error(code);
+                record_reply(code);
+                Self::reply_socks4_failure(client_stream).await
+            }
+            ConnectOutcome::Abandoned => Ok(State::Finished),
+        }
+    }
+
+    async fn reply_failure(
+        mut client_stream: Stream,
+        version: u8,
+        code: ResponseCode,
+    ) -> Result<Self, Error> {
+        debug!("Failing request: {}", code);
+        record_reply(code);
+        let response =
+            RequestResponse::new(version, code, Address::Ip(IpAddr::V4(Ipv4Addr::from(0))), 0);
+        write_message(&response, &mut client_stream).await?;
+        Ok(State::Finished)
+    }
+
+    async fn reply_socks4_failure(mut client_stream: Stream) -> Result<Self, Error> {
+        let response = Socks4Response::new(Socks4ResponseCode::Rejected, Ipv4Addr::from(0), 0);
+        write_message(&response, &mut client_stream).await?;
+        Ok(State::Finished)
+    }
+
+    async fn do_proxy(
+        client_stream: Stream,
+        output_stream: Stream,
+        session: Session,
+        context: &Context,
+    ) -> Result<Self, Error> {
+        let client_stream = client_stream.into_unbuffered();
+        let output_stream = output_stream.into_unbuffered();
+        let flush_client = client_stream.needs_flush();
+        let flush_output = output_stream.needs_flush();
+        let (client_reader, client_writer) = split(client_stream);
+        let (output_reader, output_writer) = split(output_stream);
+        let stats = context.stats();
+        let live_stats = LiveStats::default();
+        let activity = Activity::new();
+        context
+            .event_listener()
+            .on_proxy_start(&session, &live_stats)
+            .await;
+        let mut client_proxier = Proxier::new(
+            client_reader,
+            output_writer,
+            flush_output,
+            stats.bytes_in(),
+            Arc::clone(&live_stats.client_to_server),
+            session.user.as_deref(),
+            context,
+        )
+        .sharing_activity(&activity);
+        let mut output_proxier = Proxier::new(
+            output_reader,
+            client_writer,
+            flush_client,
+            stats.bytes_out(),
+            Arc::clone(&live_stats.server_to_client),
+            session.user.as_deref(),
+            context,
+        )
+        .sharing_activity(&activity);
+        // Each direction ends on its own, so half-closed connections keep
+        // flowing the other way until that one's done too. Going idle or over
+        // the user's quota ends both right away.
+        let proxying = async {
+            let client_to_server = client_proxier.run();
+            let server_to_client = output_proxier.run();
+            pin_mut!(client_to_server, server_to_client);
+            match select(client_to_server, server_to_client).await {
+                Either::Left((Err(e), _)) if ends_session(&e) => (Err(e), Ok(())),
+                Either::Right((Err(e), _)) if ends_session(&e) => (Ok(()), Err(e)),
+                Either::Left((result, other)) => (result, other.await),
+                Either::Right((result, other)) => (other.await, result),
+            }
+        };
+        let results = match context.max_session_duration() {
+            Some(limit) => timeout(limit, proxying).await.ok(),
+            None => Some(proxying.await),
+        };
+        match &results {
+            Some((Err(Error::IdleTimeout), _)) | Some((_, Err(Error::IdleTimeout))) => {
+                info!("Closed idle connection");
+                // The other direction was cut off before shutting down
+                let _ = client_proxier.writer.shutdown().await;
+                let _ = output_proxier.writer.shutdown().await;
+            }
+            Some((Err(Error::QuotaExceeded), _)) | Some((_, Err(Error::QuotaExceeded))) => {
+                info!("Closed connection of user over their quota");
+                // The other direction was cut off before shutting down
+                let _ = client_proxier.writer.shutdown().await;
+                let _ = output_proxier.writer.shutdown().await;
+            }
+            None => {
+                info!("Closed connection that lasted too long");
+                // Errors don't matter here, the session's over either way
+                let _ = client_proxier.writer.shutdown().await;
+                let _ = output_proxier.writer.shutdown().await;
+            }
+            Some((Err(e), _)) | Some((_, Err(e))) => warn!("Proxying failed: {}", e),
+            _ => (),
+        }
+        let proxy_stats = live_stats.snapshot();
+        match &session.user {
+            Some(user) => info!("Connection for user {} finished: {}", user, proxy_stats),
+            None => info!("Connection finished: {}", proxy_stats),
+        }
+        record_on_connection("bytes_in", proxy_stats.client_to_server);
+        record_on_connection("bytes_out", proxy_stats.server_to_client);
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = context.metrics();
+            metrics.record_client_to_server(proxy_stats.client_to_server);
+            metrics.record_server_to_client(proxy_stats.server_to_client);
+        }
+        if let Some(rule) = &session.rule {
+            stats.record_rule_session(
+                rule,
+                proxy_stats.client_to_server,
+                proxy_stats.server_to_client,
+            );
+        }
+        context
+            .event_listener()
+            .on_close(&session, &proxy_stats)
+            .await;
+        match results {
+            Some(_) => Ok(Self::Finished),
+            None => Err(Error::SessionTimeout),
+        }
+    }
+}
+
+// Converts Unicode domains to the ASCII form DNS uses. ASCII ones are left
+// as they are.
+#[cfg(feature = "idna")]
+fn to_ascii_domain(address: &mut Address) -> Result<(), idna::Errors> {
+    if let Address::Domain(domain) = address {
+        if !domain.is_ascii() {
+            let ascii = idna::domain_to_ascii(domain)?;
+            info!("Using {} for domain {}", ascii, domain);
+            *domain = ascii;
+        }
+    }
+    Ok(())
+}
+
+// Reads a message sent by the client while setting up the connection, giving
+// up if it takes too long to arrive
+async fn read_handshake<M: Parseable>(stream: &mut Stream, context: &Context) -> Result<M, Error> {
+    timeout(context.handshake_timeout(), read_message(stream))
+        .await
+        .map_err(|_| Error::HandshakeTimeout)?
+}
+
+// Formats a destination the way it'd be written as an address
+fn format_target(address: &Address, port: u16) -> String {
+    match address {
+        Address::Ip(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        Address::Ip(ip) => format!("{}:{}", ip, port),
+        Address::Domain(domain) => format!("{}:{}", domain, port),
+    }
+}
+
+tokio::task_local! {
+    // The span of the connection being served. Each phase runs in a span of
+    // its own, so what's known about the connection as a whole is recorded
+    // here instead.
+    static CONNECTION_SPAN: Span;
+}
+
+// Fills in a field on the connection's span, if there's one
+fn record_on_connection<V: tracing::Value>(field: &str, value: V) {
+    let _ = CONNECTION_SPAN.try_with(|span| {
+        span.record(field, &value);
+    });
+}
+
+// Fills in the destination the client asked for, on both the connection and
+// the request's own span
+fn record_target(address: &Address, port: u16) {
+    let target = format_target(address, port);
+    Span::current().record("target", &target.as_str());
+    record_on_connection("target", target.as_str());
 }
 
-struct Proxier {
+// Fills in the reply sent to the client
+fn record_reply(code: ResponseCode) {
+    record_on_connection("reply", debug(code));
+}
+
+enum ConnectOutcome {
+    // The stream's boxed to keep the other outcomes small
+    Connected(Box<Stream>, Session),
+    // The request can't be served and the client should be told why
+    Failed(ResponseCode),
+    // The client went away, there's no one to reply to
+    Abandoned,
+}
+
+// Connects to the requested destination, regardless of the protocol version
+// the client talks. The session's rule is filled in here. Whatever happens,
+// a single record of how the request went is logged.
+async fn connect_upstream(
+    client_stream: &mut Stream,
+    address: &Address,
+    port: u16,
+    session: Session,
+    context: &Context,
+) -> Result<ConnectOutcome, Error> {
+    let result = match context.route_override(address, port) {
+        Some((new_address, new_port)) => {
+            info!(
+                "Rewriting target {} to {}",
+                format_target(address, port),
+                format_target(new_address, *new_port)
+            );
+            try_connect_upstream(client_stream, new_address, *new_port, session, context).await
+        }
+        None => try_connect_upstream(client_stream, address, port, session, context).await,
+    };
+    let outcome = match &result {
+        Ok(ConnectOutcome::Connected(..)) => "connected".to_string(),
+        Ok(ConnectOutcome::Failed(code)) => format!("failed reply={:?}", code),
+        Ok(ConnectOutcome::Abandoned) => "abandoned".to_string(),
+        Err(e) => format!("error error=\"{}\"", e),
+    };
+    info!(
+        "request target={} outcome={}",
+        format_target(address, port),
+        outcome
+    );
+    result
+}
+
+// Checks a request's destination against the allowed ports, the destination
+// ACL, the user's quota and the event listener, returning the reply to send
+// if it's refused
+async fn check_request(
+    address: &Address,
+    port: u16,
+    user: Option<&str>,
+    context: &Context,
+) -> Option<ResponseCode> {
+    if !context.allows_port(port) {
+        warn!("Destination port {} not allowed", port);
+        return Some(ResponseCode::ConnectionNotAllowed);
+    }
+    match address {
+        Address::Domain(domain) => {
+            if let Some(pattern) = context.destination_acl().denied_domain(domain) {
+                warn!("Destination {:?} blocked by {}", (address, port), pattern);
+                return Some(ResponseCode::ConnectionNotAllowed);
+            }
+        }
+        Address::Ip(ip) => {
+            if !context.destination_acl().allows_ip(*ip) {
+                warn!("Destination {:?} not allowed", (address, port));
+                return Some(ResponseCode::ConnectionNotAllowed);
+            }
+        }
+    }
+    if let Some(user) = user.filter(|user| context.user_quota_exceeded(user)) {
+        warn!("User {} is over their quota", user);
+        return Some(ResponseCode::ConnectionNotAllowed);
+    }
+    if let Err(e) = context
+        .event_listener()
+        .on_request(address, port, user)
+        .await
+    {
+        warn!("Request to {:?} refused: {}", (address, port), e);
+        return Some(ResponseCode::ConnectionNotAllowed);
+    }
+    None
+}
+
+async fn try_connect_upstream(
+    client_stream: &mut Stream,
+    address: &Address,
+    port: u16,
+    mut session: Session,
+    context: &Context,
+) -> Result<ConnectOutcome, Error> {
+    if let Some(code) = check_request(address, port, session.user.as_deref(), context).await {
+        return Ok(ConnectOutcome::Failed(code));
+    }
+    if context.is_overloaded() {
+        warn!("Rejecting request, server is overloaded");
+        return Ok(ConnectOutcome::Failed(ResponseCode::GeneralFailure));
+    }
+    let slot = match context.acquire_connect_slot().await {
+        Some(slot) => slot,
+        None => {
+            warn!("Timed out waiting for a connect slot");
+            return Ok(ConnectOutcome::Failed(ResponseCode::GeneralFailure));
+        }
+    };
+    let rule = context.find_destination_rule(address, port);
+    let proxy_protocol = rule
+        .and_then(|rule| rule.proxy_protocol())
+        .or_else(|| context.proxy_protocol());
+    // Where the PROXY header says the connection goes to, when it's not
+    // the address the stream is connected to
+    let mut chained_destination = None;
+    let connect = match context.upstream_proxy() {
+        // The upstream proxy resolves domains itself. They're only resolved
+        // here when the policies or the PROXY header need their addresses.
+        Some(proxy) => {
+            let targets = match address {
+                Address::Ip(ip) => vec![SocketAddr::new(*ip, port)],
+                Address::Domain(domain)
+                    if context.destination_acl().has_cidr_rules() || proxy_protocol.is_some() =>
+                {
+                    match resolve(domain, port, context).await {
+                        Ok(addresses) => addresses,
+                        Err(e) => {
+                            warn!("{}", e);
+                            return Ok(ConnectOutcome::Failed(e.to_response_code()));
+                        }
+                    }
+                }
+                Address::Domain(_) => Vec::new(),
+            };
+            // The upstream proxy picks the address, so all of them have to
+            // be allowed
+            if targets
+                .iter()
+                .any(|target| !context.destination_acl().allows_ip(target.ip()))
+            {
+                warn!("Destination {:?} not allowed", (address, port));
+                return Ok(ConnectOutcome::Failed(ResponseCode::ConnectionNotAllowed));
+            }
+            if targets
+                .iter()
+                .any(|target| context.is_self_address(*target))
+            {
+                warn!(
+                    "Refusing to connect {:?} back to the proxy",
+                    (address, port)
+                );
+                return Ok(ConnectOutcome::Failed(ResponseCode::ConnectionNotAllowed));
+            }
+            chained_destination = targets.first().copied();
+            info!(
+                "Establishing connection with {:?} through {}",
+                (address, port),
+                proxy.address
+            );
+            Either::Left(connect_with_retries(context, slot, move || {
+                chain::connect(proxy, address, port, context.upstream_socket_options())
+            }))
+        }
+        None => {
+            let (addresses, domain) = match address {
+                Address::Ip(ip) => (vec![SocketAddr::new(*ip, port)], None),
+                Address::Domain(domain) => match resolve(domain, port, context).await {
+                    Ok(addresses) => (addresses, Some(domain.as_str())),
+                    Err(e) => {
+                        warn!("{}", e);
+                        return Ok(ConnectOutcome::Failed(e.to_response_code()));
+                    }
+                },
+            };
+            if addresses
+                .iter()
+                .any(|target| context.is_self_address(*target))
+            {
+                warn!(
+                    "Refusing to connect {:?} back to the proxy",
+                    (address, port)
+                );
+                return Ok(ConnectOutcome::Failed(ResponseCode::ConnectionNotAllowed));
+            }
+            info!("Establishing connection with {:?}", (address, port));
+            Either::Right(connect_with_retries(context, slot, move || {
+                upstream::connect(
+                    addresses.clone(),
+                    domain,
+                    context.upstream_socket_options(),
+                    context.last_good_addresses(),
+                    context.destination_acl(),
+                )
+                .map_err(Error::from)
+            }))
+        }
+    };
+    let mut output_stream = match connect_unless_abandoned(client_stream, connect).await {
+        Some(Ok(stream)) => stream,
+        Some(Err(e)) => {
+            warn!("Failed to connect to {:?}: {}", (address, port), e);
+            return Ok(ConnectOutcome::Failed(e.to_response_code()));
+        }
+        None => {
+            let abandoned = context.record_abandoned_connect();
+            info!(
+                "Client abandoned request while connecting ({} abandoned connects so far)",
+                abandoned
+            );
+            return Ok(ConnectOutcome::Abandoned);
+        }
+    };
+    context.configure_tcp_stream(&output_stream)?;
+    if let Some(grace) = context.upstream_liveness_check() {
+        if !upstream_alive(&output_stream, grace).await {
+            warn!("Upstream closed the connection right after connecting");
+            return Ok(ConnectOutcome::Failed(ResponseCode::GeneralFailure));
+        }
+    }
+    if let Some(version) = proxy_protocol {
+        // It has to go before anything else, TLS handshakes included
+        let destination = match chained_destination {
+            Some(destination) => destination,
+            None => output_stream.peer_addr()?,
+        };
+        let header = version.header(client_stream.peer_addr(), destination);
+        if let Err(e) = output_stream.write_all(&header).await {
+            warn!("Failed to send PROXY protocol header: {}", e);
+            return Ok(ConnectOutcome::Failed(ResponseCode::GeneralFailure));
+        }
+    }
+    let output_stream = match upstream_stream(output_stream, address, rule).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Failed to set up upstream stream: {:?}", e);
+            return Ok(ConnectOutcome::Failed(ResponseCode::GeneralFailure));
+        }
+    };
+    session.rule = rule.map(|rule| rule.name().into());
+    Ok(ConnectOutcome::Connected(Box::new(output_stream), session))
+}
+
+// Retries the connect as many times as the context allows as long as it
+// fails for reasons that may go away on their own. Only the attempt that
+// succeeds counts towards the connect latency, the backoff doesn't. The
+// connect slot is held while connecting, but not while backing off.
+async fn connect_with_retries<'a, F, Fut, T>(
+    context: &'a Context,
+    mut slot: ConnectSlot<'a>,
+    mut connect: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut retries_left = context.connect_retries();
+    let mut backoff = context.connect_retry_backoff();
+    loop {
+        let attempt_start = Instant::now();
+        match connect().await {
+            Ok(stream) => {
+                context
+                    .stats()
+                    .record_connect_latency(attempt_start.elapsed());
+                return Ok(stream);
+            }
+            Err(e) if retries_left > 0 && e.is_transient() => {
+                debug!("Connect failed: {}, retrying in {:?}", e, backoff);
+                drop(slot);
+                sleep(backoff).await;
+                slot = match context.acquire_connect_slot().await {
+                    Some(slot) => slot,
+                    None => {
+                        warn!("Timed out waiting for a connect slot to retry");
+                        return Err(e);
+                    }
+                };
+                retries_left -= 1;
+                backoff = backoff.saturating_mul(2);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Resolves a domain using the context's resolver. There's always at least one
+// address if it succeeds.
+async fn resolve(domain: &str, port: u16, context: &Context) -> Result<Vec<SocketAddr>, Error> {
+    let addresses = context.resolver().resolve(domain).await?;
+    if addresses.is_empty() {
+        return Err(Error::DnsError(format!(
+            "No addresses found for {}",
+            domain
+        )));
+    }
+    Ok(addresses
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+// Strips the header off a client's datagram and sends the payload to the
+// destination it names, returning how many bytes of payload were relayed.
+// Datagrams go through the same destination policies as CONNECT requests, the
+// ones that don't pass them are dropped.
+async fn relay_from_client(
+    socket: &UdpSocket,
+    datagram: &[u8],
+    context: &Context,
+) -> Result<usize, Error> {
+    let mut payload = datagram;
+    let header = UdpHeader::new(&mut payload).await?;
+    if !context.accept_udp_fragment(header.fragment) {
+        return Ok(0);
+    }
+    if !context.allows_port(header.port) {
+        debug!("Dropping UDP datagram to disallowed port {}", header.port);
+        return Ok(0);
+    }
+    let destination = match &header.address {
+        Address::Ip(ip) => SocketAddr::new(*ip, header.port),
+        Address::Domain(domain) => {
+            if let Some(pattern) = context.destination_acl().denied_domain(domain) {
+                debug!("Dropping UDP datagram to {} blocked by {}", domain, pattern);
+                return Ok(0);
+            }
+            resolve(domain, header.port, context).await?[0]
+        }
+    };
+    if !context.destination_acl().allows_ip(destination.ip()) {
+        debug!("Dropping UDP datagram to disallowed {}", destination);
+        return Ok(0);
+    }
+    if context.is_self_address(destination) {
+        debug!("Dropping UDP datagram sent back to the proxy");
+        return Ok(0);
+    }
+    socket.send_to(payload, destination).await?;
+    context
+        .stats()
+        .bytes_in()
+        .fetch_add(payload.len() as u64, Ordering::Relaxed);
+    Ok(payload.len())
+}
+
+// Wraps a datagram coming from a destination and sends it to the client,
+// returning how many bytes of it were relayed
+async fn relay_to_client(
+    socket: &UdpSocket,
+    datagram: &[u8],
+    source: SocketAddr,
+    client_address: SocketAddr,
+    context: &Context,
+) -> Result<usize, Error> {
+    let header = UdpHeader {
+        fragment: 0,
+        address: Address::Ip(source.ip()),
+        port: source.port(),
+    };
+    let mut output = Vec::with_capacity(datagram.len() + 22);
+    header.write(&mut output).await?;
+    output.extend_from_slice(datagram);
+    socket.send_to(&output, client_address).await?;
+    context
+        .stats()
+        .bytes_out()
+        .fetch_add(datagram.len() as u64, Ordering::Relaxed);
+    Ok(datagram.len())
+}
+
+// Runs the upstream connect unless the client closes its connection first, in
+// which case the connect is dropped and None is returned. Dropping it closes
+// the upstream socket if it was already established.
+async fn connect_unless_abandoned<F, T>(client_stream: &mut Stream, connect: F) -> Option<T>
+where
+    F: Future<Output = T>,
+{
+    tokio::select! {
+        _ = client_stream.closed() => None,
+        result = connect => Some(result),
+    }
+}
+
+// Waits up to `grace` to make sure the upstream doesn't close the connection
+// right after accepting it. Nothing is consumed from the stream.
+async fn upstream_alive(stream: &TcpStream, grace: Duration) -> bool {
+    let mut buffer = [0; 1];
+    match timeout(grace, stream.peek(&mut buffer)).await {
+        // Nothing happened in the meantime
+        Err(_) => true,
+        Ok(Ok(bytes_peeked)) => bytes_peeked > 0,
+        Ok(Err(_)) => false,
+    }
+}
+
+// Sets up the stream used to talk to the destination, originating TLS if the
+// matching rule asks for it. The rule's server name, or otherwise the requested
+// domain, is used as SNI so IP destinations without one are refused rather
+// than silently sent in plaintext.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn upstream_stream(
+    stream: TcpStream,
+    address: &Address,
+    rule: Option<&DestinationRule>,
+) -> Result<Stream, Error> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = rule.and_then(|r| r.tls()) {
+        let server_name = match (tls.server_name(), address) {
+            (Some(name), _) => name,
+            (None, Address::Domain(domain)) => domain.as_str(),
+            (None, _) => {
+                return Err(Error::Generic(format!(
+                    "TLS rule matched {:?} but has no server_name to use",
+                    address
+                )))
+            }
+        };
+        info!("Originating TLS towards {:?} as {}", address, server_name);
+        return tls.connect(server_name, stream).await;
+    }
+    Ok(Stream::unbuffered(stream))
+}
+
+// Feeds the outcome of a login into the stats, lockouts, metrics and audit log
+fn record_auth_result(stream: &Stream, context: &Context, username: &str, status: AuthStatusCode) {
+    if let AuthStatusCode::Failure = status {
+        context.stats().record_auth_failure();
+    }
+    if let Some(address) = stream.peer_addr() {
+        context.record_authentication(address.ip(), status == AuthStatusCode::Success);
+    }
+    #[cfg(feature = "metrics")]
+    context
+        .metrics()
+        .record_authentication(matches!(status, AuthStatusCode::Success));
+    audit_authentication(stream, username, status);
+}
+
+fn audit_authentication(stream: &Stream, username: &str, status: AuthStatusCode) {
+    let result = match status {
+        AuthStatusCode::Success => "success",
+        AuthStatusCode::Failure => "failure",
+    };
+    let source_ip = match stream.peer_addr() {
+        Some(address) => address.ip().to_string(),
+        None => "unknown".into(),
+    };
+    info!(
+        target: AUDIT_LOG_TARGET,
+        "auth result={} username={:?} source_ip={}", result, username, source_ip
+    );
+}
+
+// Errors in one direction that end the whole session rather than just that
+// direction
+fn ends_session(error: &Error) -> bool {
+    matches!(error, Error::IdleTimeout | Error::QuotaExceeded)
+}
+
+// When either direction of a session last moved data, so a direction that's
+// quiet isn't considered idle while the other one is busy
+struct Activity {
+    start: Instant,
+    // Since `start`
+    last_millis: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last_millis: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last_millis.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_millis.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+struct Proxier<'a> {
     reader: ReadHalf<Stream>,
     writer: WriteHalf<Stream>,
+    // Only needed if the writer buffers what's written to it
+    flush_writes: bool,
+    // Totals across every session, and this session's own
+    transferred: &'a AtomicU64,
+    session_transferred: Arc<AtomicU64>,
+    // The user whose quota the traffic counts towards
+    user: Option<&'a str>,
+    context: &'a Context,
+    idle_timeout: Duration,
+    // Shared with the session's other direction, if there's one
+    activity: Option<&'a Activity>,
+    limiter: Option<BandwidthLimiter>,
+    buffer_size: usize,
 }
 
-impl Proxier {
-    fn new(reader: ReadHalf<Stream>, writer: WriteHalf<Stream>) -> Self {
-        Proxier { reader, writer }
+impl<'a> Proxier<'a> {
+    fn new(
+        reader: ReadHalf<Stream>,
+        writer: WriteHalf<Stream>,
+        flush_writes: bool,
+        transferred: &'a AtomicU64,
+        session_transferred: Arc<AtomicU64>,
+        user: Option<&'a str>,
+        context: &'a Context,
+    ) -> Self {
+        Proxier {
+            reader,
+            writer,
+            flush_writes,
+            transferred,
+            session_transferred,
+            user,
+            context,
+            idle_timeout: context.idle_timeout(),
+            activity: None,
+            limiter: context.bandwidth_limit().map(BandwidthLimiter::new),
+            buffer_size: context.buffer_size(),
+        }
+    }
+
+    // Only times out once neither direction has moved data for a while
+    fn sharing_activity(mut self, activity: &'a Activity) -> Self {
+        self.activity = Some(activity);
+        self
     }
 
+    // Copies data until the reader reaches EOF, then shuts down the writer so
+    // the other end sees it as well
     async fn run(&mut self) -> Result<(), Error> {
-        let mut buffer = [0; 4096];
+        let result = match self.copy().await {
+            // Peers going away without closing cleanly is nothing unusual
+            Err(Error::Io(e)) if is_peer_close(&e) => {
+                debug!("Peer closed the connection: {}", e);
+                Ok(())
+            }
+            result => result,
+        };
+        // Errors don't matter here, we're done with this direction either way
+        let _ = self.writer.shutdown().await;
+        result
+    }
+
+    async fn copy(&mut self) -> Result<(), Error> {
+        let mut buffer = vec![0; self.buffer_size];
         loop {
-            let bytes_read = self.reader.read(&mut buffer).await?;
+            let idle_for = self.activity.map_or(Duration::ZERO, Activity::idle_for);
+            let wait = self.idle_timeout.saturating_sub(idle_for);
+            let bytes_read = match timeout(wait, self.reader.read(&mut buffer)).await {
+                Ok(result) => result?,
+                // The other direction moved data in the meantime
+                Err(_)
+                    if self
+                        .activity
+                        .is_some_and(|a| a.idle_for() < self.idle_timeout) =>
+                {
+                    continue
+                }
+                Err(_) => return Err(Error::IdleTimeout),
+            };
             if bytes_read == 0 {
-                return Err(Error::Finished);
+                return Ok(());
+            }
+            if let Some(activity) = self.activity {
+                activity.touch();
             }
             self.writer.write_all(&buffer[0..bytes_read]).await?;
-            self.writer.flush().await?;
+            if self.flush_writes {
+                self.writer.flush().await?;
+            }
+            self.transferred
+                .fetch_add(bytes_read as u64, Ordering::Relaxed);
+            self.session_transferred
+                .fetch_add(bytes_read as u64, Ordering::Relaxed);
+            if let Some(user) = self.user {
+                self.context.record_user_traffic(user, bytes_read as u64);
+                if self.context.user_quota_exceeded(user) {
+                    return Err(Error::QuotaExceeded);
+                }
+            }
+            if let Some(limiter) = &mut self.limiter {
+                sleep(limiter.consume(bytes_read)).await;
+            }
+        }
+    }
+}
+
+fn is_peer_close(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::{DestinationAcl, PortSet};
+    use crate::chain::UpstreamProxy;
+    use crate::context::Credentials;
+    use crate::events::EventListener;
+    use crate::proxy_protocol::ProxyProtocol;
+    use crate::resolver::Resolver;
+    use crate::stats::RuleStats;
+    use crate::testing::{capture_logs, logged_messages, tcp_pair};
+    use std::net::Ipv6Addr;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn failed_auth_is_audited() {
+        capture_logs();
+        let context = Context::with_credentials(Credentials::new("foo", "bar"));
+        let (mut client, server) = tcp_pair().await;
+        let source_ip = client.local_addr().unwrap().ip();
+        client
+            .write_all(&[1, 7, 109, 97, 108, 108, 111, 114, 121, 3, 98, 97, 122])
+            .await
+            .unwrap();
+        let state = State::AwaitingAuth(Stream::buffered(server));
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, [1, 1]);
+        let expected = format!(
+            "auth result=failure username=\"mallory\" source_ip={}",
+            source_ip
+        );
+        assert!(logged_messages(AUDIT_LOG_TARGET).contains(&expected));
+    }
+
+    #[tokio::test]
+    async fn locked_out_client_refused_before_verifying() {
+        let mut context = Context::with_credentials(Credentials::new("foo", "bar"));
+        context.set_auth_lockout(1, Duration::from_secs(60));
+        let (mut client, server) = tcp_pair().await;
+        context.record_authentication(client.local_addr().unwrap().ip(), false);
+        client
+            .write_all(&[1, 3, 102, 111, 111, 3, 98, 97, 114])
+            .await
+            .unwrap();
+        let state = State::AwaitingAuth(Stream::buffered(server));
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, [1, 1]);
+    }
+
+    #[tokio::test]
+    async fn unsupported_auth_version_replied() {
+        let mut context = Context::with_credentials(Credentials::new("foo", "bar"));
+        context.set_auth_lockout(1, Duration::from_secs(60));
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[2, 3, 102, 111, 111, 3, 98, 97, 114])
+            .await
+            .unwrap();
+        let state = State::AwaitingAuth(Stream::buffered(server));
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, [1, 1]);
+        // Malformed logins count as failed ones
+        assert_eq!(context.stats().snapshot().auth_failures, 1);
+        assert!(context.is_locked_out(client.local_addr().unwrap().ip()));
+    }
+
+    #[tokio::test]
+    async fn handshake_over_memory_pipe() {
+        let mut context = Context::with_credentials(Credentials::new("foo", "bar"));
+        context.set_allowed_ports(PortSet::default());
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut state = State::new(Stream::from_io(server));
+        let exchange = async {
+            let mut response = [0; 2];
+            client.write_all(&[5, 1, 2]).await.unwrap();
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, [5, 2]);
+            client
+                .write_all(&[1, 3, 102, 111, 111, 3, 98, 97, 114])
+                .await
+                .unwrap();
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, [1, 0]);
+            client
+                .write_all(&[5, 1, 0, 1, 10, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+        };
+        let proxy = async {
+            while !state.is_finished() {
+                state = state.process(&context).await.unwrap();
+            }
+        };
+        futures::join!(exchange, proxy);
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_skipped_in_negotiation() {
+        let context = Context::with_credentials(Credentials::new("foo", "bar"));
+        let (mut client, server) = tcp_pair().await;
+        // GSSAPI isn't supported, username/password is
+        client.write_all(&[5, 2, 1, 2]).await.unwrap();
+        let state = State::AwaitingHello(Stream::buffered(server));
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::AwaitingAuth(_)));
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [5, 2]);
+    }
+
+    #[tokio::test]
+    async fn only_unknown_methods_offered() {
+        let context = Context::default();
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&[5, 1, 1]).await.unwrap();
+        let state = State::AwaitingHello(Stream::buffered(server));
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [5, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn slow_handshake_times_out() {
+        let mut context = Context::default();
+        context.set_handshake_timeout(Duration::from_millis(50));
+        // Nothing at all, then a partial hello
+        for sent in [&[][..], &[5, 2, 0][..]] {
+            let (mut client, server) = tcp_pair().await;
+            client.write_all(sent).await.unwrap();
+            let state = State::new(Stream::buffered(server));
+            let result = timeout(Duration::from_secs(1), state.process(&context))
+                .await
+                .unwrap();
+            assert!(matches!(result, Err(Error::HandshakeTimeout)));
+        }
+    }
+
+    #[tokio::test]
+    async fn log_rejected_methods() {
+        capture_logs();
+        let mut context = Context::with_credentials(Credentials::new("foo", "bar"));
+        context.set_log_rejected_methods(true);
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&[5, 2, 0, 0x80]).await.unwrap();
+        let state = State::AwaitingHello(Stream::buffered(server));
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let expected =
+            "No acceptable authentication method, client offered: NoAuthentication, 0x80"
+                .to_string();
+        assert!(logged_messages("rusty_socks::states").contains(&expected));
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [5, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn disallowed_socks_versions_rejected() {
+        let mut context = Context::default();
+        context.set_allowed_socks_versions(vec![5]);
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[4, 1, 0, 80, 127, 0, 0, 1, 0])
+            .await
+            .unwrap();
+        let state = State::AwaitingHello(Stream::buffered(server));
+        assert!(state.process(&context).await.unwrap().is_finished());
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, [0, 0x5b, 0, 0, 0, 0, 0, 0]);
+
+        context.set_allowed_socks_versions(vec![4]);
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let state = State::AwaitingHello(Stream::buffered(server));
+        assert!(state.process(&context).await.unwrap().is_finished());
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn non_socks_rejected_quietly() {
+        capture_logs();
+        let mut context = Context::default();
+        context.set_reject_non_socks_quietly(true);
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let state = State::AwaitingHello(Stream::buffered(server));
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let expected = "Closing non-SOCKS connection starting with 0x47".to_string();
+        assert!(logged_messages("rusty_socks::states").contains(&expected));
+
+        // Without the setting, it's still an error
+        context.set_reject_non_socks_quietly(false);
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let state = State::AwaitingHello(Stream::buffered(server));
+        let result = state.process(&context).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn upstream_closing_right_away_fails_request() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = backend.accept().await.unwrap();
+            drop(stream);
+        });
+        let mut context = Context::default();
+        context.set_upstream_liveness_check(Duration::from_millis(500));
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&backend_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::GeneralFailure as u8);
+    }
+
+    #[tokio::test]
+    async fn abandoned_connect_closes_upstream() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let (client, server) = tcp_pair().await;
+        let mut client_stream = Stream::buffered(server);
+        // Simulate a connect that takes a while to complete on our side
+        let connect = async move {
+            let stream = TcpStream::connect(backend_addr).await?;
+            sleep(Duration::from_secs(5)).await;
+            std::io::Result::Ok(stream)
+        };
+        let abandon = async move {
+            sleep(Duration::from_millis(50)).await;
+            drop(client);
+        };
+        let (result, _) = futures::join!(
+            connect_unless_abandoned(&mut client_stream, connect),
+            abandon
+        );
+        assert!(result.is_none());
+
+        let (mut upstream, _) = backend.accept().await.unwrap();
+        let mut buffer = [0; 16];
+        let bytes_read = timeout(Duration::from_secs(1), upstream.read(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bytes_read, 0);
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn originate_tls_to_matching_destination() {
+        use crate::tls::UpstreamTls;
+        use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+        use rustls::{NoClientAuth, ServerConfig};
+        use std::sync::Arc;
+        use tokio_rustls::TlsAcceptor;
+
+        let mut cert_pem: &[u8] = include_bytes!("../testdata/localhost.pem");
+        let mut key_pem: &[u8] = include_bytes!("../testdata/localhost-key.pem");
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config
+            .set_single_cert(
+                certs(&mut cert_pem).unwrap(),
+                pkcs8_private_keys(&mut key_pem).unwrap().remove(0),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_port = backend.local_addr().unwrap().port();
+        let backend_task = tokio::spawn(async move {
+            let (stream, _) = backend.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let server_name = stream.get_ref().1.get_sni_hostname().map(String::from);
+            let mut buffer = [0; 4];
+            stream.read_exact(&mut buffer).await.unwrap();
+            (server_name, buffer)
+        });
+
+        let mut rule = DestinationRule::new("localhost", Some(backend_port));
+        let ca_file = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/ca.pem");
+        rule.set_tls(UpstreamTls::with_ca_file(ca_file).unwrap());
+        let mut context = Context::default();
+        context.add_destination_rule(rule);
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 3, 9];
+        request.extend_from_slice(b"localhost");
+        request.extend_from_slice(&backend_port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::Proxying(..)));
+        tokio::spawn(async move { state.process(&Context::default()).await });
+
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::Success as u8);
+        client.write_all(b"ping").await.unwrap();
+        let (server_name, data) = backend_task.await.unwrap();
+        assert_eq!(server_name.as_deref(), Some("localhost"));
+        assert_eq!(&data, b"ping");
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn tls_rule_without_server_name_refuses_ips() {
+        use crate::tls::UpstreamTls;
+
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let mut rule = DestinationRule::new("127.0.0.1", Some(backend_addr.port()));
+        rule.set_tls(UpstreamTls::new());
+        let mut context = Context::default();
+        context.add_destination_rule(rule);
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&backend_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::GeneralFailure as u8);
+    }
+
+    #[tokio::test]
+    async fn traffic_attributed_to_rule() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buffer = [0; 4];
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(&buffer).await.unwrap();
+        });
+        let mut rule = DestinationRule::new("127.0.0.1", Some(backend_addr.port()));
+        rule.set_name("echo");
+        let mut context = Context::default();
+        context.add_destination_rule(rule);
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&backend_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let proxy = async {
+            while !state.is_finished() {
+                state = state.process(&context).await.unwrap();
+            }
+        };
+        let exchange = async {
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut echoed = [0; 4];
+            client.read_exact(&mut echoed).await.unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+        };
+        futures::join!(proxy, exchange);
+
+        let rules = context.stats().snapshot().rules;
+        let expected = RuleStats {
+            sessions: 1,
+            bytes_in: 4,
+            bytes_out: 4,
+        };
+        assert_eq!(rules.get("echo"), Some(&expected));
+    }
+
+    #[tokio::test]
+    async fn user_quota_enforced() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut buffer = [0; 4];
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(&buffer).await.unwrap();
+        });
+        let mut context = Context::default();
+        context.set_user_quota(8, Duration::from_secs(3600));
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&backend_addr.port().to_be_bytes());
+
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&request).await.unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::UsernamePassword,
+            Some("foo".into()),
+        );
+        let proxy = async {
+            while !state.is_finished() {
+                state = state.process(&context).await.unwrap();
+            }
+        };
+        let exchange = async {
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut echoed = [0; 4];
+            client.read_exact(&mut echoed).await.unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+        };
+        futures::join!(proxy, exchange);
+        assert!(context.user_quota_exceeded("foo"));
+        assert!(!context.user_quota_exceeded("bar"));
+
+        // The user's out of quota, so their next request is refused
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::UsernamePassword,
+            Some("foo".into()),
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+    }
+
+    #[tokio::test]
+    async fn session_cut_off_once_over_quota() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            while stream.write_all(&[0; 1024]).await.is_ok() {}
+        });
+        let mut context = Context::default();
+        context.set_user_quota(4096, Duration::from_secs(3600));
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&backend_addr.port().to_be_bytes());
+
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&request).await.unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::UsernamePassword,
+            Some("foo".into()),
+        );
+        let proxy = async {
+            while !state.is_finished() {
+                state = state.process(&context).await.unwrap();
+            }
+        };
+        // The client keeps its side open, it's the proxy that has to end it
+        let exchange = async {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received.len()
+        };
+        let (_, received) = timeout(Duration::from_secs(5), async {
+            futures::join!(proxy, exchange)
+        })
+        .await
+        .unwrap();
+        assert!(received < 4096 + 10 + 1024);
+        assert!(context.user_quota_exceeded("foo"));
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_header_sent_first() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let backend_task = tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut context = Context::default();
+        context.set_proxy_protocol(ProxyProtocol::V1);
+
+        let (mut client, server) = tcp_pair().await;
+        let client_addr = client.local_addr().unwrap();
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&backend_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let proxy = async {
+            while !state.is_finished() {
+                state = state.process(&context).await.unwrap();
+            }
+        };
+        let exchange = async {
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+        };
+        futures::join!(proxy, exchange);
+
+        let received = backend_task.await.unwrap();
+        let expected = format!(
+            "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nping",
+            client_addr.port(),
+            backend_addr.port()
+        );
+        assert_eq!(String::from_utf8(received).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn socks4_connect() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let context = Context::default();
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![4, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&[127, 0, 0, 1, 102, 111, 111, 0]);
+        client.write_all(&request).await.unwrap();
+
+        let state = State::new(Stream::buffered(server));
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::Proxying(..)));
+        let mut response = [0; 8];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0, 0x5a, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn socks4_rejected_when_authentication_required() {
+        let context = Context::with_credentials(Credentials::new("foo", "bar"));
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[4, 1, 0, 80, 0, 0, 0, 1, 0, 102, 111, 111, 0])
+            .await
+            .unwrap();
+
+        let state = State::new(Stream::buffered(server));
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 8];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], 0x5b);
+    }
+
+    #[tokio::test]
+    async fn bind_accepts_connection() {
+        let context = Context::default();
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::AwaitingBindConnection(..)));
+
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[..8], [5, 0, 0, 1, 127, 0, 0, 1]);
+        let port = u16::from_be_bytes([response[8], response[9]]);
+        let peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let peer_port = peer.local_addr().unwrap().port();
+
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::Proxying(..)));
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[..8], [5, 0, 0, 1, 127, 0, 0, 1]);
+        assert_eq!(u16::from_be_bytes([response[8], response[9]]), peer_port);
+    }
+
+    #[tokio::test]
+    async fn bind_refused_by_destination_policies() {
+        let mut context = Context::default();
+        let mut ports = PortSet::default();
+        ports.add(80..=80);
+        context.set_allowed_ports(ports);
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 21])
+            .await
+            .unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+    }
+
+    #[tokio::test]
+    async fn bind_peer_checked_against_resolved_domain() {
+        let mut context = Context::default();
+        context.set_resolver(Box::new(LoopbackResolver));
+        for (domain, expected) in [("peer.test", 0), ("ipv6.test", 2)] {
+            let (mut client, server) = tcp_pair().await;
+            let request = [&[5, 2, 0, 3, 9][..], domain.as_bytes(), &[0, 0]].concat();
+            client.write_all(&request).await.unwrap();
+            let state = State::AwaitingClientRequest(
+                Stream::buffered(server),
+                AuthenticationMethod::NoAuthentication,
+                None,
+            );
+            let state = state.process(&context).await.unwrap();
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response[1], ResponseCode::Success as u8);
+            let port = u16::from_be_bytes([response[8], response[9]]);
+
+            // The peer always connects from the loopback's IPv4 address
+            let _peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            state.process(&context).await.unwrap();
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response[1], expected, "{}", domain);
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_times_out() {
+        let mut context = Context::default();
+        context.set_bind_timeout(Duration::from_millis(10));
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        while !state.is_finished() {
+            state = state.process(&context).await.unwrap();
         }
+        let mut response = [0; 20];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::Success as u8);
+        assert_eq!(response[11], ResponseCode::TtlExpired as u8);
+    }
+
+    #[tokio::test]
+    async fn udp_associate_relays_datagrams() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_address = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0; 64];
+            let (size, source) = echo.recv_from(&mut buffer).await.unwrap();
+            echo.send_to(&buffer[..size], source).await.unwrap();
+        });
+        let context = Context::default();
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let proxy = async { state.process(&context).await.unwrap() };
+        let exchange = async {
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            let port = u16::from_be_bytes([response[8], response[9]]);
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut datagram = vec![0, 0, 0, 1, 127, 0, 0, 1];
+            datagram.extend_from_slice(&echo_address.port().to_be_bytes());
+            datagram.extend_from_slice(b"ping");
+            socket
+                .send_to(&datagram, ("127.0.0.1", port))
+                .await
+                .unwrap();
+
+            let mut buffer = [0; 64];
+            let size = socket.recv(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..size], datagram.as_slice());
+            drop(client);
+        };
+        let (state, _) = futures::join!(proxy, exchange);
+        assert!(state.is_finished());
+    }
+
+    #[tokio::test]
+    async fn fragmented_datagrams_not_relayed() {
+        let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let backend_address = backend.local_addr().unwrap();
+        let context = Context::default();
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let proxy = async { state.process(&context).await.unwrap() };
+        let exchange = async {
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            let port = u16::from_be_bytes([response[8], response[9]]);
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            for (fragment, payload) in [(1, b"frag"), (0, b"ping")] {
+                let mut datagram = vec![0, 0, fragment, 1, 127, 0, 0, 1];
+                datagram.extend_from_slice(&backend_address.port().to_be_bytes());
+                datagram.extend_from_slice(payload);
+                socket
+                    .send_to(&datagram, ("127.0.0.1", port))
+                    .await
+                    .unwrap();
+            }
+
+            // Only the unfragmented one makes it through
+            let mut buffer = [0; 64];
+            let size = timeout(Duration::from_secs(1), backend.recv(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buffer[..size], b"ping");
+            drop(client);
+        };
+        let (state, _) = futures::join!(proxy, exchange);
+        assert!(state.is_finished());
+    }
+
+    #[tokio::test]
+    async fn udp_association_ended_once_over_quota() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_address = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0; 64];
+            let (size, source) = echo.recv_from(&mut buffer).await.unwrap();
+            echo.send_to(&buffer[..size], source).await.unwrap();
+        });
+        let mut context = Context::default();
+        context.set_user_quota(8, Duration::from_secs(3600));
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::UsernamePassword,
+            Some("foo".into()),
+        );
+        let proxy = async { state.process(&context).await.unwrap() };
+        let exchange = async {
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            let port = u16::from_be_bytes([response[8], response[9]]);
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut datagram = vec![0, 0, 0, 1, 127, 0, 0, 1];
+            datagram.extend_from_slice(&echo_address.port().to_be_bytes());
+            datagram.extend_from_slice(b"ping");
+            socket
+                .send_to(&datagram, ("127.0.0.1", port))
+                .await
+                .unwrap();
+            let mut buffer = [0; 64];
+            socket.recv(&mut buffer).await.unwrap();
+            // The control connection stays open
+            client
+        };
+        let (state, _client) = timeout(Duration::from_secs(5), async {
+            futures::join!(proxy, exchange)
+        })
+        .await
+        .unwrap();
+        assert!(state.is_finished());
+        assert!(context.user_quota_exceeded("foo"));
+    }
+
+    #[tokio::test]
+    async fn udp_datagrams_to_denied_destinations_dropped() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_address = target.local_addr().unwrap();
+        let mut context = Context::default();
+        let mut acl = DestinationAcl::default();
+        acl.deny_cidr("127.0.0.0/8".parse().unwrap());
+        context.set_destination_acl(acl);
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let proxy = async { state.process(&context).await.unwrap() };
+        let exchange = async {
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            let port = u16::from_be_bytes([response[8], response[9]]);
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut datagram = vec![0, 0, 0, 1, 127, 0, 0, 1];
+            datagram.extend_from_slice(&target_address.port().to_be_bytes());
+            datagram.extend_from_slice(b"ping");
+            socket
+                .send_to(&datagram, ("127.0.0.1", port))
+                .await
+                .unwrap();
+
+            let mut buffer = [0; 64];
+            let received = timeout(Duration::from_millis(200), target.recv(&mut buffer)).await;
+            assert!(received.is_err());
+            drop(client);
+        };
+        let (state, _) = futures::join!(proxy, exchange);
+        assert!(state.is_finished());
+    }
+
+    #[tokio::test]
+    async fn refused_connect_is_replied() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        drop(backend);
+        let context = Context::default();
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::ConnectionRefused as u8);
+    }
+
+    #[tokio::test]
+    async fn denied_destination_is_replied() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let mut acl = DestinationAcl::default();
+        acl.deny_cidr("127.0.0.0/8".parse().unwrap());
+        acl.deny_domain(".internal".parse().unwrap());
+        let mut context = Context::default();
+        context.set_destination_acl(acl);
+
+        let requests = vec![
+            // The address itself is denied
+            [&[5, 1, 0, 1, 127, 0, 0, 1][..], &port.to_be_bytes()].concat(),
+            // The domain is denied before resolving it
+            [&[5, 1, 0, 3, 11][..], b"db.internal", &port.to_be_bytes()].concat(),
+            // The domain resolves to a denied address
+            [&[5, 1, 0, 3, 9][..], b"localhost", &port.to_be_bytes()].concat(),
+        ];
+        for request in requests {
+            let (mut client, server) = tcp_pair().await;
+            client.write_all(&request).await.unwrap();
+            let state = State::AwaitingClientRequest(
+                Stream::buffered(server),
+                AuthenticationMethod::NoAuthentication,
+                None,
+            );
+            let state = state.process(&context).await.unwrap();
+            assert!(state.is_finished());
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_domain_pattern_is_logged() {
+        capture_logs();
+        let mut acl = DestinationAcl::default();
+        acl.deny_domain("*.doubleclick.net".parse().unwrap());
+        let mut context = Context::default();
+        context.set_destination_acl(acl);
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[&[5, 1, 0, 3, 18][..], b"ad.doubleclick.net", &[0, 80]].concat())
+            .await
+            .unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        assert!(state.process(&context).await.unwrap().is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+        let expected =
+            "Destination (Domain(\"ad.doubleclick.net\"), 80) blocked by *.doubleclick.net";
+        assert!(logged_messages("rusty_socks::states").contains(&expected.to_string()));
+    }
+
+    #[tokio::test]
+    async fn disallowed_port_is_replied() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let mut ports = PortSet::default();
+        ports.add(port + 1..=port + 1);
+        let mut context = Context::default();
+        context.set_allowed_ports(ports);
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+    }
+
+    // Resolves every domain to the loopback address, except "unknown.test"
+    struct LoopbackResolver;
+
+    #[async_trait::async_trait]
+    impl Resolver for LoopbackResolver {
+        async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+            match host {
+                "unknown.test" => Err(Error::DnsError("no such domain".into())),
+                // Only has AAAA records
+                "ipv6.test" => Ok(vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]),
+                _ => Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_resolver_used() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let mut context = Context::default();
+        context.set_resolver(Box::new(LoopbackResolver));
+
+        let (mut client, server) = tcp_pair().await;
+        let request = [&[5, 1, 0, 3, 12][..], b"service.test", &port.to_be_bytes()].concat();
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::Proxying(..)));
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::Success as u8);
+    }
+
+    #[tokio::test]
+    async fn ipv6_only_domain_connected_over_ipv6() {
+        let backend = TcpListener::bind("[::1]:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let mut context = Context::default();
+        context.set_resolver(Box::new(LoopbackResolver));
+
+        let (mut client, server) = tcp_pair().await;
+        let request = [&[5, 1, 0, 3, 9][..], b"ipv6.test", &port.to_be_bytes()].concat();
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::Proxying(..)));
+        let (_, peer) = backend.accept().await.unwrap();
+        assert_eq!(peer.ip(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+
+        // The reply carries the IPv6 address the proxy connected from
+        let mut response = [0; 22];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[..4], [5, 0, 0, 4]);
+        assert_eq!(response[4..20], Ipv6Addr::LOCALHOST.octets());
+    }
+
+    #[tokio::test]
+    async fn chained_domains_checked_against_networks() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut acl = DestinationAcl::default();
+        acl.deny_cidr("127.0.0.0/8".parse().unwrap());
+        let mut context = Context::default();
+        context.set_destination_acl(acl);
+        context.set_resolver(Box::new(LoopbackResolver));
+        context.set_upstream_proxy(UpstreamProxy {
+            address: upstream.local_addr().unwrap(),
+            credentials: None,
+        });
+
+        let (mut client, server) = tcp_pair().await;
+        let request = [&[5, 1, 0, 3, 12][..], b"service.test", &[0, 80]].concat();
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+    }
+
+    #[tokio::test]
+    async fn chained_proxy_header_names_target() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream_task = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut hello = [0; 3];
+            stream.read_exact(&mut hello).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0; 7 + 12];
+            stream.read_exact(&mut request).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut context = Context::default();
+        context.set_proxy_protocol(ProxyProtocol::V1);
+        context.set_resolver(Box::new(LoopbackResolver));
+        context.set_upstream_proxy(UpstreamProxy {
+            address: upstream_addr,
+            credentials: None,
+        });
+
+        let (mut client, server) = tcp_pair().await;
+        let client_addr = client.local_addr().unwrap();
+        let request = [
+            &[5, 1, 0, 3, 12][..],
+            b"service.test",
+            &8080u16.to_be_bytes(),
+        ]
+        .concat();
+        client.write_all(&request).await.unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let proxy = async {
+            while !state.is_finished() {
+                state = state.process(&context).await.unwrap();
+            }
+        };
+        let exchange = async {
+            let mut response = [0; 10];
+            client.read_exact(&mut response).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+        };
+        futures::join!(proxy, exchange);
+
+        // The header has the target, not the upstream proxy
+        let received = upstream_task.await.unwrap();
+        let expected = format!(
+            "PROXY TCP4 127.0.0.1 127.0.0.1 {} 8080\r\nping",
+            client_addr.port()
+        );
+        assert_eq!(String::from_utf8(received).unwrap(), expected);
+    }
+
+    // Records every event, refusing requests to port 25
+    struct RecordingListener {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RecordingListener {
+        fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl EventListener for RecordingListener {
+        async fn on_connect(&self, client: Option<SocketAddr>) {
+            self.record(format!("connect {}", client.is_some()));
+        }
+
+        async fn on_authenticated(&self, username: &str) {
+            self.record(format!("authenticated {}", username));
+        }
+
+        async fn on_request(
+            &self,
+            _address: &Address,
+            port: u16,
+            user: Option<&str>,
+        ) -> Result<(), Error> {
+            if port == 25 {
+                return Err(Error::Generic("no mail".into()));
+            }
+            self.record(format!("request {:?}", user));
+            Ok(())
+        }
+
+        async fn on_close(&self, session: &Session, stats: &ProxyStats) {
+            self.record(format!(
+                "close {:?} {:?} {}",
+                session.user, session.auth_method, stats
+            ));
+        }
+    }
+
+    // Keeps the live stats of the last session that started
+    struct LiveStatsListener {
+        stats: Arc<Mutex<Option<LiveStats>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EventListener for LiveStatsListener {
+        async fn on_proxy_start(&self, _session: &Session, stats: &LiveStats) {
+            *self.stats.lock().unwrap() = Some(stats.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_reset_ends_proxying_cleanly() {
+        let (mut client, server) = tcp_pair().await;
+        let (peer, upstream) = tcp_pair().await;
+        // Dropping it with no linger resets the connection
+        peer.set_linger(Some(Duration::from_secs(0))).unwrap();
+        drop(peer);
+        tokio::spawn(async move { while client.write_all(b"data").await.is_ok() {} });
+
+        let (reader, _) = split(Stream::unbuffered(server));
+        let (_, writer) = split(Stream::unbuffered(upstream));
+        let transferred = AtomicU64::new(0);
+        let context = Context::default();
+        let mut proxier = Proxier::new(
+            reader,
+            writer,
+            false,
+            &transferred,
+            Arc::new(AtomicU64::new(0)),
+            None,
+            &context,
+        );
+        timeout(Duration::from_secs(5), proxier.run())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn live_stats_updated_while_proxying() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let (done, done_signal) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(b"response").await.unwrap();
+            // Keep the session open until the test's done looking at it
+            let _ = done_signal.await;
+        });
+        let stats = Arc::new(Mutex::new(None));
+        let mut context = Context::default();
+        context.set_event_listener(Box::new(LiveStatsListener {
+            stats: Arc::clone(&stats),
+        }));
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        let serve = tokio::spawn(async move { state.process(&context).await });
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut response = [0; 8];
+        client.read_exact(&mut response).await.unwrap();
+
+        // Counters are updated right after each write, so they may lag
+        // behind what the client's seen for a moment
+        let live = stats.lock().unwrap().clone().unwrap();
+        let expected = ProxyStats {
+            client_to_server: 4,
+            server_to_client: 8,
+        };
+        let caught_up = async {
+            while live.snapshot() != expected {
+                sleep(Duration::from_millis(1)).await;
+            }
+        };
+        timeout(Duration::from_secs(1), caught_up).await.unwrap();
+        drop(done);
+        drop(client);
+        serve.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn events_reported() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut context = Context::with_credentials(Credentials::new("foo", "bar"));
+        context.set_event_listener(Box::new(RecordingListener {
+            events: Arc::clone(&events),
+        }));
+
+        let (mut client, server) = tcp_pair().await;
+        let requests = [
+            &[5, 1, 2][..],
+            &[1, 3, b'f', b'o', b'o', 3, b'b', b'a', b'r'],
+            &[5, 1, 0, 1, 127, 0, 0, 1],
+            &port.to_be_bytes(),
+        ];
+        client.write_all(&requests.concat()).await.unwrap();
+        let serve = tokio::spawn(async move {
+            let mut state = State::new(Stream::buffered(server));
+            while !state.is_finished() {
+                state = state.process(&context).await.unwrap();
+            }
+        });
+        // Closing before the connect finishes would abandon it
+        let mut responses = [0; 14];
+        client.read_exact(&mut responses).await.unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+        serve.await.unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "connect true",
+                "authenticated foo",
+                "request Some(\"foo\")",
+                "close Some(\"foo\") Some(UsernamePassword) client_to_server=0 server_to_client=5",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn request_refused_by_event_listener() {
+        let mut context = Context::default();
+        context.set_event_listener(Box::new(RecordingListener {
+            events: Arc::default(),
+        }));
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 25])
+            .await
+            .unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        assert!(state.process(&context).await.unwrap().is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+    }
+
+    #[cfg(feature = "idna")]
+    #[tokio::test]
+    async fn unicode_domain_resolved_as_punycode() {
+        struct PunycodeResolver;
+
+        #[async_trait::async_trait]
+        impl Resolver for PunycodeResolver {
+            async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+                assert_eq!(host, "xn--bcher-kva.test");
+                Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])
+            }
+        }
+
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let mut context = Context::default();
+        context.set_resolver(Box::new(PunycodeResolver));
+
+        let domain = "bücher.test".as_bytes();
+        let (mut client, server) = tcp_pair().await;
+        let request = [
+            &[5, 1, 0, 3, domain.len() as u8][..],
+            domain,
+            &port.to_be_bytes(),
+        ]
+        .concat();
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::Proxying(..)));
+    }
+
+    #[tokio::test]
+    async fn resolution_failure_is_replied() {
+        capture_logs();
+        let mut context = Context::default();
+        context.set_resolver(Box::new(LoopbackResolver));
+
+        let (mut client, server) = tcp_pair().await;
+        let request = [&[5, 1, 0, 3, 12][..], b"unknown.test", &80u16.to_be_bytes()].concat();
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::HostUnreachable as u8);
+        let expected = "request target=unknown.test:80 outcome=failed reply=HostUnreachable";
+        assert!(logged_messages("rusty_socks::states").contains(&expected.to_string()));
+    }
+
+    #[tokio::test]
+    async fn refused_connect_retried() {
+        // Nothing listens on the port until after the first attempt
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let backend = tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            let listener = TcpListener::bind(address).await.unwrap();
+            listener.accept().await.unwrap()
+        });
+        let mut context = Context::default();
+        context.set_connect_retries(5, Duration::from_millis(20));
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&address.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::Proxying(..)));
+        backend.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_slot_released_while_backing_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let mut context = Context::default();
+        context.set_connect_budget(1, Duration::from_secs(1));
+        context.set_connect_retries(2, Duration::from_millis(200));
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&address.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let proxy = state.process(&context);
+        let other_connect = async {
+            sleep(Duration::from_millis(50)).await;
+            timeout(Duration::from_millis(50), context.acquire_connect_slot())
+                .await
+                .unwrap()
+                .is_some()
+        };
+        let (state, got_slot) = futures::join!(proxy, other_connect);
+        assert!(got_slot);
+        assert!(state.unwrap().is_finished());
+    }
+
+    #[tokio::test]
+    async fn disallowed_connect_not_retried() {
+        let mut context = Context::default();
+        let mut acl = DestinationAcl::default();
+        acl.deny_cidr("127.0.0.0/8".parse().unwrap());
+        context.set_destination_acl(acl);
+        context.set_connect_retries(5, Duration::from_secs(10));
+
+        let (mut client, server) = tcp_pair().await;
+        client
+            .write_all(&[
+                5, 1, 0, 3, 9, b'l', b'o', b'c', b'a', b'l', b'h', b'o', b's', b't', 0, 80,
+            ])
+            .await
+            .unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = timeout(Duration::from_secs(1), state.process(&context))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+    }
+
+    #[tokio::test]
+    async fn route_override_followed() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let mut context = Context::default();
+        context.add_route_override(
+            (Address::Domain("prod.test".into()), 5432),
+            (Address::Ip(backend_addr.ip()), backend_addr.port()),
+        );
+
+        let (mut client, server) = tcp_pair().await;
+        let request = [&[5, 1, 0, 3, 9][..], b"prod.test", &5432u16.to_be_bytes()].concat();
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::Proxying(..)));
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::Success as u8);
+        backend.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn self_connect_refused() {
+        let mut context = Context::default();
+        context.set_resolver(Box::new(LoopbackResolver));
+        context.add_self_address("0.0.0.0:1080".parse().unwrap());
+
+        let (mut client, server) = tcp_pair().await;
+        let request = [&[5, 1, 0, 3, 9][..], b"localhost", &1080u16.to_be_bytes()].concat();
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(state.is_finished());
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], ResponseCode::ConnectionNotAllowed as u8);
+    }
+
+    #[tokio::test]
+    async fn reply_carries_bound_address() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let context = Context::default();
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let state = state.process(&context).await.unwrap();
+        assert!(matches!(state, State::Proxying(..)));
+
+        let (_, proxy_address) = backend.accept().await.unwrap();
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[..8], [5, 0, 0, 1, 127, 0, 0, 1]);
+        assert_eq!(
+            u16::from_be_bytes([response[8], response[9]]),
+            proxy_address.port()
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_connection_closed() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let mut context = Context::default();
+        context.set_idle_timeout(Duration::from_millis(50));
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        state = state.process(&context).await.unwrap();
+        let (_upstream, _) = backend.accept().await.unwrap();
+        let state = timeout(Duration::from_secs(1), state.process(&context))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(state.is_finished());
+
+        // The reply, followed by the connection being closed
+        let mut buffer = Vec::new();
+        client.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn quiet_direction_closed_with_the_session() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let mut context = Context::default();
+        context.set_idle_timeout(Duration::from_millis(100));
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        state = state.process(&context).await.unwrap();
+        let (mut upstream, _) = backend.accept().await.unwrap();
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+
+        // The client never sends anything, but the session isn't idle while
+        // the destination keeps talking
+        let proxy = timeout(Duration::from_secs(2), state.process(&context));
+        let traffic = async {
+            for _ in 0..5 {
+                upstream.write_all(b"ping").await.unwrap();
+                let mut buffer = [0; 4];
+                client.read_exact(&mut buffer).await.unwrap();
+                sleep(Duration::from_millis(50)).await;
+            }
+        };
+        // Once both sides go quiet, both of them are closed even though
+        // neither closed its end
+        let (state, _) = futures::join!(proxy, traffic);
+        let state = state.unwrap().unwrap();
+        assert!(state.is_finished());
+        let mut buffer = Vec::new();
+        client.read_to_end(&mut buffer).await.unwrap();
+        assert!(buffer.is_empty());
+        upstream.read_to_end(&mut buffer).await.unwrap();
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn long_session_closed() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let mut context = Context::default();
+        context.set_max_session_duration(Duration::from_millis(200));
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        state = state.process(&context).await.unwrap();
+        let (mut upstream, _) = backend.accept().await.unwrap();
+        let mut response = [0; 10];
+        client.read_exact(&mut response).await.unwrap();
+
+        // The session is busy the whole time, it's closed anyway
+        let proxy = timeout(Duration::from_secs(2), state.process(&context));
+        let traffic = async {
+            let mut buffer = [0; 4];
+            while client.write_all(b"ping").await.is_ok() {
+                if upstream.read_exact(&mut buffer).await.is_err() {
+                    break;
+                }
+                sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let (result, _) = futures::join!(proxy, traffic);
+        assert!(matches!(result.unwrap(), Err(Error::SessionTimeout)));
+        let mut buffer = Vec::new();
+        upstream.read_to_end(&mut buffer).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn half_closed_connection_keeps_flowing() {
+        capture_logs();
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            // Only reply once the client's done sending
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+            stream.write_all(b"response").await.unwrap();
+        });
+        let context = Context::default();
+
+        let (mut client, server) = tcp_pair().await;
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut state = State::AwaitingClientRequest(
+            Stream::buffered(server),
+            AuthenticationMethod::NoAuthentication,
+            None,
+        );
+        let proxy = async {
+            while !state.is_finished() {
+                state = state.process(&context).await.unwrap();
+            }
+        };
+        let exchange = async {
+            client.write_all(b"request").await.unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert_eq!(&response[10..], b"response");
+        };
+        futures::join!(proxy, exchange);
+        let expected = "Connection finished: client_to_server=7 server_to_client=8".to_string();
+        assert!(logged_messages("rusty_socks::states").contains(&expected));
     }
 }
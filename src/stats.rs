@@ -0,0 +1,261 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How many of the most recent connect latencies are kept
+const LATENCY_SAMPLES: usize = 256;
+// Latencies older than this are forgotten, so that a slow spell doesn't keep
+// the server shedding load once no new connects are let through
+const LATENCY_WINDOW: Duration = Duration::from_secs(60);
+
+// Counters for the whole server
+#[derive(Default)]
+pub struct Stats {
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+    // Bytes received from clients and relayed upstream
+    bytes_in: AtomicU64,
+    // Bytes received from upstream and relayed to clients
+    bytes_out: AtomicU64,
+    auth_failures: AtomicU64,
+    // When each latency was recorded, along with it
+    connect_latencies: Mutex<VecDeque<(Instant, Duration)>>,
+    rules: Mutex<HashMap<String, RuleStats>>,
+}
+
+// Traffic handled by sessions that matched a destination rule
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RuleStats {
+    pub sessions: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+// Keeps a connection counted as active until dropped
+pub struct ActiveConnection<'a> {
+    stats: &'a Stats,
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.stats
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Stats {
+    pub fn connection_opened(&self) -> ActiveConnection<'_> {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection { stats: self }
+    }
+
+    pub fn bytes_in(&self) -> &AtomicU64 {
+        &self.bytes_in
+    }
+
+    pub fn bytes_out(&self) -> &AtomicU64 {
+        &self.bytes_out
+    }
+
+    pub fn record_auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Attributes a finished session's traffic to the rule it matched
+    pub fn record_rule_session(&self, rule: &str, bytes_in: u64, bytes_out: u64) {
+        let mut rules = self.rules.lock().unwrap();
+        let stats = rules.entry(rule.into()).or_default();
+        stats.sessions += 1;
+        stats.bytes_in += bytes_in;
+        stats.bytes_out += bytes_out;
+    }
+
+    pub fn record_connect_latency(&self, latency: Duration) {
+        self.record_connect_latency_at(latency, Instant::now());
+    }
+
+    fn record_connect_latency_at(&self, latency: Duration, now: Instant) {
+        let mut latencies = self.connect_latencies.lock().unwrap();
+        if latencies.len() == LATENCY_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back((now, latency));
+    }
+
+    // The 95th percentile of the most recent upstream connect latencies
+    pub fn connect_latency_p95(&self) -> Option<Duration> {
+        self.connect_latency_p95_at(Instant::now())
+    }
+
+    fn connect_latency_p95_at(&self, now: Instant) -> Option<Duration> {
+        let mut latencies = self.connect_latencies.lock().unwrap();
+        while let Some((recorded, _)) = latencies.front() {
+            if now.saturating_duration_since(*recorded) <= LATENCY_WINDOW {
+                break;
+            }
+            latencies.pop_front();
+        }
+        let mut latencies: Vec<Duration> = latencies.iter().map(|(_, latency)| *latency).collect();
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort();
+        let index = (latencies.len() * 95).div_ceil(100) - 1;
+        Some(latencies[index])
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            rules: self.rules.lock().unwrap().clone(),
+        }
+    }
+}
+
+// Thresholds past which the server is considered overloaded and new requests
+// get rejected. Any of them being exceeded is enough.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LoadShedding {
+    pub max_active_connections: Option<u64>,
+    pub max_connect_latency_p95: Option<Duration>,
+}
+
+impl LoadShedding {
+    pub fn is_overloaded(&self, stats: &Stats) -> bool {
+        if let Some(max) = self.max_active_connections {
+            if stats.active_connections.load(Ordering::Relaxed) > max {
+                return true;
+            }
+        }
+        match (self.max_connect_latency_p95, stats.connect_latency_p95()) {
+            (Some(max), Some(p95)) => p95 > max,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub active_connections: u64,
+    pub total_connections: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub auth_failures: u64,
+    pub rules: HashMap<String, RuleStats>,
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "active_connections={} total_connections={} bytes_in={} bytes_out={} auth_failures={}",
+            self.active_connections,
+            self.total_connections,
+            self.bytes_in,
+            self.bytes_out,
+            self.auth_failures
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_snapshot() {
+        let snapshot = StatsSnapshot {
+            active_connections: 2,
+            total_connections: 10,
+            bytes_in: 1024,
+            bytes_out: 4096,
+            auth_failures: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            snapshot.to_string(),
+            "active_connections=2 total_connections=10 bytes_in=1024 bytes_out=4096 auth_failures=1"
+        );
+    }
+
+    #[test]
+    fn active_connections_tracked() {
+        let stats = Stats::default();
+        let first = stats.connection_opened();
+        let _second = stats.connection_opened();
+        drop(first);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.active_connections, 1);
+        assert_eq!(snapshot.total_connections, 2);
+    }
+
+    #[test]
+    fn connect_latency_percentile() {
+        let stats = Stats::default();
+        assert_eq!(stats.connect_latency_p95(), None);
+        for millis in 1..=100 {
+            stats.record_connect_latency(Duration::from_millis(millis));
+        }
+        assert_eq!(stats.connect_latency_p95(), Some(Duration::from_millis(95)));
+    }
+
+    #[test]
+    fn shed_load_on_active_connections() {
+        let stats = Stats::default();
+        let shedding = LoadShedding {
+            max_active_connections: Some(2),
+            ..Default::default()
+        };
+        let first = stats.connection_opened();
+        let _second = stats.connection_opened();
+        assert!(!shedding.is_overloaded(&stats));
+        let _third = stats.connection_opened();
+        assert!(shedding.is_overloaded(&stats));
+        drop(first);
+        assert!(!shedding.is_overloaded(&stats));
+    }
+
+    #[test]
+    fn shed_load_on_connect_latency() {
+        let stats = Stats::default();
+        let shedding = LoadShedding {
+            max_connect_latency_p95: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        stats.record_connect_latency(Duration::from_millis(100));
+        assert!(!shedding.is_overloaded(&stats));
+        for _ in 0..10 {
+            stats.record_connect_latency(Duration::from_secs(1));
+        }
+        assert!(shedding.is_overloaded(&stats));
+    }
+
+    #[test]
+    fn slow_connects_forgotten_after_window() {
+        let stats = Stats::default();
+        let start = Instant::now();
+        for _ in 0..10 {
+            stats.record_connect_latency_at(Duration::from_secs(1), start);
+        }
+        assert_eq!(
+            stats.connect_latency_p95_at(start + LATENCY_WINDOW),
+            Some(Duration::from_secs(1))
+        );
+        // Nothing got through since, but the server recovers anyway
+        let later = start + LATENCY_WINDOW + Duration::from_secs(1);
+        assert_eq!(stats.connect_latency_p95_at(later), None);
+        stats.record_connect_latency_at(Duration::from_millis(100), later);
+        assert_eq!(
+            stats.connect_latency_p95_at(later),
+            Some(Duration::from_millis(100))
+        );
+    }
+}
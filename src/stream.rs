@@ -1,8 +1,21 @@
+use futures::future::{pending, poll_fn};
 use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::io::{split, AsyncRead, AsyncWrite, BufReader, BufWriter, ReadBuf, ReadHalf, WriteHalf};
+use tokio::io::{
+    split, AsyncBufRead, AsyncRead, AsyncWrite, BufReader, BufWriter, ReadBuf, ReadHalf, WriteHalf,
+};
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsStream;
+
+// Anything a stream can be built from, see `Stream::from_io`
+pub trait AsyncIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncIo for T {}
 
 enum StreamType {
     Tcp(ReadHalf<TcpStream>, WriteHalf<TcpStream>),
@@ -10,38 +23,192 @@ enum StreamType {
         BufReader<ReadHalf<TcpStream>>,
         BufWriter<WriteHalf<TcpStream>>,
     ),
+    #[cfg(feature = "tls")]
+    Tls(
+        ReadHalf<TlsStream<TcpStream>>,
+        WriteHalf<TlsStream<TcpStream>>,
+    ),
+    #[cfg(feature = "tls")]
+    BufferedTls(
+        BufReader<ReadHalf<TlsStream<TcpStream>>>,
+        BufWriter<WriteHalf<TlsStream<TcpStream>>>,
+    ),
+    // Unix sockets are only used for clients, and stay buffered
+    #[cfg(unix)]
+    BufferedUnix(
+        BufReader<ReadHalf<UnixStream>>,
+        BufWriter<WriteHalf<UnixStream>>,
+    ),
+    BufferedIo(
+        BufReader<ReadHalf<Box<dyn AsyncIo>>>,
+        BufWriter<WriteHalf<Box<dyn AsyncIo>>>,
+    ),
 }
 
 pub struct Stream {
     stream_type: StreamType,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
 }
 
 impl Stream {
     pub fn unbuffered(stream: TcpStream) -> Self {
+        let peer_addr = stream.peer_addr().ok();
+        let local_addr = stream.local_addr().ok();
         let (reader, writer) = split(stream);
         Stream {
             stream_type: StreamType::Tcp(reader, writer),
+            peer_addr,
+            local_addr,
         }
     }
 
     pub fn buffered(stream: TcpStream) -> Self {
+        let peer_addr = stream.peer_addr().ok();
+        let local_addr = stream.local_addr().ok();
         let (reader, writer) = split(stream);
         Stream {
             stream_type: StreamType::BufferedTcp(BufReader::new(reader), BufWriter::new(writer)),
+            peer_addr,
+            local_addr,
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(stream: TlsStream<TcpStream>) -> Self {
+        let peer_addr = stream.get_ref().0.peer_addr().ok();
+        let local_addr = stream.get_ref().0.local_addr().ok();
+        let (reader, writer) = split(stream);
+        Stream {
+            stream_type: StreamType::Tls(reader, writer),
+            peer_addr,
+            local_addr,
         }
     }
 
+    // Used for client connections, which are buffered like plain TCP ones
+    #[cfg(feature = "tls")]
+    pub fn buffered_tls(stream: TlsStream<TcpStream>) -> Self {
+        let peer_addr = stream.get_ref().0.peer_addr().ok();
+        let local_addr = stream.get_ref().0.local_addr().ok();
+        let (reader, writer) = split(stream);
+        Stream {
+            stream_type: StreamType::BufferedTls(BufReader::new(reader), BufWriter::new(writer)),
+            peer_addr,
+            local_addr,
+        }
+    }
+
+    // Unix socket peers have no IP address, so neither address is known
+    #[cfg(unix)]
+    pub fn buffered_unix(stream: UnixStream) -> Self {
+        let (reader, writer) = split(stream);
+        Stream {
+            stream_type: StreamType::BufferedUnix(BufReader::new(reader), BufWriter::new(writer)),
+            peer_addr: None,
+            local_addr: None,
+        }
+    }
+
+    // Wraps any IO object, like an in-memory pipe. Neither address is known.
+    pub fn from_io<T: AsyncIo + 'static>(io: T) -> Self {
+        let io: Box<dyn AsyncIo> = Box::new(io);
+        let (reader, writer) = split(io);
+        Stream {
+            stream_type: StreamType::BufferedIo(BufReader::new(reader), BufWriter::new(writer)),
+            peer_addr: None,
+            local_addr: None,
+        }
+    }
+
+    // The address is captured before splitting the socket, as it's not
+    // reachable afterwards
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    // Returns the next byte without consuming it, or None if the peer closed
+    // the connection. Only buffered streams can look ahead.
+    pub async fn peek_u8(&mut self) -> io::Result<Option<u8>> {
+        let stream_type = &mut self.stream_type;
+        poll_fn(|cx| match stream_type {
+            StreamType::BufferedTcp(ref mut reader, _) => poll_peek(Pin::new(reader), cx),
+            #[cfg(feature = "tls")]
+            StreamType::BufferedTls(ref mut reader, _) => poll_peek(Pin::new(reader), cx),
+            #[cfg(unix)]
+            StreamType::BufferedUnix(ref mut reader, _) => poll_peek(Pin::new(reader), cx),
+            StreamType::BufferedIo(ref mut reader, _) => poll_peek(Pin::new(reader), cx),
+            _ => Poll::Ready(Err(io::Error::other("Can't peek on unbuffered stream"))),
+        })
+        .await
+    }
+
+    // Resolves once the peer closes its side of the connection. Nothing is
+    // consumed from the stream, so if data arrives instead this never resolves.
+    // Unbuffered streams can't look ahead and never resolve either.
+    pub async fn closed(&mut self) -> io::Result<()> {
+        let stream_type = &mut self.stream_type;
+        let next = poll_fn(|cx| match stream_type {
+            StreamType::Tcp(..) => Poll::Pending,
+            #[cfg(feature = "tls")]
+            StreamType::Tls(..) => Poll::Pending,
+            StreamType::BufferedTcp(ref mut reader, _) => poll_peek(Pin::new(reader), cx),
+            #[cfg(feature = "tls")]
+            StreamType::BufferedTls(ref mut reader, _) => poll_peek(Pin::new(reader), cx),
+            #[cfg(unix)]
+            StreamType::BufferedUnix(ref mut reader, _) => poll_peek(Pin::new(reader), cx),
+            StreamType::BufferedIo(ref mut reader, _) => poll_peek(Pin::new(reader), cx),
+        })
+        .await?;
+        if next.is_some() {
+            pending::<()>().await;
+        }
+        Ok(())
+    }
+
+    // Plain TCP streams send everything as soon as it's written, so flushing
+    // them does nothing
+    pub fn needs_flush(&self) -> bool {
+        !matches!(self.stream_type, StreamType::Tcp(..))
+    }
+
+    // Drops the buffering, unless there's data that was already read into
+    // the buffer, which would be lost otherwise
     pub fn into_unbuffered(self) -> Self {
-        let (reader, writer) = match self.stream_type {
-            StreamType::Tcp(reader, writer) => (reader, writer),
-            StreamType::BufferedTcp(reader, writer) => (reader.into_inner(), writer.into_inner()),
+        let stream_type = match self.stream_type {
+            StreamType::BufferedTcp(reader, writer) if reader.buffer().is_empty() => {
+                StreamType::Tcp(reader.into_inner(), writer.into_inner())
+            }
+            #[cfg(feature = "tls")]
+            StreamType::BufferedTls(reader, writer) if reader.buffer().is_empty() => {
+                StreamType::Tls(reader.into_inner(), writer.into_inner())
+            }
+            stream_type => stream_type,
         };
         Stream {
-            stream_type: StreamType::Tcp(reader, writer),
+            stream_type,
+            peer_addr: self.peer_addr,
+            local_addr: self.local_addr,
         }
     }
 }
 
+// The next byte in the reader's buffer, filling it if needed
+fn poll_peek<R: AsyncBufRead>(
+    reader: Pin<&mut R>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<Option<u8>>> {
+    match reader.poll_fill_buf(cx) {
+        Poll::Ready(Ok(buffer)) => Poll::Ready(Ok(buffer.first().copied())),
+        Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+        Poll::Pending => Poll::Pending,
+    }
+}
+
 impl AsyncRead for Stream {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -53,6 +220,19 @@ impl AsyncRead for Stream {
             StreamType::BufferedTcp(ref mut reader, _) => {
                 AsyncRead::poll_read(Pin::new(reader), cx, buf)
             }
+            #[cfg(feature = "tls")]
+            StreamType::Tls(ref mut reader, _) => AsyncRead::poll_read(Pin::new(reader), cx, buf),
+            #[cfg(feature = "tls")]
+            StreamType::BufferedTls(ref mut reader, _) => {
+                AsyncRead::poll_read(Pin::new(reader), cx, buf)
+            }
+            #[cfg(unix)]
+            StreamType::BufferedUnix(ref mut reader, _) => {
+                AsyncRead::poll_read(Pin::new(reader), cx, buf)
+            }
+            StreamType::BufferedIo(ref mut reader, _) => {
+                AsyncRead::poll_read(Pin::new(reader), cx, buf)
+            }
         }
     }
 }
@@ -68,6 +248,19 @@ impl AsyncWrite for Stream {
             StreamType::BufferedTcp(_, ref mut writer) => {
                 AsyncWrite::poll_write(Pin::new(writer), cx, buf)
             }
+            #[cfg(feature = "tls")]
+            StreamType::Tls(_, ref mut writer) => AsyncWrite::poll_write(Pin::new(writer), cx, buf),
+            #[cfg(feature = "tls")]
+            StreamType::BufferedTls(_, ref mut writer) => {
+                AsyncWrite::poll_write(Pin::new(writer), cx, buf)
+            }
+            #[cfg(unix)]
+            StreamType::BufferedUnix(_, ref mut writer) => {
+                AsyncWrite::poll_write(Pin::new(writer), cx, buf)
+            }
+            StreamType::BufferedIo(_, ref mut writer) => {
+                AsyncWrite::poll_write(Pin::new(writer), cx, buf)
+            }
         }
     }
 
@@ -77,6 +270,19 @@ impl AsyncWrite for Stream {
             StreamType::BufferedTcp(_, ref mut writer) => {
                 AsyncWrite::poll_flush(Pin::new(writer), cx)
             }
+            #[cfg(feature = "tls")]
+            StreamType::Tls(_, ref mut writer) => AsyncWrite::poll_flush(Pin::new(writer), cx),
+            #[cfg(feature = "tls")]
+            StreamType::BufferedTls(_, ref mut writer) => {
+                AsyncWrite::poll_flush(Pin::new(writer), cx)
+            }
+            #[cfg(unix)]
+            StreamType::BufferedUnix(_, ref mut writer) => {
+                AsyncWrite::poll_flush(Pin::new(writer), cx)
+            }
+            StreamType::BufferedIo(_, ref mut writer) => {
+                AsyncWrite::poll_flush(Pin::new(writer), cx)
+            }
         }
     }
 
@@ -86,6 +292,133 @@ impl AsyncWrite for Stream {
             StreamType::BufferedTcp(_, ref mut writer) => {
                 AsyncWrite::poll_shutdown(Pin::new(writer), cx)
             }
+            #[cfg(feature = "tls")]
+            StreamType::Tls(_, ref mut writer) => AsyncWrite::poll_shutdown(Pin::new(writer), cx),
+            #[cfg(feature = "tls")]
+            StreamType::BufferedTls(_, ref mut writer) => {
+                AsyncWrite::poll_shutdown(Pin::new(writer), cx)
+            }
+            #[cfg(unix)]
+            StreamType::BufferedUnix(_, ref mut writer) => {
+                AsyncWrite::poll_shutdown(Pin::new(writer), cx)
+            }
+            StreamType::BufferedIo(_, ref mut writer) => {
+                AsyncWrite::poll_shutdown(Pin::new(writer), cx)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tcp_pair;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn addresses_kept_after_splitting() {
+        let (client, server) = tcp_pair().await;
+        let client_addr = client.local_addr().unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let stream = Stream::buffered(server);
+        assert_eq!(stream.peer_addr(), Some(client_addr));
+        assert_eq!(stream.local_addr(), Some(server_addr));
+        let stream = Stream::unbuffered(client);
+        assert_eq!(stream.peer_addr(), Some(server_addr));
+        assert_eq!(stream.local_addr(), Some(client_addr));
+
+        let (io, _) = tokio::io::duplex(64);
+        let stream = Stream::from_io(io);
+        assert_eq!(stream.peer_addr(), None);
+        assert_eq!(stream.local_addr(), None);
+    }
+
+    #[tokio::test]
+    async fn peek_does_not_consume_data() {
+        let (mut client, server) = tcp_pair().await;
+        let mut stream = Stream::buffered(server);
+        client.write_all(&[4, 1]).await.unwrap();
+        assert_eq!(stream.peek_u8().await.unwrap(), Some(4));
+        let mut buffer = [0; 2];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [4, 1]);
+
+        drop(client);
+        assert_eq!(stream.peek_u8().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn into_unbuffered_keeps_pending_data() {
+        let (mut client, server) = tcp_pair().await;
+        let mut stream = Stream::buffered(server);
+        client.write_all(&[1, 2, 3]).await.unwrap();
+        assert_eq!(stream.read_u8().await.unwrap(), 1);
+        let mut stream = stream.into_unbuffered();
+        let mut buffer = [0; 2];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn unbuffered_tcp_needs_no_flush() {
+        let (_client, server) = tcp_pair().await;
+        let stream = Stream::buffered(server);
+        assert!(stream.needs_flush());
+        assert!(!stream.into_unbuffered().needs_flush());
+        assert!(Stream::from_io(tokio::io::duplex(64).0).needs_flush());
+    }
+
+    #[tokio::test]
+    async fn closed_when_peer_closes() {
+        let (client, server) = tcp_pair().await;
+        let mut stream = Stream::buffered(server);
+        drop(client);
+        timeout(Duration::from_secs(1), stream.closed())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_stream_peek() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut stream = Stream::buffered_unix(server);
+        client.write_all(&[5, 1]).await.unwrap();
+        assert_eq!(stream.peek_u8().await.unwrap(), Some(5));
+        let mut buffer = [0; 2];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [5, 1]);
+        assert!(stream.peer_addr().is_none());
+    }
+
+    #[tokio::test]
+    async fn in_memory_stream() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut stream = Stream::from_io(server);
+        client.write_all(&[5, 1]).await.unwrap();
+        assert_eq!(stream.peek_u8().await.unwrap(), Some(5));
+        let mut buffer = [0; 2];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [5, 1]);
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.flush().await.unwrap();
+        client.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [5, 0]);
+    }
+
+    #[tokio::test]
+    async fn closed_does_not_consume_data() {
+        let (mut client, server) = tcp_pair().await;
+        let mut stream = Stream::buffered(server);
+        client.write_all(&[1, 2, 3]).await.unwrap();
+        assert!(timeout(Duration::from_millis(50), stream.closed())
+            .await
+            .is_err());
+        let mut buffer = [0; 3];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+    }
+}
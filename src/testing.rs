@@ -0,0 +1,59 @@
+// Helpers shared by the unit tests
+
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::{Mutex, Once};
+use tokio::net::{TcpListener, TcpStream};
+
+struct CapturingLogger {
+    records: Mutex<Vec<(String, String)>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.records
+            .lock()
+            .unwrap()
+            .push((record.target().into(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    records: Mutex::new(Vec::new()),
+};
+static LOGGER_INIT: Once = Once::new();
+
+// Installs a logger that keeps every record so tests can inspect them
+pub fn capture_logs() {
+    LOGGER_INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+}
+
+// Returns the messages logged so far under the given target
+pub fn logged_messages(target: &str) -> Vec<String> {
+    LOGGER
+        .records
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(record_target, _)| record_target == target)
+        .map(|(_, message)| message.clone())
+        .collect()
+}
+
+// Returns both ends of a loopback TCP connection, as (client, server)
+pub async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
@@ -0,0 +1,179 @@
+use crate::error::Error;
+use crate::stream::Stream;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{
+    Certificate, ClientConfig, NoClientAuth, PrivateKey, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, ServerConfig, TLSError,
+};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+// Accepts any certificate the server presents
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// Originates TLS on upstream connections, so clients can speak plaintext
+// through the proxy while the proxy speaks TLS to the destination
+#[derive(Clone)]
+pub struct UpstreamTls {
+    connector: TlsConnector,
+    server_name: Option<String>,
+}
+
+impl UpstreamTls {
+    // Verifies server certificates against the bundled webpki roots
+    pub fn new() -> Self {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        Self::from_config(config)
+    }
+
+    // Verifies server certificates against the CAs in the given PEM file
+    pub fn with_ca_file(path: &str) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        let mut config = ClientConfig::new();
+        match config.root_store.add_pem_file(&mut BufReader::new(file)) {
+            Ok((valid, _)) if valid > 0 => Ok(Self::from_config(config)),
+            _ => Err(Error::Generic(format!("No valid CAs found in {}", path))),
+        }
+    }
+
+    // Doesn't verify server certificates at all
+    pub fn without_verification() -> Self {
+        let mut config = ClientConfig::new();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoVerification));
+        Self::from_config(config)
+    }
+
+    fn from_config(config: ClientConfig) -> Self {
+        UpstreamTls {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: None,
+        }
+    }
+
+    // Name used as SNI and verified against the certificate instead of the
+    // requested domain. Needed when clients ask for IP addresses.
+    pub fn set_server_name(&mut self, name: &str) {
+        self.server_name = Some(name.to_string());
+    }
+
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    // Performs the TLS handshake over the connection, using the domain as SNI
+    pub async fn connect(&self, domain: &str, stream: TcpStream) -> Result<Stream, Error> {
+        let domain = DNSNameRef::try_from_ascii_str(domain)
+            .map_err(|_| Error::Generic(format!("Invalid TLS server name: {}", domain)))?;
+        let stream = self.connector.connect(domain, stream).await?;
+        Ok(Stream::tls(stream.into()))
+    }
+}
+
+impl Default for UpstreamTls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Terminates TLS on client connections, so the SOCKS traffic between clients
+// and the proxy is encrypted
+#[derive(Clone)]
+pub struct ClientTls {
+    acceptor: TlsAcceptor,
+}
+
+impl ClientTls {
+    // Loads the certificate chain and its private key from PEM files. The key
+    // can be either PKCS #8 or RSA.
+    pub fn from_files(cert_path: &str, key_path: &str) -> Result<Self, Error> {
+        let chain = certs(&mut BufReader::new(File::open(cert_path)?))
+            .ok()
+            .filter(|chain| !chain.is_empty())
+            .ok_or_else(|| Error::Generic(format!("No certificates found in {}", cert_path)))?;
+        let key = load_private_key(key_path)?;
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config
+            .set_single_cert(chain, key)
+            .map_err(|e| Error::Generic(format!("Invalid TLS certificate or key: {}", e)))?;
+        Ok(ClientTls {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    // Performs the TLS handshake with the client
+    pub async fn accept(&self, stream: TcpStream) -> Result<Stream, Error> {
+        let stream = self.acceptor.accept(stream).await?;
+        Ok(Stream::buffered_tls(stream.into()))
+    }
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, Error> {
+    let read_keys = |parse: fn(&mut dyn std::io::BufRead) -> Result<Vec<PrivateKey>, ()>| {
+        let file = File::open(path)?;
+        Ok::<_, Error>(parse(&mut BufReader::new(file)).unwrap_or_default())
+    };
+    let mut keys = read_keys(pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_keys(rsa_private_keys)?;
+    }
+    match keys.is_empty() {
+        false => Ok(keys.remove(0)),
+        true => Err(Error::Generic(format!("No private key found in {}", path))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tcp_pair;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const CERT_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost.pem");
+    const KEY_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/localhost-key.pem");
+    const CA_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/ca.pem");
+
+    #[tokio::test]
+    async fn terminate_client_tls() {
+        let client_tls = ClientTls::from_files(CERT_FILE, KEY_FILE).unwrap();
+        let upstream_tls = UpstreamTls::with_ca_file(CA_FILE).unwrap();
+        let (client, server) = tcp_pair().await;
+        let (client, server) = futures::join!(
+            upstream_tls.connect("localhost", client),
+            client_tls.accept(server)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.flush().await.unwrap();
+        // Client streams can look ahead, like plain TCP ones
+        assert_eq!(server.peek_u8().await.unwrap(), Some(5));
+        let mut buffer = [0; 3];
+        server.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, [5, 1, 0]);
+    }
+
+    #[test]
+    fn missing_key() {
+        assert!(ClientTls::from_files(CERT_FILE, CERT_FILE).is_err());
+    }
+}
@@ -0,0 +1,505 @@
+use crate::acl::DestinationAcl;
+use crate::lru::LruCache;
+use futures::stream::{FuturesUnordered, StreamExt};
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tracing::debug;
+
+// How long a connection attempt goes on before the next address is tried
+// alongside it, as recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Options applied to the sockets used to connect upstream
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SocketOptions {
+    // Sets SO_REUSEADDR
+    pub reuse_address: bool,
+    // Sets IP_BIND_ADDRESS_NO_PORT so the kernel only picks the source port
+    // on connect. Only supported on Linux.
+    pub bind_address_no_port: bool,
+    // The local address connections originate from
+    pub bind_address: Option<IpAddr>,
+}
+
+impl SocketOptions {
+    fn is_default(&self) -> bool {
+        !self.reuse_address && !self.bind_address_no_port && self.bind_address.is_none()
+    }
+}
+
+// TCP keepalive probing, so dead peers on idle connections are noticed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Keepalive {
+    // How long a connection stays idle before probes are sent
+    pub idle: Duration,
+    // How long to wait between probes
+    pub interval: Duration,
+}
+
+// Enables keepalive on a client or upstream stream
+pub(crate) fn set_keepalive(stream: &TcpStream, keepalive: &Keepalive) -> io::Result<()> {
+    let params = TcpKeepalive::new()
+        .with_time(keepalive.idle)
+        .with_interval(keepalive.interval);
+    with_socket(stream, |socket| socket.set_tcp_keepalive(&params))
+}
+
+// Runs `f` on a socket2 view of the stream, to set options tokio doesn't
+// expose
+#[cfg(unix)]
+fn with_socket<F, R>(stream: &TcpStream, f: F) -> io::Result<R>
+where
+    F: FnOnce(&Socket) -> io::Result<R>,
+{
+    use std::mem::ManuallyDrop;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    // SAFETY: the fd stays owned by the stream, which outlives the socket.
+    // ManuallyDrop keeps the socket from closing it.
+    let socket = ManuallyDrop::new(unsafe { Socket::from_raw_fd(stream.as_raw_fd()) });
+    f(&socket)
+}
+
+#[cfg(not(unix))]
+fn with_socket<F, R>(_stream: &TcpStream, _f: F) -> io::Result<R>
+where
+    F: FnOnce(&Socket) -> io::Result<R>,
+{
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Setting socket options on streams is only supported on unix",
+    ))
+}
+
+// Remembers, per domain, the last address a connection succeeded to so it
+// can be tried first next time
+pub struct LastGoodAddresses {
+    entries: Mutex<LruCache<String, IpAddr>>,
+}
+
+impl LastGoodAddresses {
+    pub fn new(capacity: usize) -> Self {
+        LastGoodAddresses {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    pub fn record(&self, domain: &str, address: IpAddr) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(domain.to_lowercase(), address);
+    }
+
+    // Moves the domain's last good address to the front, if it's there
+    pub fn prioritize(&self, domain: &str, addresses: &mut Vec<SocketAddr>) {
+        let last_good = match self.entries.lock().unwrap().get(&domain.to_lowercase()) {
+            Some(address) => *address,
+            None => return,
+        };
+        if let Some(index) = addresses.iter().position(|a| a.ip() == last_good) {
+            let address = addresses.remove(index);
+            addresses.insert(0, address);
+        }
+    }
+}
+
+// Connects to one of the resolved addresses the ACL allows, racing them
+// against each other. `domain` is the one the addresses were resolved from,
+// if any.
+pub async fn connect(
+    mut addresses: Vec<SocketAddr>,
+    domain: Option<&str>,
+    options: &SocketOptions,
+    last_good: Option<&LastGoodAddresses>,
+    acl: &DestinationAcl,
+) -> io::Result<TcpStream> {
+    if let (Some(last_good), Some(domain)) = (last_good, domain) {
+        last_good.prioritize(domain, &mut addresses);
+    }
+    // Connections can only originate from an address in the same family
+    if let Some(bind_address) = options.bind_address {
+        let any = !addresses.is_empty();
+        addresses.retain(|candidate| candidate.is_ipv4() == bind_address.is_ipv4());
+        if any && addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NetworkUnreachable,
+                "no addresses in the outgoing bind address' family",
+            ));
+        }
+    }
+    if !addresses.is_empty() {
+        addresses.retain(|candidate| acl.allows_ip(candidate.ip()));
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "destination not allowed",
+            ));
+        }
+    }
+    let (stream, address) = race(interleave_families(addresses), options).await?;
+    if let (Some(last_good), Some(domain)) = (last_good, domain) {
+        last_good.record(domain, address.ip());
+    }
+    Ok(stream)
+}
+
+// Orders the addresses alternating between families, starting with the
+// family of the first one, so a broken path over one of them doesn't hold up
+// the other
+fn interleave_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = match addresses.first() {
+        Some(address) => address.is_ipv6(),
+        None => return addresses,
+    };
+    let mut output = Vec::with_capacity(addresses.len());
+    let (preferred, other): (Vec<_>, Vec<_>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == first_is_ipv6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return output,
+            (first, second) => output.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+// Connects to the addresses in order as RFC 8305 describes: each attempt
+// starts once the previous one fails or has been going on for a while, and
+// the first one to succeed wins. The rest are dropped, closing their sockets.
+async fn race(
+    addresses: Vec<SocketAddr>,
+    options: &SocketOptions,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut remaining = addresses.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+    loop {
+        if attempts.is_empty() {
+            match remaining.next() {
+                Some(address) => attempts.push(attempt(address, options)),
+                None => return Err(last_error),
+            }
+        }
+        tokio::select! {
+            Some((address, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream, address)),
+                Err(e) => {
+                    debug!("Failed to connect to {}: {}", address, e);
+                    last_error = e;
+                    if let Some(address) = remaining.next() {
+                        attempts.push(attempt(address, options));
+                    }
+                }
+            },
+            _ = sleep(CONNECTION_ATTEMPT_DELAY), if !remaining.as_slice().is_empty() => {
+                if let Some(address) = remaining.next() {
+                    attempts.push(attempt(address, options));
+                }
+            }
+        }
+    }
+}
+
+async fn attempt(
+    address: SocketAddr,
+    options: &SocketOptions,
+) -> (SocketAddr, io::Result<TcpStream>) {
+    (address, connect_address(address, options).await)
+}
+
+pub(crate) async fn connect_address(
+    address: SocketAddr,
+    options: &SocketOptions,
+) -> io::Result<TcpStream> {
+    if options.is_default() {
+        return TcpStream::connect(address).await;
+    }
+    let socket = build_socket(address, options)?;
+    connect_socket(socket, address).await
+}
+
+fn build_socket(address: SocketAddr, options: &SocketOptions) -> io::Result<Socket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if options.reuse_address {
+        socket.set_reuse_address(true)?;
+    }
+    if options.bind_address_no_port {
+        set_bind_address_no_port(&socket)?;
+    }
+    if let Some(bind_address) = options.bind_address {
+        socket.bind(&SocketAddr::new(bind_address, 0).into())?;
+    }
+    Ok(socket)
+}
+
+#[cfg(target_os = "linux")]
+fn set_bind_address_no_port(socket: &Socket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let enabled: libc::c_int = 1;
+    // SAFETY: the fd is a valid socket owned by `socket` and the option value
+    // points to a c_int that outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_BIND_ADDRESS_NO_PORT,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_bind_address_no_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "IP_BIND_ADDRESS_NO_PORT is only supported on Linux",
+    ))
+}
+
+#[cfg(unix)]
+async fn connect_socket(socket: Socket, address: SocketAddr) -> io::Result<TcpStream> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use tokio::net::TcpSocket;
+
+    socket.set_nonblocking(true)?;
+    // SAFETY: the fd comes straight from a socket we own and is handed over
+    let socket = unsafe { TcpSocket::from_raw_fd(socket.into_raw_fd()) };
+    socket.connect(address).await
+}
+
+#[cfg(not(unix))]
+async fn connect_socket(socket: Socket, address: SocketAddr) -> io::Result<TcpStream> {
+    let stream = tokio::task::spawn_blocking(move || {
+        socket.connect(&address.into())?;
+        socket.set_nonblocking(true)?;
+        Ok::<_, io::Error>(std::net::TcpStream::from(socket))
+    })
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+    TcpStream::from_std(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{lookup_host, TcpListener};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn keepalive_set() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let keepalive = Keepalive {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+        };
+        set_keepalive(&stream, &keepalive).unwrap();
+        with_socket(&stream, |socket| {
+            assert!(socket.keepalive()?);
+            assert_eq!(socket.keepalive_time()?, keepalive.idle);
+            assert_eq!(socket.keepalive_interval()?, keepalive.interval);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn default_socket_options() {
+        let address = "127.0.0.1:80".parse().unwrap();
+        let socket = build_socket(address, &SocketOptions::default()).unwrap();
+        assert!(!socket.reuse_address().unwrap());
+    }
+
+    #[test]
+    fn reuse_address_set() {
+        let options = SocketOptions {
+            reuse_address: true,
+            ..Default::default()
+        };
+        let address = "127.0.0.1:80".parse().unwrap();
+        let socket = build_socket(address, &options).unwrap();
+        assert!(socket.reuse_address().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bind_address_no_port_set() {
+        use std::os::unix::io::AsRawFd;
+
+        let options = SocketOptions {
+            bind_address_no_port: true,
+            ..Default::default()
+        };
+        let address = "127.0.0.1:80".parse().unwrap();
+        let socket = build_socket(address, &options).unwrap();
+        let mut value: libc::c_int = 0;
+        let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_BIND_ADDRESS_NO_PORT,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut length,
+            )
+        };
+        assert_eq!(result, 0);
+        assert_eq!(value, 1);
+    }
+
+    #[tokio::test]
+    async fn connect_with_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let options = SocketOptions {
+            reuse_address: true,
+            ..Default::default()
+        };
+        let stream = connect(
+            vec![local_addr],
+            None,
+            &options,
+            None,
+            &DestinationAcl::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), local_addr);
+    }
+
+    #[test]
+    fn prioritize_last_good_address() {
+        let last_good = LastGoodAddresses::new(16);
+        let mut addresses: Vec<SocketAddr> = vec![
+            "10.0.0.1:80".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+        ];
+        last_good.prioritize("example.com", &mut addresses);
+        assert_eq!(addresses[0], "10.0.0.1:80".parse().unwrap());
+
+        last_good.record("example.com", "10.0.0.2".parse().unwrap());
+        last_good.prioritize("EXAMPLE.com", &mut addresses);
+        assert_eq!(addresses[0], "10.0.0.2:80".parse().unwrap());
+        assert_eq!(addresses[1], "10.0.0.1:80".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn successful_address_tried_first() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let last_good = LastGoodAddresses::new(16);
+        let addresses = lookup_host(("localhost", port)).await.unwrap().collect();
+        let options = SocketOptions::default();
+        connect(
+            addresses,
+            Some("localhost"),
+            &options,
+            Some(&last_good),
+            &DestinationAcl::default(),
+        )
+        .await
+        .unwrap();
+
+        // Whatever order localhost resolves in, the address that worked now
+        // comes first
+        let mut addresses = vec![
+            SocketAddr::new("::1".parse().unwrap(), port),
+            SocketAddr::new("127.0.0.1".parse().unwrap(), port),
+        ];
+        last_good.prioritize("localhost", &mut addresses);
+        assert_eq!(addresses[0].ip(), "127.0.0.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn families_interleaved() {
+        let addresses: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:80".parse().unwrap(),
+            "[2001:db8::2]:80".parse().unwrap(),
+            "[2001:db8::3]:80".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+        ];
+        let expected: Vec<SocketAddr> = vec![
+            "[2001:db8::1]:80".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap(),
+            "[2001:db8::2]:80".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+            "[2001:db8::3]:80".parse().unwrap(),
+        ];
+        assert_eq!(interleave_families(addresses), expected);
+        assert_eq!(interleave_families(Vec::new()), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn unresponsive_address_raced() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        // Connecting to a documentation address either hangs or fails right
+        // away, either way the next address is tried without waiting it out
+        let addresses = vec!["[2001:db8::1]:80".parse().unwrap(), local_addr];
+        let start = std::time::Instant::now();
+        let stream = timeout(
+            Duration::from_secs(5),
+            connect(
+                addresses,
+                None,
+                &SocketOptions::default(),
+                None,
+                &DestinationAcl::default(),
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), local_addr);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn connect_from_bind_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let options = SocketOptions {
+            bind_address: Some("127.0.0.2".parse().unwrap()),
+            ..Default::default()
+        };
+        let addresses = vec!["[::1]:80".parse().unwrap(), local_addr];
+        let stream = connect(addresses, None, &options, None, &DestinationAcl::default())
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), local_addr);
+        assert_eq!(
+            stream.local_addr().unwrap().ip(),
+            "127.0.0.2".parse::<IpAddr>().unwrap()
+        );
+
+        // None of the addresses can be reached from the bind address
+        let addresses = vec!["[::1]:80".parse().unwrap()];
+        let result = connect(addresses, None, &options, None, &DestinationAcl::default()).await;
+        assert_eq!(
+            result.unwrap_err().kind(),
+            io::ErrorKind::NetworkUnreachable
+        );
+    }
+}
@@ -0,0 +1,171 @@
+// Drives clients through the whole state machine, from the hello until the
+// data is proxied, over in-memory pipes
+
+use rusty_socks::context::{Context, Credentials};
+use rusty_socks::messages::ResponseCode;
+use rusty_socks::states::State;
+use rusty_socks::stream::Stream;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+// Serves a single client connected through the returned pipe. The task
+// resolves to whether the state machine finished without errors.
+fn serve_client(context: Context) -> (DuplexStream, JoinHandle<bool>) {
+    let (client, server) = duplex(1024);
+    let context = Arc::new(context);
+    let task = tokio::spawn(async move {
+        let mut state = State::new(Stream::from_io(server));
+        while !state.is_finished() {
+            state = match state.process(&context).await {
+                Ok(state) => state,
+                Err(_) => return false,
+            };
+        }
+        true
+    });
+    (client, task)
+}
+
+// Echoes back everything written to it, for a single connection
+async fn spawn_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0; 1024];
+        loop {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(size) => stream.write_all(&buffer[..size]).await.unwrap(),
+            }
+        }
+    });
+    address
+}
+
+fn connect_request(address: SocketAddr) -> Vec<u8> {
+    let ip = match address {
+        SocketAddr::V4(address) => address.ip().octets(),
+        SocketAddr::V6(_) => panic!("only IPv4 is used here"),
+    };
+    [&[5, 1, 0, 1][..], &ip, &address.port().to_be_bytes()].concat()
+}
+
+async fn read_reply_code(client: &mut DuplexStream) -> u8 {
+    let mut reply = [0; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[0], 5);
+    reply[1]
+}
+
+async fn assert_echoed(client: &mut DuplexStream) {
+    client.write_all(b"ping").await.unwrap();
+    let mut data = [0; 4];
+    client.read_exact(&mut data).await.unwrap();
+    assert_eq!(&data, b"ping");
+}
+
+#[tokio::test]
+async fn anonymous_connect_proxies_data() {
+    let echo = spawn_echo_server().await;
+    let (mut client, _task) = serve_client(Context::default());
+
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut response = [0; 2];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, [5, 0]);
+    client.write_all(&connect_request(echo)).await.unwrap();
+    assert_eq!(
+        read_reply_code(&mut client).await,
+        ResponseCode::Success as u8
+    );
+    assert_echoed(&mut client).await;
+}
+
+#[tokio::test]
+async fn password_authentication_succeeds() {
+    let echo = spawn_echo_server().await;
+    let context = Context::with_credentials(Credentials::new("foo", "bar"));
+    let (mut client, _task) = serve_client(context);
+
+    client.write_all(&[5, 2, 0, 2]).await.unwrap();
+    let mut response = [0; 2];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, [5, 2]);
+    client
+        .write_all(&[1, 3, b'f', b'o', b'o', 3, b'b', b'a', b'r'])
+        .await
+        .unwrap();
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, [1, 0]);
+    client.write_all(&connect_request(echo)).await.unwrap();
+    assert_eq!(
+        read_reply_code(&mut client).await,
+        ResponseCode::Success as u8
+    );
+    assert_echoed(&mut client).await;
+}
+
+#[tokio::test]
+async fn gssapi_offer_negotiates_password() {
+    let context = Context::with_credentials(Credentials::new("foo", "bar"));
+    let (mut client, _task) = serve_client(context);
+
+    client.write_all(&[5, 2, 1, 2]).await.unwrap();
+    let mut response = [0; 2];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, [5, 2]);
+}
+
+#[tokio::test]
+async fn password_authentication_fails() {
+    let context = Context::with_credentials(Credentials::new("foo", "bar"));
+    let (mut client, task) = serve_client(context);
+
+    client.write_all(&[5, 1, 2]).await.unwrap();
+    let mut response = [0; 2];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, [5, 2]);
+    client
+        .write_all(&[1, 3, b'f', b'o', b'o', 3, b'b', b'a', b'z'])
+        .await
+        .unwrap();
+    // The failure is replied and the connection closed right after
+    let mut data = Vec::new();
+    client.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data, [1, 1]);
+    assert!(task.await.unwrap());
+}
+
+#[tokio::test]
+async fn unsupported_version_rejected() {
+    let (mut client, task) = serve_client(Context::default());
+
+    client.write_all(&[6, 1, 0]).await.unwrap();
+    assert!(!task.await.unwrap());
+    let mut data = Vec::new();
+    client.read_to_end(&mut data).await.unwrap();
+    assert!(data.is_empty());
+}
+
+#[tokio::test]
+async fn unreachable_target_replied() {
+    // Nothing's listening on the port once the listener's gone
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    drop(listener);
+    let (mut client, task) = serve_client(Context::default());
+
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut response = [0; 2];
+    client.read_exact(&mut response).await.unwrap();
+    client.write_all(&connect_request(address)).await.unwrap();
+    assert_eq!(
+        read_reply_code(&mut client).await,
+        ResponseCode::ConnectionRefused as u8
+    );
+    assert!(task.await.unwrap());
+}